@@ -0,0 +1,88 @@
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use json_elem::JsonElem;
+use remote_call::{
+    wait_for_objects_with, ClientOptions, Connector, RemoteError, ServerBuilder, SharedObject,
+    SharedObjectDispatcher,
+};
+use tokio::runtime::{Builder, Runtime};
+
+struct Echo;
+
+#[async_trait]
+impl SharedObject for Echo {
+    async fn remote_call(&self, _method: &str, param: JsonElem) -> Result<JsonElem, RemoteError> {
+        Ok(param)
+    }
+}
+
+fn setup_server() -> (Runtime, ClientOptions) {
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    let options = runtime.block_on(async {
+        let server = ServerBuilder::new()
+            .with_address("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let options = ClientOptions::default().with_address(&server.local_addr().to_string());
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("echo", Box::new(Echo))
+            .await
+            .unwrap();
+        shared.spawn().await;
+        wait_for_objects_with(options.clone(), vec!["echo".to_string()])
+            .await
+            .unwrap();
+        options
+    });
+    (runtime, options)
+}
+
+/// Measures how long it takes for `callers` clients, each with its own
+/// connection, to complete one remote call each at the same time.
+fn concurrent_calls(c: &mut Criterion) {
+    let (runtime, options) = setup_server();
+    let mut group = c.benchmark_group("concurrent_calls");
+
+    for callers in [1, 8, 32, 128] {
+        let connectors: Vec<Connector> = runtime.block_on(async {
+            let mut connectors = Vec::new();
+            for _ in 0..callers {
+                connectors.push(Connector::connect_with(options.clone()).await.unwrap());
+            }
+            connectors
+        });
+
+        group.throughput(Throughput::Elements(callers as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(callers),
+            &connectors,
+            |b, connectors| {
+                b.to_async(&runtime).iter(|| async {
+                    let tasks: Vec<_> = connectors
+                        .iter()
+                        .cloned()
+                        .map(|connector| {
+                            tokio::spawn(async move {
+                                connector
+                                    .remote_call("echo", "echo", JsonElem::Integer(1))
+                                    .await
+                                    .unwrap()
+                            })
+                        })
+                        .collect();
+                    for task in tasks {
+                        task.await.unwrap();
+                    }
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_calls);
+criterion_main!(benches);
@@ -0,0 +1,53 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use json_elem::JsonElem;
+use remote_call::message::{
+    CallMethod, MessageType, SocketMessage, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+
+fn call_method(size: usize) -> SocketMessage {
+    let call = CallMethod {
+        object: "my_object".to_string(),
+        method: "my_function".to_string(),
+        param: JsonElem::String("x".repeat(size)),
+    };
+    SocketMessage::new()
+        .set_kind(MessageType::RemoteCallRequest)
+        .set_body(&call.as_bytes())
+}
+
+/// Compares the wire size and the encoding and decoding throughput of the
+/// legacy byte array body against the embedded JSON body.
+fn wire_format(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wire_format");
+
+    for size in [64, 1024, 16 * 1024] {
+        let msg = call_method(size);
+
+        for (name, version) in [("v1", LEGACY_PROTOCOL_VERSION), ("v2", PROTOCOL_VERSION)] {
+            let encoded = msg.encode(version);
+            println!(
+                "wire_format/{}/{}: {} body bytes, {} bytes on the wire",
+                name,
+                size,
+                msg.body().len(),
+                encoded.len()
+            );
+
+            group.throughput(Throughput::Bytes(msg.body().len() as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("encode_{}", name), size),
+                &msg,
+                |b, msg| b.iter(|| msg.encode(black_box(version))),
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("decode_{}", name), size),
+                &encoded,
+                |b, encoded| b.iter(|| SocketMessage::decode(black_box(encoded)).unwrap()),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, wire_format);
+criterion_main!(benches);
@@ -0,0 +1,271 @@
+use std::time::Duration;
+
+use json_elem::JsonElem;
+
+pub const USAGE: &str = "\
+Usage: remote [--server <address>] [--json] <command>
+
+Commands:
+  list                                The shared objects and the connections.
+  list <method> [json]                Calls a method of the built-in list object.
+  call <object> <method> [json]       Calls a method of a shared object.
+  describe <object>                   The methods of a shared object.
+  emit <event> [json] [--ack]         Publishes an event, --ack waits for the delivery report.
+  listen <event>                      Prints the event until Ctrl-C.
+  wait <object>... [--timeout <s>]    Waits until the objects are registered.
+  shell                               Opens an interactive shell.
+  <object> <method> [json]            Same as call.
+
+Options:
+  --server <address>   The IPC server, instead of ENV_SERVER_ADDRESS.
+  --json               Prints the results as JSON.
+  -h, --help           Prints this help.
+  -V, --version        Prints the version.
+
+Exit codes:
+  0 success, 1 the remote call failed, 2 invalid arguments,
+  3 the server cannot be reached, 4 timed out.";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    List,
+    Call {
+        object: String,
+        method: String,
+        param: JsonElem,
+    },
+    Describe {
+        object: String,
+    },
+    Emit {
+        event: String,
+        param: JsonElem,
+        ack: bool,
+    },
+    Listen {
+        event: String,
+    },
+    Wait {
+        objects: Vec<String>,
+        timeout: Option<Duration>,
+    },
+    Shell,
+    Help,
+    Version,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub server: Option<String>,
+    pub json: bool,
+    pub command: Command,
+}
+
+/// Parses the command line, without the program name. The options may be
+/// given anywhere, before or after the command.
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut server = None;
+    let mut json = false;
+    let mut ack = false;
+    let mut timeout = None;
+    let mut help = false;
+    let mut version = false;
+    let mut positional = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value)),
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| {
+            inline
+                .map(String::from)
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} expects a value", name))
+        };
+        match flag.as_str() {
+            "--server" => server = Some(value("--server")?),
+            "--timeout" => {
+                let seconds = value("--timeout")?;
+                let seconds = seconds
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                    .ok_or_else(|| format!("Invalid timeout: {}", seconds))?;
+                timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            "--json" => json = true,
+            "--ack" => ack = true,
+            "-h" | "--help" => help = true,
+            "-V" | "--version" => version = true,
+            // Kept for the scripts written for the first version of the CLI.
+            "--describe" => positional.push("describe".to_string()),
+            flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let command = if help {
+        Command::Help
+    } else if version {
+        Command::Version
+    } else {
+        command(positional, ack, timeout)?
+    };
+    Ok(Args {
+        server,
+        json,
+        command,
+    })
+}
+
+fn command(
+    positional: Vec<String>,
+    ack: bool,
+    timeout: Option<Duration>,
+) -> Result<Command, String> {
+    let mut positional = positional.into_iter();
+    let name = positional.next().ok_or("Missing command")?;
+    let rest: Vec<String> = positional.collect();
+
+    if ack && name != "emit" {
+        return Err("--ack is an option of emit".to_string());
+    }
+    if timeout.is_some() && name != "wait" {
+        return Err("--timeout is an option of wait".to_string());
+    }
+
+    let command = match (name.as_str(), rest.as_slice()) {
+        ("list", []) => Command::List,
+        ("call", [object, method]) => Command::Call {
+            object: object.clone(),
+            method: method.clone(),
+            param: JsonElem::Null,
+        },
+        ("call", [object, method, param]) => Command::Call {
+            object: object.clone(),
+            method: method.clone(),
+            param: json(param)?,
+        },
+        ("describe", [object]) => Command::Describe {
+            object: object.clone(),
+        },
+        ("emit", [event]) => Command::Emit {
+            event: event.clone(),
+            param: JsonElem::Null,
+            ack,
+        },
+        ("emit", [event, param]) => Command::Emit {
+            event: event.clone(),
+            param: json(param)?,
+            ack,
+        },
+        ("listen", [event]) => Command::Listen {
+            event: event.clone(),
+        },
+        ("wait", objects) if !objects.is_empty() => Command::Wait {
+            objects: objects.to_vec(),
+            timeout,
+        },
+        ("shell", []) => Command::Shell,
+        // The built-in `list` object is still called as `list <method> [json]`.
+        ("call" | "describe" | "emit" | "listen" | "wait" | "shell", _)
+        | ("list", [_, _, _, ..]) => return Err(format!("Invalid arguments for {}", name)),
+        (object, [method]) => Command::Call {
+            object: object.to_string(),
+            method: method.clone(),
+            param: JsonElem::Null,
+        },
+        (object, [method, param]) => Command::Call {
+            object: object.to_string(),
+            method: method.clone(),
+            param: json(param)?,
+        },
+        _ => return Err(format!("Unknown command: {}", name)),
+    };
+    Ok(command)
+}
+
+fn json(param: &str) -> Result<JsonElem, String> {
+    JsonElem::try_from(param.as_bytes()).map_err(|err| format!("Invalid JSON {}: {}", param, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use json_elem::JsonElem;
+
+    use super::{parse, Args, Command};
+
+    fn args(line: &str) -> Result<Args, String> {
+        parse(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            args("--server 127.0.0.1:2000 --json call mango login \"admin\"").unwrap(),
+            Args {
+                server: Some("127.0.0.1:2000".into()),
+                json: true,
+                command: Command::Call {
+                    object: "mango".into(),
+                    method: "login".into(),
+                    param: JsonElem::String("admin".into()),
+                },
+            }
+        );
+        assert_eq!(
+            args("mango login").unwrap().command,
+            Command::Call {
+                object: "mango".into(),
+                method: "login".into(),
+                param: JsonElem::Null,
+            }
+        );
+        assert_eq!(
+            args("list listObjects").unwrap().command,
+            Command::Call {
+                object: "list".into(),
+                method: "listObjects".into(),
+                param: JsonElem::Null,
+            }
+        );
+        assert_eq!(
+            args("--describe mango").unwrap().command,
+            Command::Describe {
+                object: "mango".into()
+            }
+        );
+        assert_eq!(
+            args("wait mango apple --timeout=1.5").unwrap().command,
+            Command::Wait {
+                objects: vec!["mango".into(), "apple".into()],
+                timeout: Some(Duration::from_millis(1500)),
+            }
+        );
+        assert_eq!(
+            args("emit ready --ack").unwrap().command,
+            Command::Emit {
+                event: "ready".into(),
+                param: JsonElem::Null,
+                ack: true,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(args("").is_err());
+        assert!(args("list objectInfo \"mango\" extra").is_err());
+        assert!(args("shell extra").is_err());
+        assert!(args("wait").is_err());
+        assert!(args("call mango login {").is_err());
+        assert!(args("list --ack").is_err());
+        assert!(args("wait mango --timeout soon").is_err());
+        assert!(args("--server").is_err());
+        assert!(args("--verbose list").is_err());
+    }
+}
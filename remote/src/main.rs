@@ -1,34 +1,216 @@
-use std::env;
+mod args;
+mod shell;
+
+use std::process::ExitCode;
 
 use json_elem::JsonElem;
-use remote_call::Connector;
+use remote_call::{
+    wait_for_objects_with, ClientOptions, Connector, ErrorCode, ErrorOrigin, EventListener,
+    RemoteError,
+};
+
+use crate::args::{Args, Command, USAGE};
+
+const EXIT_FAILED: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_UNREACHABLE: u8 = 3;
+const EXIT_TIMEOUT: u8 = 4;
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let args: Vec<String> = env::args().collect();
+async fn main() -> ExitCode {
+    let args = match args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
 
-    if args.len() < 3 {
-        println!("Incomplete parameters! -> remote.exe <object_name> <method> <parameters in JSON format>");
-        return;
+    match run(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::Remote(err)) => {
+            if args.json {
+                eprintln!("{}", String::from_utf8_lossy(&err.as_bytes()));
+            } else {
+                eprintln!("Exception Error: {}", err);
+            }
+            match (err.origin, err.code) {
+                (ErrorOrigin::Client, ErrorCode::ClientConnectionError) => {
+                    ExitCode::from(EXIT_UNREACHABLE)
+                }
+                _ => ExitCode::from(EXIT_FAILED),
+            }
+        }
+        Err(Failure::TimedOut(objects)) => {
+            eprintln!("Timed out waiting for {}", objects.join(", "));
+            ExitCode::from(EXIT_TIMEOUT)
+        }
     }
-    let object = args[1].as_str();
-    let method = args[2].as_str();
+}
 
-    let param = if args.len() == 3 {
-        JsonElem::Null
-    } else {
-        JsonElem::try_from(args[3].as_bytes()).unwrap()
-    };
+enum Failure {
+    Remote(RemoteError),
+    TimedOut(Vec<String>),
+}
+
+impl From<RemoteError> for Failure {
+    fn from(err: RemoteError) -> Self {
+        Self::Remote(err)
+    }
+}
 
-    let proxy = Connector::connect().await.unwrap();
+async fn run(args: &Args) -> Result<(), Failure> {
+    let mut options = ClientOptions::default();
+    if let Some(server) = &args.server {
+        options = options.with_address(server);
+    }
 
-    let result = proxy.remote_call(object, method, param).await;
-    match result {
-        Ok(res) => {
-            res.print(0);
+    match &args.command {
+        Command::Help => println!("{}", USAGE),
+        Command::Shell => shell::run(options, args.json).await?,
+        Command::Version => println!("remote {}", env!("CARGO_PKG_VERSION")),
+        Command::List => {
+            let proxy = Connector::connect_with(options).await?;
+            let objects = proxy
+                .remote_call("list", "listObjects", JsonElem::Null)
+                .await?;
+            let connections = proxy
+                .remote_call("list", "listConnections", JsonElem::Null)
+                .await?;
+            print_list(args.json, value(&objects), value(&connections));
         }
-        Err(err) => {
-            println!("Exception Error: {}", err);
+        Command::Call {
+            object,
+            method,
+            param,
+        } => {
+            let proxy = Connector::connect_with(options).await?;
+            let result = proxy.remote_call(object, method, param.clone()).await?;
+            print(args.json, &result);
+        }
+        Command::Describe { object } => {
+            let proxy = Connector::connect_with(options).await?;
+            let description = proxy.describe(object).await?;
+            if args.json {
+                println!("{}", String::from_utf8_lossy(&description.as_bytes()));
+            } else {
+                print!("{}", description);
+            }
+        }
+        Command::Emit { event, param, ack } => {
+            let proxy = Connector::connect_with(options).await?;
+            if *ack {
+                let report = proxy.send_event_with_ack(event, param.clone()).await?;
+                if args.json {
+                    println!("{}", String::from_utf8_lossy(&report.as_bytes()));
+                } else {
+                    println!("Delivered to {} subscriber(s)", report.delivered);
+                    for failed in &report.failed {
+                        println!("Failed: {}", failed);
+                    }
+                }
+            } else {
+                proxy.send_event(event, param.clone()).await?;
+            }
         }
+        Command::Listen { event } => {
+            let listener = EventListener::dispatch_with(options).await?;
+            let json = args.json;
+            listener
+                .listen(event, move |param| async move {
+                    print(json, &param);
+                    Ok::<(), RemoteError>(())
+                })
+                .await?;
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                notice = listener.going_away() => {
+                    return Err(RemoteError::from_code(
+                        ErrorCode::ServerConnectionError,
+                        ErrorOrigin::Broker,
+                        notice.reason,
+                    )
+                    .into());
+                }
+                _ = listener.closed() => {
+                    return Err(RemoteError::from_code(
+                        ErrorCode::ServerConnectionError,
+                        ErrorOrigin::Client,
+                        "The connection to the server was lost.",
+                    )
+                    .into());
+                }
+            }
+        }
+        Command::Wait { objects, timeout } => {
+            let wait = wait_for_objects_with(options, objects.clone());
+            match timeout {
+                Some(timeout) => tokio::time::timeout(*timeout, wait)
+                    .await
+                    .map_err(|_| Failure::TimedOut(objects.clone()))??,
+                None => wait.await?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn value(result: &JsonElem) -> serde_json::Value {
+    serde_json::to_value(result).unwrap_or_default()
+}
+
+fn print(json: bool, result: &JsonElem) {
+    if json {
+        println!("{}", value(result));
+    } else {
+        result.print(0);
+    }
+}
+
+fn print_list(json: bool, objects: serde_json::Value, connections: serde_json::Value) {
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "objects": objects["objects"],
+                "connections": connections["connections"],
+            })
+        );
+        return;
+    }
+
+    let names = |value: &serde_json::Value| {
+        let names: Vec<&str> = value
+            .as_array()
+            .map(|names| names.iter().filter_map(|name| name.as_str()).collect())
+            .unwrap_or_default();
+        if names.is_empty() {
+            "-".to_string()
+        } else {
+            names.join(", ")
+        }
+    };
+
+    println!("Objects:");
+    let mut objects: Vec<&str> = objects["objects"]
+        .as_array()
+        .map(|objects| objects.iter().filter_map(|name| name.as_str()).collect())
+        .unwrap_or_default();
+    objects.sort_unstable();
+    for object in objects {
+        println!("  {}", object);
+    }
+
+    println!("Connections:");
+    for connection in connections["connections"].as_array().into_iter().flatten() {
+        println!(
+            "  {}({})@{} v{} objects: {} subscriptions: {}",
+            connection["name"].as_str().unwrap_or_default(),
+            connection["pid"],
+            connection["address"].as_str().unwrap_or_default(),
+            connection["version"].as_str().unwrap_or_default(),
+            names(&connection["objects"]),
+            names(&connection["subscriptions"]),
+        );
     }
 }
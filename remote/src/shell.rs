@@ -0,0 +1,344 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use json_elem::JsonElem;
+use remote_call::{ClientOptions, Connector, ErrorCode, ErrorOrigin, EventListener, RemoteError};
+use rustyline::{
+    completion::Completer, error::ReadlineError, history::DefaultHistory, Context, ExternalPrinter,
+    Helper, Highlighter, Hinter, Validator,
+};
+
+use crate::print;
+
+pub const HELP: &str = "\
+Commands:
+  list                            The shared objects, refreshes the completion.
+  call <object> <method> [json]   Calls a method, `call` may be left out.
+  describe <object>               The methods of a shared object.
+  emit <event> [json]             Publishes an event.
+  subscribe <event>               Prints the event in the background.
+  history                         The commands entered so far.
+  help                            Prints this help.
+  exit                            Leaves the shell, as Ctrl-D does.";
+
+const COMMANDS: [&str; 8] = [
+    "list",
+    "call",
+    "describe",
+    "emit",
+    "subscribe",
+    "history",
+    "help",
+    "exit",
+];
+const HISTORY_FILE: &str = ".remote_history";
+
+/// The object and method names offered by the tab completion.
+#[derive(Default)]
+struct Names {
+    objects: Vec<String>,
+    /// The methods of the objects that describe themselves.
+    methods: HashMap<String, Vec<String>>,
+}
+
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ShellHelper {
+    names: Arc<Mutex<Names>>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let prefix = &line[start..];
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let names = self.names.lock().unwrap();
+        let methods = |object: &str| names.methods.get(object).cloned().unwrap_or_default();
+        let candidates: Vec<String> = match words.as_slice() {
+            [] => COMMANDS
+                .iter()
+                .map(|command| command.to_string())
+                .chain(names.objects.iter().cloned())
+                .collect(),
+            ["call" | "describe"] => names.objects.clone(),
+            ["call", object] => methods(object),
+            [object] => methods(object),
+            _ => Vec::new(),
+        };
+        let mut candidates: Vec<String> = candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(prefix))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        Ok((start, candidates))
+    }
+}
+
+type Printer = Arc<Mutex<Option<Box<dyn ExternalPrinter + Send>>>>;
+
+struct Shell {
+    options: ClientOptions,
+    proxy: Connector,
+    json: bool,
+    names: Arc<Mutex<Names>>,
+    printer: Printer,
+    /// The listeners of the events subscribed to, kept for the whole session.
+    listeners: Vec<EventListener>,
+}
+
+/// Runs the interactive shell on one connection until `exit` or Ctrl-D.
+pub async fn run(options: ClientOptions, json: bool) -> Result<(), RemoteError> {
+    let proxy = Connector::connect_with(options.clone()).await?;
+    let names = Arc::new(Mutex::new(Names::default()));
+    let mut shell = Shell {
+        options,
+        proxy,
+        json,
+        names: names.clone(),
+        printer: Arc::default(),
+        listeners: Vec::new(),
+    };
+    shell.refresh_names().await;
+
+    let mut editor =
+        rustyline::Editor::<ShellHelper, DefaultHistory>::new().map_err(local_error)?;
+    editor.set_helper(Some(ShellHelper { names }));
+    if let Ok(printer) = editor.create_external_printer() {
+        *shell.printer.lock().unwrap() = Some(Box::new(printer));
+    }
+    let history = history_file();
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        println!(
+            "Connected to {}, `help` lists the commands.",
+            shell.options.address
+        );
+        loop {
+            let line = match editor.readline("remote> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    break;
+                }
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let _ = editor.add_history_entry(line);
+
+            match line {
+                "exit" | "quit" => break,
+                "help" => println!("{}", HELP),
+                "history" => {
+                    for (index, entry) in editor.history().iter().enumerate() {
+                        println!("{:>4}  {}", index + 1, entry);
+                    }
+                }
+                _ => {
+                    if let Err(err) = handle.block_on(shell.execute(line)) {
+                        eprintln!("Exception Error: {}", err);
+                    }
+                }
+            }
+        }
+        if let Some(history) = &history {
+            let _ = editor.save_history(history);
+        }
+    })
+    .await
+    .map_err(local_error)
+}
+
+impl Shell {
+    async fn execute(&mut self, line: &str) -> Result<(), RemoteError> {
+        let words: Vec<&str> = line.splitn(2, char::is_whitespace).collect();
+        let (command, rest) = match words.as_slice() {
+            [command] => (*command, ""),
+            [command, rest] => (*command, rest.trim()),
+            _ => return Ok(()),
+        };
+
+        match command {
+            // The built-in `list` object is called as `list <method>`.
+            "list" if rest.is_empty() => {
+                self.refresh_names().await;
+                let mut objects = self.names.lock().unwrap().objects.clone();
+                objects.sort();
+                for object in objects {
+                    println!("  {}", object);
+                }
+            }
+            "describe" => {
+                let description = self.proxy.describe(rest).await?;
+                print!("{}", description);
+            }
+            "emit" => {
+                let (event, param) = split_param(rest)?;
+                self.proxy.send_event(event, param).await?;
+            }
+            "subscribe" => self.subscribe(rest).await?,
+            "call" => {
+                let (object, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                self.call(object, rest.trim()).await?;
+            }
+            object => self.call(object, rest).await?,
+        }
+        Ok(())
+    }
+
+    async fn call(&self, object: &str, rest: &str) -> Result<(), RemoteError> {
+        let (method, param) = split_param(rest)?;
+        if method.is_empty() {
+            return Err(local_error(format!("Which method of {}?", object)));
+        }
+        let result = self.proxy.remote_call(object, method, param).await?;
+        print(self.json, &result);
+        Ok(())
+    }
+
+    async fn subscribe(&mut self, event: &str) -> Result<(), RemoteError> {
+        if event.is_empty() {
+            return Err(local_error("Which event?"));
+        }
+        let listener = EventListener::dispatch_with(self.options.clone()).await?;
+        let printer = self.printer.clone();
+        let name = event.to_string();
+        listener
+            .listen(event, move |param| {
+                let printer = printer.clone();
+                let name = name.clone();
+                async move {
+                    let param = serde_json::to_string(&param).unwrap_or_default();
+                    let line = format!("[{}] {}", name, param);
+                    match printer.lock().unwrap().as_mut() {
+                        Some(printer) => {
+                            let _ = printer.print(line);
+                        }
+                        None => println!("{}", line),
+                    }
+                    Ok::<(), RemoteError>(())
+                }
+            })
+            .await?;
+        self.listeners.push(listener);
+        println!("Subscribed to {}", event);
+        Ok(())
+    }
+
+    /// Reloads the object names, and the methods of the objects that
+    /// describe themselves.
+    async fn refresh_names(&self) {
+        let Ok(objects) = self
+            .proxy
+            .remote_call("list", "listObjects", JsonElem::Null)
+            .await
+        else {
+            return;
+        };
+        let objects: Vec<String> = serde_json::to_value(&objects)
+            .ok()
+            .and_then(|objects| serde_json::from_value(objects["objects"].clone()).ok())
+            .unwrap_or_default();
+
+        let mut methods = HashMap::new();
+        for object in &objects {
+            if let Ok(description) = self.proxy.describe(object).await {
+                methods.insert(
+                    object.clone(),
+                    description
+                        .methods
+                        .into_iter()
+                        .map(|method| method.name)
+                        .collect(),
+                );
+            }
+        }
+
+        let mut names = self.names.lock().unwrap();
+        names.objects = objects;
+        names.methods = methods;
+    }
+}
+
+/// Splits `<name> [json]` into the name and the parameter.
+fn split_param(rest: &str) -> Result<(&str, JsonElem), RemoteError> {
+    match rest.split_once(char::is_whitespace) {
+        Some((name, param)) => {
+            let param = JsonElem::try_from(param.trim().as_bytes())
+                .map_err(|err| local_error(format!("Invalid JSON: {}", err)))?;
+            Ok((name, param))
+        }
+        None => Ok((rest, JsonElem::Null)),
+    }
+}
+
+/// An error of the shell itself, such as a mistyped command.
+fn local_error(err: impl std::fmt::Display) -> RemoteError {
+    RemoteError::from_code(ErrorCode::InvalidParams, ErrorOrigin::Client, err)
+}
+
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use rustyline::{completion::Completer, history::DefaultHistory, Context};
+
+    use super::{Names, ShellHelper};
+
+    #[test]
+    fn complete_names() {
+        let mut names = Names {
+            objects: vec!["list".into(), "mango".into()],
+            ..Names::default()
+        };
+        names.methods.insert(
+            "list".into(),
+            vec!["listObjects".into(), "objectInfo".into()],
+        );
+        let helper = ShellHelper {
+            names: Arc::new(Mutex::new(names)),
+        };
+        let history = DefaultHistory::new();
+        let complete = |line: &str| {
+            helper
+                .complete(line, line.len(), &Context::new(&history))
+                .unwrap()
+        };
+
+        assert_eq!(complete("ma"), (0, vec!["mango".to_string()]));
+        assert_eq!(complete("li"), (0, vec!["list".to_string()]));
+        assert_eq!(complete("call m"), (5, vec!["mango".to_string()]));
+        assert_eq!(complete("list o"), (5, vec!["objectInfo".to_string()]));
+        assert_eq!(
+            complete("call list "),
+            (
+                10,
+                vec!["listObjects".to_string(), "objectInfo".to_string()]
+            )
+        );
+        assert_eq!(complete("call mango "), (11, Vec::new()));
+    }
+}
@@ -0,0 +1,562 @@
+use std::{fmt::Debug, sync::Arc};
+
+use crate::{error::Error, message::SocketMessage, util};
+
+pub const ENV_CODEC: &str = "ENV_CODEC";
+pub const JSON: &str = "json";
+#[cfg(feature = "msgpack")]
+pub const MSGPACK: &str = "msgpack";
+#[cfg(feature = "cbor")]
+pub const CBOR: &str = "cbor";
+
+/// The frames decoded from the start of a stream, and the number of bytes
+/// they span. A frame that is not a valid message is kept as an error so
+/// that the frames after it are not lost.
+pub type Decoded = (Vec<Result<(SocketMessage, u8), Error>>, usize);
+
+/// How far a stream has been scanned for the end of its first frame, so
+/// that a frame arriving over many reads is scanned once and decoded once.
+#[derive(Debug, Default)]
+pub struct FrameScan {
+    offset: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// The items still due to each container open in a binary frame.
+    #[cfg(any(feature = "msgpack", feature = "cbor"))]
+    open: Vec<usize>,
+}
+
+impl FrameScan {
+    /// Where the first frame ends, once it has been found.
+    pub fn frame_end(&self) -> usize {
+        self.offset
+    }
+}
+
+/// Encodes and decodes the [`SocketMessage`] frames exchanged with a peer.
+/// The codec is negotiated per connection during the handshake, so the
+/// server can bridge peers that use different codecs.
+pub trait Codec: Send + Sync + Debug {
+    /// The name the codec is negotiated with.
+    fn name(&self) -> &'static str;
+
+    /// Encodes the message for a peer that speaks the given protocol version.
+    fn encode(&self, msg: &SocketMessage, version: u8) -> Result<Vec<u8>, Error>;
+
+    /// Decodes the complete frames at the start of the stream, together
+    /// with the protocol version advertised in each of them.
+    fn decode(&self, data: &[u8]) -> Result<Decoded, Error>;
+
+    /// Whether the stream holds a complete frame worth decoding, scanning
+    /// only the bytes after the previous scan. The scan is reset once the
+    /// stream has been decoded. Codecs that cannot tell cheaply decode the
+    /// stream after every read.
+    fn has_frame(&self, data: &[u8], scan: &mut FrameScan) -> bool {
+        let _ = (data, scan);
+        true
+    }
+}
+
+/// The JSON codec. It is the default and the one every peer supports.
+#[derive(Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        JSON
+    }
+
+    fn encode(&self, msg: &SocketMessage, version: u8) -> Result<Vec<u8>, Error> {
+        Ok(msg.encode(version))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Decoded, Error> {
+        let (frames, consumed) = util::separate(data)?;
+        let frames = frames
+            .into_iter()
+            .map(|frame| SocketMessage::decode(frame).map_err(Error::Serde))
+            .collect();
+
+        Ok((frames, consumed))
+    }
+
+    /// A frame ends where its outermost braces close, strings aside.
+    fn has_frame(&self, data: &[u8], scan: &mut FrameScan) -> bool {
+        for (offset, byte) in data.iter().enumerate().skip(scan.offset) {
+            if scan.in_string {
+                match byte {
+                    _ if scan.escaped => scan.escaped = false,
+                    b'\\' => scan.escaped = true,
+                    b'"' => scan.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => scan.in_string = true,
+                b'{' | b'[' => scan.depth += 1,
+                // A stray closing brace is left for the decoder to reject.
+                b'}' | b']' => {
+                    scan.depth = scan.depth.saturating_sub(1);
+                    if scan.depth == 0 {
+                        scan.offset = offset + 1;
+                        return true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        scan.offset = data.len();
+        false
+    }
+}
+
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+mod binary {
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        message::{Caller, MessageType, ResponseStatus, SocketMessage, PROTOCOL_VERSION},
+        trace::TraceContext,
+    };
+
+    /// A [`SocketMessage`] as it is written in the binary codecs.
+    /// The body is carried as raw bytes.
+    #[derive(Serialize)]
+    pub struct BinaryFrame<'a> {
+        pub v: u8,
+        pub id: u64,
+        pub kind: MessageType,
+        #[serde(with = "serde_bytes")]
+        pub msg: &'a [u8],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub trace: Option<&'a TraceContext>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub caller: Option<&'a Caller>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub deadline: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub status: Option<ResponseStatus>,
+    }
+
+    impl<'a> From<&'a SocketMessage> for BinaryFrame<'a> {
+        fn from(msg: &'a SocketMessage) -> Self {
+            Self {
+                v: PROTOCOL_VERSION,
+                id: msg.id(),
+                kind: msg.kind(),
+                msg: msg.body(),
+                trace: msg.trace(),
+                caller: msg.caller(),
+                deadline: msg.deadline(),
+                status: msg.status(),
+            }
+        }
+    }
+
+    /// A [`SocketMessage`] as it is read in the binary codecs.
+    #[derive(Deserialize)]
+    pub struct OwnedBinaryFrame {
+        pub v: u8,
+        pub id: u64,
+        pub kind: MessageType,
+        #[serde(with = "serde_bytes")]
+        pub msg: Vec<u8>,
+        #[serde(default)]
+        pub trace: Option<TraceContext>,
+        #[serde(default)]
+        pub caller: Option<Caller>,
+        #[serde(default)]
+        pub deadline: Option<u64>,
+        #[serde(default)]
+        pub status: Option<ResponseStatus>,
+    }
+
+    impl From<OwnedBinaryFrame> for (SocketMessage, u8) {
+        fn from(frame: OwnedBinaryFrame) -> Self {
+            let msg = SocketMessage::new()
+                .set_id(frame.id)
+                .set_kind(frame.kind)
+                .set_body(&frame.msg)
+                .set_trace(frame.trace)
+                .set_caller(frame.caller)
+                .set_deadline(frame.deadline)
+                .set_status(frame.status);
+            (msg, frame.v)
+        }
+    }
+}
+
+/// What follows the head of an item in a binary frame.
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+enum Nested {
+    /// That many items, none for a scalar.
+    Items(usize),
+    /// Items up to a break, only in CBOR.
+    #[cfg_attr(not(feature = "cbor"), allow(dead_code))]
+    UntilBreak,
+    #[cfg_attr(not(feature = "cbor"), allow(dead_code))]
+    Break,
+    Invalid,
+}
+
+/// Scans a binary frame item by item from the head of each, which tells
+/// the size of the item or how many items are nested in it. The head is
+/// `None` until all of it, and the bytes of a scalar, are in the stream.
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+fn scan_items(
+    data: &[u8],
+    scan: &mut FrameScan,
+    head: fn(&[u8]) -> Option<(usize, Nested)>,
+) -> bool {
+    while let Some((len, nested)) = head(&data[scan.offset..]) {
+        scan.offset += len;
+        match nested {
+            Nested::Items(0) => {}
+            Nested::Items(items) => {
+                scan.open.push(items);
+                continue;
+            }
+            Nested::UntilBreak => {
+                scan.open.push(usize::MAX);
+                continue;
+            }
+            Nested::Break if scan.open.pop() == Some(usize::MAX) => {}
+            // Left for the decoder to reject.
+            Nested::Break | Nested::Invalid => return true,
+        }
+        // The item is whole, and so are the containers it was the last of.
+        loop {
+            match scan.open.last_mut() {
+                None => return true,
+                Some(&mut usize::MAX) => break,
+                Some(items) => {
+                    *items -= 1;
+                    if *items > 0 {
+                        break;
+                    }
+                    scan.open.pop();
+                }
+            }
+        }
+    }
+    false
+}
+
+/// The big-endian unsigned integer of the given size at the start of the data.
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+fn uint(data: &[u8], size: usize) -> Option<usize> {
+    let value = data
+        .get(..size)?
+        .iter()
+        .fold(0u64, |value, byte| value << 8 | u64::from(*byte));
+    Some(usize::try_from(value).unwrap_or(usize::MAX))
+}
+
+/// The head of a MessagePack item, see [`scan_items`].
+#[cfg(feature = "msgpack")]
+fn msgpack_head(data: &[u8]) -> Option<(usize, Nested)> {
+    let (&marker, rest) = data.split_first()?;
+    // The bytes after the marker, and the items nested after those.
+    let (len, items) = match marker {
+        0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (0, 0),
+        0x80..=0x8f => (0, 2 * usize::from(marker & 0x0f)),
+        0x90..=0x9f => (0, usize::from(marker & 0x0f)),
+        0xa0..=0xbf => (usize::from(marker & 0x1f), 0),
+        0xc1 => return Some((1, Nested::Invalid)),
+        0xc4 | 0xd9 => (uint(rest, 1)?.saturating_add(1), 0),
+        0xc5 | 0xda => (uint(rest, 2)?.saturating_add(2), 0),
+        0xc6 | 0xdb => (uint(rest, 4)?.saturating_add(4), 0),
+        0xc7 => (uint(rest, 1)?.saturating_add(2), 0),
+        0xc8 => (uint(rest, 2)?.saturating_add(3), 0),
+        0xc9 => (uint(rest, 4)?.saturating_add(5), 0),
+        0xcc | 0xd0 => (1, 0),
+        0xcd | 0xd1 => (2, 0),
+        0xca | 0xce | 0xd2 => (4, 0),
+        0xcb | 0xcf | 0xd3 => (8, 0),
+        0xd4 => (2, 0),
+        0xd5 => (3, 0),
+        0xd6 => (5, 0),
+        0xd7 => (9, 0),
+        0xd8 => (17, 0),
+        0xdc => (2, uint(rest, 2)?),
+        0xdd => (4, uint(rest, 4)?),
+        0xde => (2, uint(rest, 2)?.saturating_mul(2)),
+        0xdf => (4, uint(rest, 4)?.saturating_mul(2)),
+    };
+    (rest.len() >= len).then_some((len + 1, Nested::Items(items)))
+}
+
+/// The head of a CBOR item, see [`scan_items`].
+#[cfg(feature = "cbor")]
+fn cbor_head(data: &[u8]) -> Option<(usize, Nested)> {
+    let (&initial, rest) = data.split_first()?;
+    let (major, info) = (initial >> 5, initial & 0x1f);
+    let (size, argument) = match info {
+        0..=23 => (0, usize::from(info)),
+        24..=27 => {
+            let size = 1 << (info - 24);
+            (size, uint(rest, size)?)
+        }
+        31 => {
+            let nested = match major {
+                2..=5 => Nested::UntilBreak,
+                7 => Nested::Break,
+                _ => Nested::Invalid,
+            };
+            return Some((1, nested));
+        }
+        _ => return Some((1, Nested::Invalid)),
+    };
+    // The bytes after the initial byte, and the items nested after those.
+    let (len, items) = match major {
+        2 | 3 => (size.saturating_add(argument), 0),
+        4 => (size, argument),
+        5 => (size, argument.saturating_mul(2)),
+        6 => (size, 1),
+        _ => (size, 0),
+    };
+    (rest.len() >= len).then_some((len + 1, Nested::Items(items)))
+}
+
+/// The MessagePack codec.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Default)]
+pub struct MsgPackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgPackCodec {
+    fn name(&self) -> &'static str {
+        MSGPACK
+    }
+
+    fn encode(&self, msg: &SocketMessage, _version: u8) -> Result<Vec<u8>, Error> {
+        rmp_serde::to_vec_named(&binary::BinaryFrame::from(msg))
+            .map_err(|e| Error::Others(e.to_string()))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Decoded, Error> {
+        use rmp_serde::decode::Error as DecodeError;
+
+        let mut cursor = std::io::Cursor::new(data);
+        let mut frames = Vec::new();
+        let mut consumed = 0;
+
+        while (consumed as usize) < data.len() {
+            match rmp_serde::from_read::<_, binary::OwnedBinaryFrame>(&mut cursor) {
+                Ok(frame) => frames.push(Ok(frame.into())),
+                Err(DecodeError::InvalidMarkerRead(err) | DecodeError::InvalidDataRead(err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break;
+                }
+                Err(err) => return Err(Error::Others(err.to_string())),
+            }
+            consumed = cursor.position();
+        }
+        Ok((frames, consumed as usize))
+    }
+
+    fn has_frame(&self, data: &[u8], scan: &mut FrameScan) -> bool {
+        scan_items(data, scan, msgpack_head)
+    }
+}
+
+/// The CBOR codec.
+#[cfg(feature = "cbor")]
+#[derive(Debug, Default)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl Codec for CborCodec {
+    fn name(&self) -> &'static str {
+        CBOR
+    }
+
+    fn encode(&self, msg: &SocketMessage, _version: u8) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        ciborium::into_writer(&binary::BinaryFrame::from(msg), &mut data)
+            .map_err(|e| Error::Others(e.to_string()))?;
+        Ok(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Decoded, Error> {
+        use ciborium::de::Error as DecodeError;
+
+        let mut cursor = std::io::Cursor::new(data);
+        let mut frames = Vec::new();
+        let mut consumed = 0;
+
+        while (consumed as usize) < data.len() {
+            match ciborium::from_reader::<binary::OwnedBinaryFrame, _>(&mut cursor) {
+                Ok(frame) => frames.push(Ok(frame.into())),
+                Err(DecodeError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    break;
+                }
+                Err(err) => return Err(Error::Others(err.to_string())),
+            }
+            consumed = cursor.position();
+        }
+        Ok((frames, consumed as usize))
+    }
+
+    fn has_frame(&self, data: &[u8], scan: &mut FrameScan) -> bool {
+        scan_items(data, scan, cbor_head)
+    }
+}
+
+/// The names of the codecs this build supports, in order of preference.
+pub fn supported() -> Vec<&'static str> {
+    vec![
+        #[cfg(feature = "msgpack")]
+        MSGPACK,
+        #[cfg(feature = "cbor")]
+        CBOR,
+        JSON,
+    ]
+}
+
+/// Looks up a supported codec by its name.
+pub fn by_name(name: &str) -> Option<Arc<dyn Codec>> {
+    match name {
+        JSON => Some(Arc::new(JsonCodec)),
+        #[cfg(feature = "msgpack")]
+        MSGPACK => Some(Arc::new(MsgPackCodec)),
+        #[cfg(feature = "cbor")]
+        CBOR => Some(Arc::new(CborCodec)),
+        _ => None,
+    }
+}
+
+/// The codec this process asks for when connecting, read from `ENV_CODEC`.
+/// It defaults to JSON.
+pub fn preferred() -> String {
+    std::env::var(ENV_CODEC)
+        .map(|var| var.to_lowercase())
+        .ok()
+        .filter(|name| by_name(name).is_some())
+        .unwrap_or_else(|| JSON.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use crate::{
+        message::{Caller, MessageType, ResponseStatus, SocketMessage, PROTOCOL_VERSION},
+        trace::TraceContext,
+    };
+
+    use super::{by_name, Codec, FrameScan, JsonCodec};
+
+    #[test_case("json"; "Json")]
+    #[cfg_attr(feature = "msgpack", test_case("msgpack"; "MessagePack"))]
+    #[cfg_attr(feature = "cbor", test_case("cbor"; "Cbor"))]
+    fn codec_round_trip(name: &str) {
+        let codec = by_name(name).unwrap();
+        let first = SocketMessage::new()
+            .set_id(1)
+            .set_kind(MessageType::RemoteCallRequest)
+            .set_body(br#"{"object":"my_object"}"#)
+            .set_trace(Some(TraceContext::new_root()))
+            .set_caller(Some(Caller {
+                connection: "127.0.0.1:50000".into(),
+                name: "caller".into(),
+                pid: 42,
+            }))
+            .set_deadline(Some(1_700_000_000_000));
+        let second = SocketMessage::new()
+            .set_id(2)
+            .set_kind(MessageType::RemoteCallResponse)
+            .set_body(b"my_object")
+            .set_status(Some(ResponseStatus::Ok));
+
+        let mut stream = codec.encode(&first, PROTOCOL_VERSION).unwrap();
+        let first_len = stream.len();
+        stream.extend(codec.encode(&second, PROTOCOL_VERSION).unwrap());
+
+        let (frames, consumed) = codec.decode(&stream[..stream.len() - 1]).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(consumed, first_len);
+
+        let (frames, consumed) = codec.decode(&stream).unwrap();
+        assert_eq!(consumed, stream.len());
+        let frames: Vec<SocketMessage> = frames.into_iter().map(|frame| frame.unwrap().0).collect();
+        assert_eq!(frames, vec![first, second]);
+    }
+
+    #[test]
+    fn json_has_frame() {
+        let frame = br#"{"v":2,"id":5,"kind":3,"body":"}{\"]"}"#;
+        let mut scan = FrameScan::default();
+
+        // Fed a few bytes at a time, the end is found once, on the last byte.
+        for end in 1..frame.len() {
+            assert!(!JsonCodec.has_frame(&frame[..end], &mut scan), "{}", end);
+        }
+        assert!(JsonCodec.has_frame(frame, &mut scan));
+        assert_eq!(scan.offset, frame.len());
+
+        assert!(!JsonCodec.has_frame(b"  {\"id\":", &mut FrameScan::default()));
+        assert!(JsonCodec.has_frame(b"}", &mut FrameScan::default()));
+    }
+
+    /// Fed a byte at a time, the end of the first frame is found once, on
+    /// its last byte, whatever follows it.
+    #[cfg(any(feature = "msgpack", feature = "cbor"))]
+    fn assert_finds_frame(codec: &dyn Codec) {
+        let msg = SocketMessage::new()
+            .set_id(u64::MAX)
+            .set_kind(MessageType::RemoteCallRequest)
+            .set_body(&[0xc1; 300])
+            .set_trace(Some(TraceContext::new_root()))
+            .set_caller(Some(Caller {
+                connection: "127.0.0.1:50000".into(),
+                name: "caller".repeat(50),
+                pid: 42,
+            }))
+            .set_deadline(Some(1_700_000_000_000));
+        let frame = codec.encode(&msg, PROTOCOL_VERSION).unwrap();
+        let mut stream = frame.clone();
+        stream.extend(codec.encode(&msg, PROTOCOL_VERSION).unwrap());
+
+        let mut scan = FrameScan::default();
+        for end in 0..frame.len() {
+            assert!(!codec.has_frame(&stream[..end], &mut scan), "{}", end);
+        }
+        assert!(codec.has_frame(&stream[..frame.len() + 1], &mut scan));
+        assert_eq!(scan.frame_end(), frame.len());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_has_frame() {
+        use super::MsgPackCodec;
+
+        assert_finds_frame(&MsgPackCodec);
+        // A reserved marker is left for the decoder to reject.
+        assert!(MsgPackCodec.has_frame(&[0xc1], &mut FrameScan::default()));
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_has_frame() {
+        use super::CborCodec;
+
+        assert_finds_frame(&CborCodec);
+        // An array of indefinite length ends with its break.
+        let array = [0x9f, 0x01, 0x9f, 0xff, 0xff];
+        let mut scan = FrameScan::default();
+        for end in 0..array.len() {
+            assert!(!CborCodec.has_frame(&array[..end], &mut scan), "{}", end);
+        }
+        assert!(CborCodec.has_frame(&array, &mut scan));
+        assert!(CborCodec.has_frame(&[0xff], &mut FrameScan::default()));
+    }
+
+    #[test]
+    fn unknown_codec() {
+        assert!(by_name("xml").is_none());
+    }
+}
@@ -0,0 +1,769 @@
+use std::{net::IpAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use serde::Deserialize;
+use strum::{AsRefStr, Display, EnumString};
+
+use crate::{
+    error::Error,
+    logger::{LogConfig, LogFormat, Rotation},
+    metrics::ENV_METRICS_ADDRESS,
+    options::Heartbeat,
+    outbound::{OverflowPolicy, QueueConfig},
+    server::{DRAIN_TIMEOUT, ENV_DRAIN_TIMEOUT, ENV_WORKER_THREADS},
+    socket::{ENV_SERVER_ADDRESS, MAX_FRAME_SIZE, SERVER_ADDRESS},
+};
+
+/// The TOML file the server reads its settings from, unless `--config` is given.
+pub const ENV_SERVER_CONFIG: &str = "ENV_SERVER_CONFIG";
+
+pub const USAGE: &str = "\
+Usage: remote-call [options]
+
+Options:
+  -c, --config <file>          Reads the settings from a TOML file, instead of ENV_SERVER_CONFIG.
+  -l, --listen <address>       Listens on the address, such as tcp://0.0.0.0:1986. May be repeated.
+      --metrics <address>      Serves the metrics on GET /metrics at the address.
+      --log-level <level>      trace, debug, info, warn, error or off.
+      --log-format <format>    text or json.
+      --log-file <file>        Writes the logs to the file instead of stdout.
+      --log-max-size <bytes>   Rotates the log file once it grows past the size.
+      --log-rotation <period>  never, hourly or daily.
+      --log-keep <count>       The number of rotated log files kept.
+      --queue-capacity <n>     The outbound queue capacity of every connection.
+      --overflow <policy>      For events to a full queue: drop-oldest, drop-newest or disconnect.
+      --worker-threads <n>     The number of worker threads of the runtime.
+      --drain-timeout <s>      How long a shutdown waits for the calls in flight.
+      --max-connections <n>    The connections served at once, 0 for no limit.
+      --max-frame-size <bytes> The largest message a peer may send, 0 for no limit.
+      --max-pending-calls <n>  The calls of a connection waiting for their response.
+      --rate-limit <n>         The requests a connection may send per second.
+      --rate-burst <n>         The requests a connection may send at once, the rate by default.
+      --heartbeat-interval <s> How often the clients are pinged, 0 to disable the heartbeat.
+      --heartbeat-misses <n>   The heartbeats a client may miss before it is dropped.
+      --allow <ip[/prefix]>    Accepts connections from the addresses. May be repeated.
+      --deny <ip[/prefix]>     Refuses connections from the addresses. May be repeated.
+      --acl-default <action>   allow or deny the addresses no rule matches.
+  -h, --help                   Prints this help.
+  -V, --version                Prints the version.
+
+The flags take precedence over the config file, and the file over the
+environment variables.";
+
+/// How the clients reach a listen address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, EnumString, Display, AsRefStr)]
+pub enum Transport {
+    #[default]
+    #[strum(serialize = "tcp")]
+    Tcp,
+}
+
+/// An address the server accepts connections on, written as
+/// `tcp://127.0.0.1:1986` or just `127.0.0.1:1986`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listen {
+    pub transport: Transport,
+    pub address: String,
+}
+
+impl FromStr for Listen {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (transport, address) = match s.split_once("://") {
+            Some((transport, address)) => {
+                let transport = transport.to_lowercase().parse::<Transport>().map_err(|_| {
+                    Error::Others(format!("Unsupported transport {} in {}", transport, s))
+                })?;
+                (transport, address)
+            }
+            None => (Transport::default(), s),
+        };
+        if address.is_empty() {
+            return Err(Error::Others(format!("Missing listen address in {}", s)));
+        }
+        Ok(Self {
+            transport,
+            address: address.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}", self.transport, self.address)
+    }
+}
+
+/// What the ACL does with a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, EnumString, Display, AsRefStr)]
+pub enum AclAction {
+    #[default]
+    #[strum(serialize = "allow")]
+    Allow,
+    #[strum(serialize = "deny")]
+    Deny,
+}
+
+/// A single address, or a network written as `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpRule {
+    pub network: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpRule {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u32::from(network) as u128, u32::from(ip) as u128, 32u32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            (IpAddr::V6(_), IpAddr::V4(ip)) => {
+                return self.contains(IpAddr::V6(ip.to_ipv6_mapped()))
+            }
+            (IpAddr::V4(_), IpAddr::V6(ip)) => {
+                return ip
+                    .to_ipv4_mapped()
+                    .is_some_and(|ip| self.contains(IpAddr::V4(ip)))
+            }
+        };
+        let shift = bits - self.prefix as u32;
+        shift >= bits || (network >> shift) == (ip >> shift)
+    }
+}
+
+impl FromStr for IpRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::Others(format!("Invalid address rule: {}", s));
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+        let network = network.parse::<IpAddr>().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+/// Which peers may connect. A deny rule wins over an allow rule, and the
+/// default applies to the addresses no rule matches.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AclPolicy {
+    pub default: AclAction,
+    pub allow: Vec<IpRule>,
+    pub deny: Vec<IpRule>,
+}
+
+impl AclPolicy {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|rule| rule.contains(ip)) {
+            return false;
+        }
+        if self.allow.iter().any(|rule| rule.contains(ip)) {
+            return true;
+        }
+        self.default == AclAction::Allow
+    }
+}
+
+/// The resources a server hands out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub queue: QueueConfig,
+    /// The number of worker threads of the runtime, the number of CPU cores
+    /// when there is none.
+    pub worker_threads: Option<usize>,
+    /// How long a shutdown waits for the calls in flight to be answered.
+    pub drain_timeout: Duration,
+    /// The connections served at once. The clients past the limit are
+    /// rejected in the handshake.
+    pub max_connections: Option<usize>,
+    /// The largest frame a peer may send, in bytes. A peer sending a larger
+    /// one is disconnected.
+    pub max_frame_size: Option<usize>,
+    /// The calls and describes of a connection waiting for their response,
+    /// the next ones are refused with `LimitExceeded`.
+    pub max_pending_calls: Option<usize>,
+    /// The calls, describes and events a connection may send.
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            queue: QueueConfig::default(),
+            worker_threads: None,
+            drain_timeout: DRAIN_TIMEOUT,
+            max_connections: None,
+            max_frame_size: Some(MAX_FRAME_SIZE),
+            max_pending_calls: None,
+            rate_limit: None,
+        }
+    }
+}
+
+/// The requests a connection may send: `burst` at once, then `rate` per
+/// second. The requests past the limit are refused with `LimitExceeded`,
+/// and a peer that keeps sending them is disconnected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub rate: u32,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Allows `rate` requests per second, with a burst of as many.
+    pub fn per_second(rate: u32) -> Self {
+        Self { rate, burst: rate }
+    }
+}
+
+/// Everything the server binary can be configured with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub listen: Vec<Listen>,
+    /// The address of the metrics HTTP endpoint, disabled when there is none.
+    pub metrics: Option<String>,
+    pub log: LogConfig,
+    pub limits: Limits,
+    pub acl: AclPolicy,
+    /// Pings the clients that support it, and drops the silent ones.
+    pub heartbeat: Option<Heartbeat>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![Listen {
+                transport: Transport::Tcp,
+                address: SERVER_ADDRESS.to_string(),
+            }],
+            metrics: None,
+            log: LogConfig::default(),
+            limits: Limits::default(),
+            acl: AclPolicy::default(),
+            heartbeat: Some(Heartbeat::default()),
+        }
+    }
+}
+
+/// The config file as written, every setting being optional.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<Vec<String>>,
+    metrics: Option<String>,
+    log: FileLog,
+    limits: FileLimits,
+    acl: FileAcl,
+    heartbeat: FileHeartbeat,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileLog {
+    level: Option<String>,
+    format: Option<String>,
+    file: Option<PathBuf>,
+    max_size: Option<u64>,
+    rotation: Option<String>,
+    keep: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileLimits {
+    queue_capacity: Option<usize>,
+    overflow: Option<String>,
+    worker_threads: Option<usize>,
+    /// In seconds.
+    drain_timeout: Option<f64>,
+    max_connections: Option<usize>,
+    max_frame_size: Option<usize>,
+    max_pending_calls: Option<usize>,
+    rate_limit: Option<u32>,
+    rate_burst: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileHeartbeat {
+    /// In seconds.
+    interval: Option<f64>,
+    misses: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileAcl {
+    default: Option<String>,
+    allow: Option<Vec<String>>,
+    deny: Option<Vec<String>>,
+}
+
+impl ServerConfig {
+    /// Reads the settings from `ENV_SERVER_ADDRESS`, `ENV_METRICS_ADDRESS`,
+    /// `ENV_WORKER_THREADS`, `ENV_DRAIN_TIMEOUT` and the variables of [`LogConfig::from_env`],
+    /// [`QueueConfig::from_env`] and [`Heartbeat::from_env`], falling back to the defaults.
+    /// An `ENV_SERVER_ADDRESS` that is not a listen address is an error, like
+    /// a bad `--listen`.
+    pub fn from_env() -> Result<Self, Error> {
+        let address = std::env::var(ENV_SERVER_ADDRESS).unwrap_or(SERVER_ADDRESS.to_owned());
+        let listen = address
+            .parse::<Listen>()
+            .map_err(|err| Error::Others(format!("{}: {}", ENV_SERVER_ADDRESS, err)))?;
+        let worker_threads = std::env::var(ENV_WORKER_THREADS)
+            .ok()
+            .and_then(|var| var.parse::<usize>().ok())
+            .filter(|workers| *workers > 0);
+        let drain_timeout = std::env::var(ENV_DRAIN_TIMEOUT)
+            .ok()
+            .and_then(|var| seconds(&var).ok())
+            .unwrap_or(DRAIN_TIMEOUT);
+
+        Ok(Self {
+            listen: vec![listen],
+            metrics: std::env::var(ENV_METRICS_ADDRESS).ok(),
+            log: LogConfig::from_env(),
+            limits: Limits {
+                queue: QueueConfig::from_env(),
+                worker_threads,
+                drain_timeout,
+                ..Limits::default()
+            },
+            acl: AclPolicy::default(),
+            heartbeat: Heartbeat::from_env(),
+        })
+    }
+
+    /// The settings of the environment, then of the config file named by
+    /// `--config` or `ENV_SERVER_CONFIG`, then of the flags. The program
+    /// name is not part of `args`.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, Error> {
+        let args: Vec<String> = args.into_iter().collect();
+        let mut config = Self::from_env()?;
+
+        let file = args
+            .iter()
+            .enumerate()
+            .find_map(|(index, arg)| match arg.as_str() {
+                "-c" | "--config" => Some(args.get(index + 1).cloned()),
+                arg => arg.strip_prefix("--config=").map(|file| Some(file.into())),
+            });
+        let file = match file {
+            Some(Some(file)) => Some(file),
+            Some(None) => return Err(Error::Others("--config expects a value".into())),
+            None => std::env::var(ENV_SERVER_CONFIG).ok(),
+        };
+        if let Some(file) = file {
+            config = config.load(file)?;
+        }
+        config.apply_args(args)
+    }
+
+    /// Applies the settings of a TOML config file.
+    pub fn load(self, path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let text = std::fs::read_to_string(&path)
+            .map_err(|err| Error::Others(format!("{}: {}", path.display(), err)))?;
+        self.apply_toml(&text)
+            .map_err(|err| Error::Others(format!("{}: {}", path.display(), err)))
+    }
+
+    /// Applies the settings of a TOML document, keeping those it leaves out.
+    pub fn apply_toml(mut self, text: &str) -> Result<Self, Error> {
+        let file: FileConfig =
+            toml::from_str(text).map_err(|err| Error::Others(err.to_string()))?;
+
+        if let Some(listen) = file.listen {
+            self.listen = parse_all(&listen)?;
+        }
+        if let Some(metrics) = file.metrics {
+            self.metrics = Some(metrics).filter(|metrics| !metrics.is_empty());
+        }
+        if let Some(level) = file.log.level {
+            self.log.level = parse_level(&level)?;
+        }
+        if let Some(format) = file.log.format {
+            self.log.format = parse_named::<LogFormat>("log format", &format)?;
+        }
+        if let Some(file) = file.log.file {
+            self.log.file = Some(file).filter(|file| !file.as_os_str().is_empty());
+        }
+        if let Some(max_size) = file.log.max_size {
+            self.log.max_size = Some(max_size).filter(|size| *size > 0);
+        }
+        if let Some(rotation) = file.log.rotation {
+            self.log.rotation = parse_named::<Rotation>("log rotation", &rotation)?;
+        }
+        if let Some(keep) = file.log.keep {
+            self.log.keep = keep;
+        }
+        if let Some(capacity) = file.limits.queue_capacity {
+            self.limits.queue.capacity = positive("queue_capacity", capacity)?;
+        }
+        if let Some(overflow) = file.limits.overflow {
+            self.limits.queue.overflow = parse_named::<OverflowPolicy>("overflow", &overflow)?;
+        }
+        if let Some(workers) = file.limits.worker_threads {
+            self.limits.worker_threads = Some(positive("worker_threads", workers)?);
+        }
+        if let Some(drain_timeout) = file.limits.drain_timeout {
+            self.limits.drain_timeout = seconds(&drain_timeout.to_string())?;
+        }
+        if let Some(max) = file.limits.max_connections {
+            self.limits.max_connections = unlimited_at_zero(max);
+        }
+        if let Some(max) = file.limits.max_frame_size {
+            self.limits.max_frame_size = unlimited_at_zero(max);
+        }
+        if let Some(max) = file.limits.max_pending_calls {
+            self.limits.max_pending_calls = unlimited_at_zero(max);
+        }
+        self.limits.rate_limit = rate_limit(
+            self.limits.rate_limit,
+            file.limits.rate_limit,
+            file.limits.rate_burst,
+        )?;
+        let interval = match file.heartbeat.interval {
+            Some(interval) => Some(seconds(&interval.to_string())?),
+            None => None,
+        };
+        self.heartbeat = heartbeat(self.heartbeat, interval, file.heartbeat.misses)?;
+        if let Some(default) = file.acl.default {
+            self.acl.default = parse_named::<AclAction>("acl default", &default)?;
+        }
+        if let Some(allow) = file.acl.allow {
+            self.acl.allow = parse_all(&allow)?;
+        }
+        if let Some(deny) = file.acl.deny {
+            self.acl.deny = parse_all(&deny)?;
+        }
+        Ok(self)
+    }
+
+    /// Applies the flags listed in [`USAGE`]. `--config` is skipped, it is
+    /// read by [`ServerConfig::from_args`]. The listen addresses and the ACL
+    /// rules given as flags replace those of the file.
+    pub fn apply_args<I: IntoIterator<Item = String>>(mut self, args: I) -> Result<Self, Error> {
+        let mut listen = Vec::new();
+        let mut allow = Vec::new();
+        let mut deny = Vec::new();
+        let mut rate = None;
+        let mut burst = None;
+        let mut interval = None;
+        let mut misses = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value)),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline
+                    .map(String::from)
+                    .or_else(|| args.next())
+                    .ok_or_else(|| Error::Others(format!("{} expects a value", flag)))
+            };
+            match flag.as_str() {
+                "-c" | "--config" => {
+                    value()?;
+                }
+                "-l" | "--listen" => listen.push(value()?.parse::<Listen>()?),
+                "--metrics" => self.metrics = Some(value()?),
+                "--log-level" => self.log.level = parse_level(&value()?)?,
+                "--log-format" => self.log.format = parse_named("log format", &value()?)?,
+                "--log-file" => self.log.file = Some(PathBuf::from(value()?)),
+                "--log-max-size" => {
+                    self.log.max_size = Some(positive("--log-max-size", parse_number(&value()?)?)?)
+                }
+                "--log-rotation" => self.log.rotation = parse_named("log rotation", &value()?)?,
+                "--log-keep" => self.log.keep = parse_number(&value()?)?,
+                "--queue-capacity" => {
+                    self.limits.queue.capacity =
+                        positive("--queue-capacity", parse_number(&value()?)?)?
+                }
+                "--overflow" => self.limits.queue.overflow = parse_named("overflow", &value()?)?,
+                "--worker-threads" => {
+                    self.limits.worker_threads =
+                        Some(positive("--worker-threads", parse_number(&value()?)?)?)
+                }
+                "--drain-timeout" => self.limits.drain_timeout = seconds(&value()?)?,
+                "--max-connections" => {
+                    self.limits.max_connections = unlimited_at_zero(parse_number(&value()?)?)
+                }
+                "--max-frame-size" => {
+                    self.limits.max_frame_size = unlimited_at_zero(parse_number(&value()?)?)
+                }
+                "--max-pending-calls" => {
+                    self.limits.max_pending_calls = unlimited_at_zero(parse_number(&value()?)?)
+                }
+                "--rate-limit" => rate = Some(parse_number(&value()?)?),
+                "--rate-burst" => burst = Some(parse_number(&value()?)?),
+                "--heartbeat-interval" => interval = Some(seconds(&value()?)?),
+                "--heartbeat-misses" => misses = Some(parse_number(&value()?)?),
+                "--allow" => allow.push(value()?.parse::<IpRule>()?),
+                "--deny" => deny.push(value()?.parse::<IpRule>()?),
+                "--acl-default" => self.acl.default = parse_named("acl default", &value()?)?,
+                flag => return Err(Error::Others(format!("Unknown option: {}", flag))),
+            }
+        }
+
+        if !listen.is_empty() {
+            self.listen = listen;
+        }
+        if !allow.is_empty() {
+            self.acl.allow = allow;
+        }
+        if !deny.is_empty() {
+            self.acl.deny = deny;
+        }
+        self.limits.rate_limit = rate_limit(self.limits.rate_limit, rate, burst)?;
+        self.heartbeat = heartbeat(self.heartbeat, interval, misses)?;
+        if self.listen.is_empty() {
+            return Err(Error::Others("No listen address".into()));
+        }
+        Ok(self)
+    }
+}
+
+fn parse_all<T: FromStr<Err = Error>>(values: &[String]) -> Result<Vec<T>, Error> {
+    values.iter().map(|value| value.parse::<T>()).collect()
+}
+
+fn parse_named<T: FromStr>(name: &str, value: &str) -> Result<T, Error> {
+    value
+        .to_lowercase()
+        .parse::<T>()
+        .map_err(|_| Error::Others(format!("Invalid {}: {}", name, value)))
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Result<T, Error> {
+    value
+        .parse::<T>()
+        .map_err(|_| Error::Others(format!("Invalid number: {}", value)))
+}
+
+fn positive<T: Default + PartialEq>(name: &str, value: T) -> Result<T, Error> {
+    if value == T::default() {
+        return Err(Error::Others(format!("{} must be greater than 0", name)));
+    }
+    Ok(value)
+}
+
+/// A limit written as a number, 0 meaning that there is none.
+fn unlimited_at_zero(value: usize) -> Option<usize> {
+    Some(value).filter(|value| *value > 0)
+}
+
+/// Applies a new rate and burst to the rate limit. A rate of 0 removes the
+/// limit, and a new rate without a burst allows a burst of the rate.
+fn rate_limit(
+    current: Option<RateLimit>,
+    rate: Option<u32>,
+    burst: Option<u32>,
+) -> Result<Option<RateLimit>, Error> {
+    let limit = match rate {
+        Some(0) => None,
+        Some(rate) => Some(RateLimit::per_second(rate)),
+        None => current,
+    };
+    match (limit, burst) {
+        (limit, None) => Ok(limit),
+        (Some(limit), Some(burst)) => Ok(Some(RateLimit {
+            burst: positive("rate burst", burst)?,
+            ..limit
+        })),
+        (None, Some(_)) => Err(Error::Others("The rate burst needs a rate limit".into())),
+    }
+}
+
+/// Applies a new interval and misses to the heartbeat. An interval of 0
+/// disables it.
+fn heartbeat(
+    current: Option<Heartbeat>,
+    interval: Option<Duration>,
+    misses: Option<u32>,
+) -> Result<Option<Heartbeat>, Error> {
+    let heartbeat = match interval {
+        Some(interval) if interval.is_zero() => None,
+        Some(interval) => Some(Heartbeat {
+            interval,
+            ..current.unwrap_or_default()
+        }),
+        None => current,
+    };
+    match (heartbeat, misses) {
+        (heartbeat, None) => Ok(heartbeat),
+        (Some(heartbeat), Some(misses)) => Ok(Some(Heartbeat {
+            misses: positive("heartbeat misses", misses)?,
+            ..heartbeat
+        })),
+        (None, Some(_)) => Err(Error::Others(
+            "The heartbeat misses need a heartbeat interval".into(),
+        )),
+    }
+}
+
+fn seconds(value: &str) -> Result<Duration, Error> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| Error::Others(format!("Invalid number of seconds: {}", value)))
+}
+
+fn parse_level(level: &str) -> Result<log::LevelFilter, Error> {
+    level
+        .parse::<log::LevelFilter>()
+        .map_err(|_| Error::Others(format!("Invalid log level: {}", level)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use crate::{
+        logger::{LogFormat, Rotation},
+        options::Heartbeat,
+        outbound::OverflowPolicy,
+        socket::MAX_FRAME_SIZE,
+    };
+
+    use super::{AclAction, IpRule, Listen, RateLimit, ServerConfig, Transport};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn config_file_and_flags() {
+        let config = ServerConfig::default()
+            .apply_toml(
+                r#"
+                listen = ["tcp://0.0.0.0:1986", "127.0.0.1:1987"]
+                metrics = "127.0.0.1:9186"
+
+                [log]
+                level = "debug"
+                format = "json"
+                rotation = "daily"
+
+                [limits]
+                queue_capacity = 64
+                overflow = "disconnect"
+                drain_timeout = 2.5
+                max_connections = 100
+                max_pending_calls = 16
+                rate_limit = 50
+
+                [heartbeat]
+                interval = 5
+                misses = 2
+
+                [acl]
+                default = "deny"
+                allow = ["127.0.0.1", "10.0.0.0/8"]
+                "#,
+            )
+            .unwrap();
+        assert_eq!(
+            config.listen,
+            vec![
+                Listen {
+                    transport: Transport::Tcp,
+                    address: "0.0.0.0:1986".into()
+                },
+                Listen {
+                    transport: Transport::Tcp,
+                    address: "127.0.0.1:1987".into()
+                },
+            ]
+        );
+        assert_eq!(config.metrics.as_deref(), Some("127.0.0.1:9186"));
+        assert_eq!(config.log.level, log::LevelFilter::Debug);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.log.rotation, Rotation::Daily);
+        assert_eq!(config.limits.queue.capacity, 64);
+        assert_eq!(config.limits.queue.overflow, OverflowPolicy::Disconnect);
+        assert_eq!(config.limits.drain_timeout, Duration::from_millis(2500));
+        assert_eq!(config.limits.max_connections, Some(100));
+        assert_eq!(config.limits.max_frame_size, Some(MAX_FRAME_SIZE));
+        assert_eq!(config.limits.max_pending_calls, Some(16));
+        assert_eq!(config.limits.rate_limit, Some(RateLimit::per_second(50)));
+        assert_eq!(
+            config.heartbeat,
+            Some(Heartbeat {
+                interval: Duration::from_secs(5),
+                misses: 2
+            })
+        );
+        assert_eq!(config.acl.default, AclAction::Deny);
+
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert!(config.acl.permits(ip("127.0.0.1")));
+        assert!(config.acl.permits(ip("10.20.30.40")));
+        assert!(config.acl.permits(ip("::ffff:10.0.0.1")));
+        assert!(!config.acl.permits(ip("192.168.1.1")));
+
+        let config = config
+            .apply_args(args(
+                "--config x.toml -l 127.0.0.1:2000 --log-level=warn --worker-threads 2 --deny 10.1.0.0/16 --max-frame-size 0 --rate-burst=80 --heartbeat-interval 0",
+            ))
+            .unwrap();
+        assert_eq!(config.listen.len(), 1);
+        assert_eq!(config.listen[0].to_string(), "tcp://127.0.0.1:2000");
+        assert_eq!(config.log.level, log::LevelFilter::Warn);
+        assert_eq!(config.limits.worker_threads, Some(2));
+        assert_eq!(config.limits.queue.capacity, 64);
+        assert_eq!(config.limits.max_frame_size, None);
+        assert_eq!(config.heartbeat, None);
+        assert_eq!(
+            config.limits.rate_limit,
+            Some(RateLimit {
+                rate: 50,
+                burst: 80
+            })
+        );
+        assert!(config.acl.permits(ip("10.20.30.40")));
+        assert!(!config.acl.permits(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn config_errors() {
+        let config = ServerConfig::default;
+        assert!(config()
+            .apply_toml("listen = [\"udp://0.0.0.0:1\"]")
+            .is_err());
+        assert!(config().apply_toml("port = 1986").is_err());
+        assert!(config().apply_toml("[log]\nlevel = \"loud\"").is_err());
+        assert!(config().apply_toml("[limits]\nqueue_capacity = 0").is_err());
+        assert!(config().apply_args(args("--listen")).is_err());
+        assert!(config().apply_args(args("--allow 10.0.0.0/33")).is_err());
+        assert!(config().apply_args(args("--verbose")).is_err());
+        assert!(config().apply_args(args("--drain-timeout -1")).is_err());
+        assert!(config().apply_args(args("--rate-burst 10")).is_err());
+        assert!(config()
+            .apply_args(args("--rate-limit 10 --rate-burst 0"))
+            .is_err());
+        assert!(config()
+            .apply_args(args("--heartbeat-interval 0 --heartbeat-misses 2"))
+            .is_err());
+
+        assert!("0.0.0.0/0"
+            .parse::<IpRule>()
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+    }
+}
@@ -1,10 +1,24 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use json_elem::JsonElem;
-use tokio::net::TcpStream;
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard};
 
 use crate::{
-    error::{CommonErrors, RemoteError},
-    message::{CallMethod, Event, MessageType, SocketMessage},
-    socket::{Socket, ENV_SERVER_ADDRESS, SERVER_ADDRESS},
+    error::{CommonErrors, ErrorCode, ErrorOrigin, RemoteError},
+    message::{
+        CallMethod, Event, EventReport, GoingAway, MessageType, ObjectDescription, ResponseStatus,
+        SocketMessage, StreamCall, STREAMING,
+    },
+    options::ClientOptions,
+    socket::Socket,
+    stream::{RemoteStream, STREAM_WINDOW},
+    trace::TraceContext,
 };
 
 /// An object that is responsible for remote object method calls,
@@ -12,24 +26,84 @@ use crate::{
 #[derive(Clone, Debug)]
 pub struct Connector {
     socket: Socket,
+    timeout: Option<Duration>,
+    stream_window: u32,
+    /// Held while a response is awaited, so that the calls sharing the
+    /// connection, and the streams, do not read each other's responses. It
+    /// counts the responses still due to the calls given up while waiting,
+    /// such as under a timeout, which the next call discards first.
+    busy: Arc<Mutex<usize>>,
+    /// The calls waiting for the connection, which the reader looking for
+    /// the going-away notice makes way for.
+    waiting: Arc<Waiting>,
+}
+
+#[derive(Debug, Default)]
+struct Waiting {
+    count: AtomicUsize,
+    notify: Notify,
+}
+
+/// Counts a call among the waiting ones until it is dropped.
+struct WaitingTurn<'a>(&'a Waiting);
+
+impl<'a> WaitingTurn<'a> {
+    fn new(waiting: &'a Waiting) -> Self {
+        waiting.count.fetch_add(1, Ordering::SeqCst);
+        waiting.notify.notify_one();
+        Self(waiting)
+    }
+}
+
+impl Drop for WaitingTurn<'_> {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl Connector {
     /// Connects to the IPC server.
     pub async fn connect() -> Result<Self, RemoteError> {
-        let server_address = std::env::var(ENV_SERVER_ADDRESS).unwrap_or(SERVER_ADDRESS.to_owned());
-        let stream = TcpStream::connect(server_address)
+        Self::connect_with(ClientOptions::default()).await
+    }
+
+    /// Connects to the IPC server with the given client name, address and codec.
+    pub async fn connect_with(options: ClientOptions) -> Result<Self, RemoteError> {
+        let socket = Socket::connect(&options)
             .await
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+            .map_err(RemoteError::client)?;
 
-        let addr = stream.peer_addr().unwrap();
         Ok(Self {
-            socket: Socket::new(stream, addr),
+            socket,
+            timeout: None,
+            stream_window: STREAM_WINDOW,
+            busy: Arc::new(Mutex::new(0)),
+            waiting: Arc::default(),
         })
     }
 
+    /// Sets how long the remote calls are expected to take. The resulting
+    /// deadline is passed to the shared object in its [`CallContext`], which
+    /// may give up on the call once it has passed.
+    ///
+    /// [`CallContext`]: crate::CallContext
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how many chunks of a stream the owner may send ahead of the ones
+    /// taken from the [`RemoteStream`], [`STREAM_WINDOW`] by default.
+    pub fn with_stream_window(mut self, window: u32) -> Self {
+        self.stream_window = window.max(1);
+        self
+    }
+
     /// Calls shared object methods from other processes.
     /// It has an optional parameters, the value is in JsonElem type.
+    ///
+    /// The call carries a trace context. Made while serving a remote call,
+    /// it is a child of that call, otherwise it starts a new trace.
     pub async fn remote_call(
         &self,
         object: &str,
@@ -41,37 +115,198 @@ impl Connector {
             method: method.to_string(),
             param,
         };
+        let trace = TraceContext::outgoing();
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "remote_call",
+            object,
+            method,
+            trace_id = %trace.trace_id,
+            span_id = %trace.span_id,
+        );
 
         let msg = SocketMessage::new()
             .set_kind(MessageType::RemoteCallRequest)
-            .set_body(&call_method.as_bytes());
+            .set_body(&call_method.as_bytes())
+            .set_trace(Some(trace))
+            .set_deadline(self.deadline());
 
-        self.socket
-            .write(&msg.as_bytes())
-            .await
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+        let call = self.call(msg);
+        #[cfg(feature = "tracing")]
+        let call = tracing::Instrument::instrument(call, span);
+        call.await
+    }
 
-        let mut buf = Vec::new();
-        let n = self
-            .socket
-            .read(&mut buf)
-            .await
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+    /// Calls a shared object method that streams its result in chunks, see
+    /// [`SharedObject::remote_stream`]. The owner sends the chunks as the
+    /// returned stream takes them, at most a stream window ahead.
+    ///
+    /// The connection is held until the stream has ended or is dropped, the
+    /// other calls made meanwhile wait for it.
+    ///
+    /// [`SharedObject::remote_stream`]: crate::SharedObject::remote_stream
+    pub async fn remote_stream(
+        &self,
+        object: &str,
+        method: &str,
+        param: JsonElem,
+    ) -> Result<RemoteStream, RemoteError> {
+        if !self.socket.supports(STREAMING) {
+            return Err(RemoteError::from_code(
+                ErrorCode::MethodNotFound,
+                ErrorOrigin::Client,
+                "The server does not support streaming.",
+            ));
+        }
+
+        let stream_call = StreamCall {
+            object: object.to_string(),
+            method: method.to_string(),
+            param,
+            window: self.stream_window,
+        };
+        let msg = SocketMessage::new()
+            .set_kind(MessageType::StreamRequest)
+            .set_body(&stream_call.as_bytes())
+            .set_trace(Some(TraceContext::outgoing()))
+            .set_deadline(self.deadline());
+
+        let mut busy = self.take_turn().await;
+        self.discard(&mut busy).await?;
+        self.socket.send(&msg).await.map_err(RemoteError::client)?;
+        Ok(RemoteStream::spawn(
+            self.socket.clone(),
+            self.stream_window,
+            busy,
+        ))
+    }
+
+    /// The deadline of a call made now, in milliseconds since the Unix epoch.
+    fn deadline(&self) -> Option<u64> {
+        let deadline = SystemTime::now() + self.timeout?;
+        deadline
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|deadline| deadline.as_millis() as u64)
+    }
+
+    /// Takes the connection, once the calls before have their responses.
+    async fn take_turn(&self) -> OwnedMutexGuard<usize> {
+        let _waiting = WaitingTurn::new(&self.waiting);
+        self.busy.clone().lock_owned().await
+    }
+
+    /// Sends the request and reads its response. A call given up before its
+    /// response came leaves the response to be discarded by the next one.
+    async fn request(&self, msg: &SocketMessage) -> Result<SocketMessage, RemoteError> {
+        let mut abandoned = self.take_turn().await;
+        self.discard(&mut abandoned).await?;
+        self.socket.send(msg).await.map_err(RemoteError::client)?;
+
+        *abandoned += 1;
+        let resp = self.socket.receive().await.map_err(RemoteError::client)?;
+        *abandoned -= 1;
+        Ok(resp)
+    }
 
-        let resp = serde_json::from_slice::<SocketMessage>(&buf[0..n])
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+    /// Reads the responses of the calls given up before.
+    async fn discard(&self, abandoned: &mut usize) -> Result<(), RemoteError> {
+        while *abandoned > 0 {
+            let resp = self.socket.receive().await.map_err(RemoteError::client)?;
+            log::debug!("[{}] Discarded {}", self.socket.ip_address(), resp);
+            *abandoned -= 1;
+        }
+        Ok(())
+    }
+
+    async fn call(&self, msg: SocketMessage) -> Result<JsonElem, RemoteError> {
+        let resp = self.request(&msg).await?;
         if resp.kind() == MessageType::RemoteCallResponse {
+            match resp.status() {
+                Some(ResponseStatus::Ok) => {
+                    JsonElem::try_from(resp.body()).map_err(invalid_response)
+                }
+                Some(ResponseStatus::Error | ResponseStatus::Broker) => {
+                    Err(serde_json::from_slice::<RemoteError>(resp.body())
+                        .map_err(invalid_response)?)
+                }
+                // A legacy peer does not tell, so the body has to be guessed.
+                Some(ResponseStatus::Unknown) | None => {
+                    if let Ok(err) = serde_json::from_slice::<RemoteError>(resp.body()) {
+                        Err(err)
+                    } else {
+                        let json = JsonElem::try_from(resp.body()).map_err(invalid_response)?;
+                        Ok(json)
+                    }
+                }
+            }
+        } else {
+            Err(RemoteError::from(CommonErrors::InvalidResponseData)
+                .with_origin(ErrorOrigin::Client))
+        }
+    }
+
+    /// The going-away notice of the server, once a call, or
+    /// [`Connector::wait_going_away`], has read it. A server going away
+    /// refuses the new calls with `ServerConnectionError`, so a client should
+    /// reconnect when a call fails and there is a notice.
+    pub fn going_away(&self) -> Option<GoingAway> {
+        self.socket.going_away()
+    }
+
+    /// Completes once the server has announced that it is going away, so
+    /// that the client can reconnect before its calls are refused. The
+    /// connection is read for the notice while no call is using it, the
+    /// calls made meanwhile go first.
+    pub async fn wait_going_away(&self) -> GoingAway {
+        tokio::select! {
+            notice = self.socket.wait_going_away() => notice,
+            never = self.read_idle() => match never {},
+        }
+    }
+
+    /// Reads the connection whenever no call is waiting for it. What it reads
+    /// besides the notice are the responses of the calls given up before.
+    async fn read_idle(&self) -> std::convert::Infallible {
+        loop {
+            let mut abandoned = self.busy.lock().await;
+            while self.waiting.count.load(Ordering::SeqCst) == 0 {
+                tokio::select! {
+                    _ = self.waiting.notify.notified() => {}
+                    resp = self.socket.receive() => match resp {
+                        Ok(resp) if *abandoned > 0 => {
+                            log::debug!("[{}] Discarded {}", self.socket.ip_address(), resp);
+                            *abandoned -= 1;
+                        }
+                        Ok(resp) => {
+                            log::warn!("[{}] Unexpected {}", self.socket.ip_address(), resp);
+                        }
+                        // Nothing more to read, until a call wants to find out.
+                        Err(_) => self.waiting.notify.notified().await,
+                    }
+                }
+            }
+            drop(abandoned);
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Asks the owner of the object which methods it supports.
+    pub async fn describe(&self, object: &str) -> Result<ObjectDescription, RemoteError> {
+        let msg = SocketMessage::new()
+            .set_kind(MessageType::DescribeRequest)
+            .set_body(object.as_bytes());
+
+        let resp = self.request(&msg).await?;
+        if resp.kind() == MessageType::DescribeResponse {
             if let Ok(err) = serde_json::from_slice::<RemoteError>(resp.body()) {
                 Err(err)
             } else {
-                let json = JsonElem::try_from(resp.body())
-                    .map_err(|err| RemoteError::new(JsonElem::String(err.to_string())))?;
-                Ok(json)
+                serde_json::from_slice::<ObjectDescription>(resp.body()).map_err(invalid_response)
             }
         } else {
-            Err(RemoteError::new(JsonElem::String(
-                CommonErrors::InvalidResponseData.to_string(),
-            )))
+            Err(RemoteError::from(CommonErrors::InvalidResponseData)
+                .with_origin(ErrorOrigin::Client))
         }
     }
 
@@ -79,19 +314,45 @@ impl Connector {
     /// boadcast the message to all subscribed processes.
     /// Parameters in JsonElem type.
     pub async fn send_event(&self, event: &str, param: JsonElem) -> Result<(), RemoteError> {
-        let event = Event {
-            event: event.to_string(),
-            param,
-        };
-
-        let msg = SocketMessage::new()
-            .set_kind(MessageType::SendEventRequest)
-            .set_body(&event.as_bytes());
+        let msg = event_message(event, param, false);
+        self.socket.send(&msg).await.map_err(RemoteError::client)
+    }
 
-        self.socket
-            .write(&msg.as_bytes())
-            .await
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
-        Ok(())
+    /// Sends the event to the server and waits until the server has queued
+    /// it for all subscribed processes. The returned report tells how many
+    /// subscribers the event was queued for and whose queue rejected it, so
+    /// that critical notifications can be retried.
+    pub async fn send_event_with_ack(
+        &self,
+        event: &str,
+        param: JsonElem,
+    ) -> Result<EventReport, RemoteError> {
+        let resp = self.request(&event_message(event, param, true)).await?;
+        if resp.kind() == MessageType::SendEventResponse {
+            if let Ok(err) = serde_json::from_slice::<RemoteError>(resp.body()) {
+                Err(err)
+            } else {
+                serde_json::from_slice::<EventReport>(resp.body()).map_err(invalid_response)
+            }
+        } else {
+            Err(RemoteError::from(CommonErrors::InvalidResponseData)
+                .with_origin(ErrorOrigin::Client))
+        }
     }
 }
+
+fn event_message(event: &str, param: JsonElem, ack: bool) -> SocketMessage {
+    let event = Event {
+        event: event.to_string(),
+        param,
+        ack,
+    };
+
+    SocketMessage::new()
+        .set_kind(MessageType::SendEventRequest)
+        .set_body(&event.as_bytes())
+}
+
+pub(crate) fn invalid_response(err: impl std::fmt::Display) -> RemoteError {
+    RemoteError::from_code(ErrorCode::InvalidResponseData, ErrorOrigin::Client, err)
+}
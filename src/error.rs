@@ -1,19 +1,62 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
 use json_elem::JsonElem;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use strum::{AsRefStr, Display, EnumString};
 
-/// An object that is responsible to house error in JsonElem type
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+use crate::message::MessageType;
+
+/// An object that is responsible to house error in JsonElem type.
+///
+/// Besides the message in `error`, an error carries a stable [`ErrorCode`]
+/// to branch on, optional details and the [`ErrorOrigin`] it comes from.
+/// Errors of peers that only send `{"error": ...}` are still accepted,
+/// their code is guessed from the message.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(from = "WireRemoteError")]
 pub struct RemoteError {
     pub error: JsonElem,
+    pub code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<JsonElem>,
+    pub origin: ErrorOrigin,
 }
 
 impl RemoteError {
-    /// Creates an Error object in JsonElem
+    /// Creates an Error object in JsonElem. It is the error of a shared
+    /// object, with the [`ErrorCode::Application`] code.
     pub fn new(error: JsonElem) -> Self {
-        Self { error }
+        Self {
+            error,
+            code: ErrorCode::Application,
+            details: None,
+            origin: ErrorOrigin::Object,
+        }
+    }
+
+    /// Creates an error with the given code, origin and message.
+    pub fn from_code(code: ErrorCode, origin: ErrorOrigin, message: impl Display) -> Self {
+        Self {
+            error: JsonElem::String(message.to_string()),
+            code,
+            details: None,
+            origin,
+        }
+    }
+
+    /// An error of this process while talking to the server.
+    pub(crate) fn client(err: impl Display) -> Self {
+        Self::from_code(ErrorCode::ClientConnectionError, ErrorOrigin::Client, err)
+    }
+
+    pub fn with_details(mut self, details: JsonElem) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn with_origin(mut self, origin: ErrorOrigin) -> Self {
+        self.origin = origin;
+        self
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
@@ -21,6 +64,14 @@ impl RemoteError {
     }
 }
 
+/// The common errors are raised by the server, use
+/// [`RemoteError::with_origin`] for the ones raised elsewhere.
+impl From<CommonErrors> for RemoteError {
+    fn from(common: CommonErrors) -> Self {
+        Self::from_code(ErrorCode::from(&common), ErrorOrigin::Broker, common)
+    }
+}
+
 impl Display for RemoteError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.error)
@@ -29,6 +80,141 @@ impl Display for RemoteError {
 
 impl std::error::Error for RemoteError {}
 
+/// A [`RemoteError`] as it is read, with the fields that older peers do not send.
+#[derive(Deserialize)]
+struct WireRemoteError {
+    error: JsonElem,
+    #[serde(default)]
+    code: Option<ErrorCode>,
+    #[serde(default)]
+    details: Option<JsonElem>,
+    #[serde(default)]
+    origin: ErrorOrigin,
+}
+
+impl From<WireRemoteError> for RemoteError {
+    fn from(wire: WireRemoteError) -> Self {
+        let code = wire.code.unwrap_or_else(|| match &wire.error {
+            JsonElem::String(message) => CommonErrors::from_str(message)
+                .map(|common| ErrorCode::from(&common))
+                .unwrap_or(ErrorCode::Application),
+            _ => ErrorCode::Application,
+        });
+        Self {
+            error: wire.error,
+            code,
+            details: wire.details,
+            origin: wire.origin,
+        }
+    }
+}
+
+/// The stable code of a [`RemoteError`]. It is sent as its number, and
+/// the numbers a peer does not know are read as `Unknown`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Unknown,
+    /// An error returned by the shared object itself.
+    Application,
+    ObjectNotFound,
+    MethodNotFound,
+    InvalidParams,
+    SerdeParseError,
+    InvalidResponseData,
+    ClientConnectionError,
+    ServerConnectionError,
+    RemoteConnectionError,
+    /// A request turned down by one of the server limits.
+    LimitExceeded,
+    /// A stream the caller cancelled.
+    Cancelled,
+}
+
+impl ErrorCode {
+    pub fn as_u16(&self) -> u16 {
+        match self {
+            ErrorCode::Unknown => 0,
+            ErrorCode::Application => 1,
+            ErrorCode::ObjectNotFound => 2,
+            ErrorCode::MethodNotFound => 3,
+            ErrorCode::InvalidParams => 4,
+            ErrorCode::SerdeParseError => 5,
+            ErrorCode::InvalidResponseData => 6,
+            ErrorCode::ClientConnectionError => 7,
+            ErrorCode::ServerConnectionError => 8,
+            ErrorCode::RemoteConnectionError => 9,
+            ErrorCode::LimitExceeded => 10,
+            ErrorCode::Cancelled => 11,
+        }
+    }
+
+    pub fn from_u16(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::Application,
+            2 => ErrorCode::ObjectNotFound,
+            3 => ErrorCode::MethodNotFound,
+            4 => ErrorCode::InvalidParams,
+            5 => ErrorCode::SerdeParseError,
+            6 => ErrorCode::InvalidResponseData,
+            7 => ErrorCode::ClientConnectionError,
+            8 => ErrorCode::ServerConnectionError,
+            9 => ErrorCode::RemoteConnectionError,
+            10 => ErrorCode::LimitExceeded,
+            11 => ErrorCode::Cancelled,
+            _ => ErrorCode::Unknown,
+        }
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u16(self.as_u16())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value: u16 = Deserialize::deserialize(deserializer)?;
+        Ok(ErrorCode::from_u16(value))
+    }
+}
+
+impl From<&CommonErrors> for ErrorCode {
+    fn from(common: &CommonErrors) -> Self {
+        match common {
+            CommonErrors::Ok => ErrorCode::Unknown,
+            CommonErrors::ObjectNotFound => ErrorCode::ObjectNotFound,
+            CommonErrors::ClientConnectionError => ErrorCode::ClientConnectionError,
+            CommonErrors::ServerConnectionError => ErrorCode::ServerConnectionError,
+            CommonErrors::SerdeParseError => ErrorCode::SerdeParseError,
+            CommonErrors::RemoteConnectionError => ErrorCode::RemoteConnectionError,
+            CommonErrors::InvalidResponseData => ErrorCode::InvalidResponseData,
+        }
+    }
+}
+
+/// Where a [`RemoteError`] was raised.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorOrigin {
+    /// The IPC server.
+    Broker,
+    /// The process that shares the object.
+    Object,
+    /// This process, while talking to the server.
+    Client,
+    /// A peer that does not tell.
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
 /// A list of common error string.
 #[derive(Debug, EnumString, Display, AsRefStr)]
 pub enum CommonErrors {
@@ -51,10 +237,13 @@ pub enum CommonErrors {
 #[derive(Debug)]
 pub enum Error {
     IO(std::io::Error),
-    Atticus(atticus::Error),
     Others(String),
     Serde(serde_json::Error),
     JsonElem(json_elem::error::Error),
+    /// A frame grew past the size limit of its socket.
+    FrameTooLarge(usize),
+    /// A peer sent a kind of message that it is not supposed to send.
+    UnexpectedMessage(MessageType),
 }
 
 impl std::error::Error for Error {}
@@ -63,20 +252,15 @@ impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::IO(err) => write!(f, "{}", err),
-            Error::Atticus(err) => write!(f, "{}", err),
             Error::Others(err) => write!(f, "{}", err),
             Error::Serde(err) => write!(f, "{}", err),
             Error::JsonElem(err) => write!(f, "{}", err),
+            Error::FrameTooLarge(limit) => write!(f, "Frame larger than {} bytes", limit),
+            Error::UnexpectedMessage(kind) => write!(f, "Unexpected {:?} message", kind),
         }
     }
 }
 
-impl From<atticus::Error> for Error {
-    fn from(value: atticus::Error) -> Self {
-        Self::Atticus(value)
-    }
-}
-
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
         Self::IO(value)
@@ -94,3 +278,36 @@ impl From<json_elem::error::Error> for Error {
         Self::JsonElem(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use json_elem::JsonElem;
+
+    use super::{CommonErrors, ErrorCode, ErrorOrigin, RemoteError};
+
+    #[test]
+    fn remote_error_shapes() {
+        let legacy: RemoteError = serde_json::from_str(r#"{"error":"Object not found"}"#).unwrap();
+        assert_eq!(legacy.code, ErrorCode::ObjectNotFound);
+        assert_eq!(legacy.origin, ErrorOrigin::Unknown);
+
+        let legacy: RemoteError = serde_json::from_str(r#"{"error":{"reason":1}}"#).unwrap();
+        assert_eq!(legacy.code, ErrorCode::Application);
+
+        let newer: RemoteError =
+            serde_json::from_str(r#"{"error":"later","code":999,"origin":"moon"}"#).unwrap();
+        assert_eq!(newer.code, ErrorCode::Unknown);
+        assert_eq!(newer.origin, ErrorOrigin::Unknown);
+
+        let err = RemoteError::from(CommonErrors::ObjectNotFound)
+            .with_details(JsonElem::String("mango".into()));
+        assert_eq!(
+            String::from_utf8(err.as_bytes()).unwrap(),
+            r#"{"error":"Object not found","code":2,"details":"mango","origin":"broker"}"#
+        );
+        assert_eq!(
+            serde_json::from_slice::<RemoteError>(&err.as_bytes()).unwrap(),
+            err
+        );
+    }
+}
@@ -1,13 +1,13 @@
 use std::future::Future;
 
 use json_elem::JsonElem;
-use tokio::net::TcpStream;
 
 use crate::{
-    error::CommonErrors,
-    message::{Event, MessageType, SocketMessage},
-    socket::{Socket, ENV_SERVER_ADDRESS, SERVER_ADDRESS},
-    util, RemoteError,
+    error::{CommonErrors, Error},
+    message::{Event, GoingAway, MessageType, SocketMessage},
+    options::ClientOptions,
+    socket::Socket,
+    RemoteError,
 };
 
 #[derive(Clone, Debug)]
@@ -17,17 +17,37 @@ pub struct EventListener {
 
 impl EventListener {
     pub async fn dispatch() -> Result<Self, RemoteError> {
-        let server_address = std::env::var(ENV_SERVER_ADDRESS).unwrap_or(SERVER_ADDRESS.to_owned());
-        let stream = TcpStream::connect(server_address)
+        Self::dispatch_with(ClientOptions::default()).await
+    }
+
+    /// Connects the listener to the IPC server with the given client name, address and codec.
+    pub async fn dispatch_with(options: ClientOptions) -> Result<Self, RemoteError> {
+        let socket = Socket::connect(&options)
             .await
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+            .map_err(RemoteError::client)?;
+
+        Ok(Self { socket })
+    }
 
-        let addr = stream.peer_addr().unwrap();
-        Ok(Self {
-            socket: Socket::new(stream, addr),
-        })
+    /// Completes once the server has announced that it is going away, so
+    /// that the listener can reconnect and subscribe again. The notice is
+    /// read by the task that [`EventListener::listen`] spawns.
+    pub async fn going_away(&self) -> GoingAway {
+        self.socket.wait_going_away().await
     }
 
+    /// Completes once the connection to the server is lost, such as a server
+    /// that died or stopped answering the heartbeat. Like the notice, the
+    /// loss is seen by the task that [`EventListener::listen`] spawns.
+    pub async fn closed(&self) {
+        self.socket.closed().await
+    }
+
+    /// Subscribes to the event and calls the callback with the parameter of
+    /// each one received, on a task of its own. A callback that fails is
+    /// logged and the listening goes on with the next event, so one bad
+    /// event does not end the subscription. It ends when the connection is
+    /// lost.
     pub async fn listen<
         F: Future<Output = Result<(), RE>> + Send,
         RE: std::error::Error + 'static + Send,
@@ -40,52 +60,49 @@ impl EventListener {
         let msg = SocketMessage::new()
             .set_kind(MessageType::SubscribeEventRequest)
             .set_body(event_name.as_bytes());
-        self.socket
-            .write(&msg.as_bytes())
-            .await
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+        self.socket.send(&msg).await.map_err(RemoteError::client)?;
 
         let socket = self.socket.clone();
 
         tokio::spawn(async move {
             loop {
-                let mut buf = Vec::new();
-                let n = socket
-                    .read(&mut buf)
-                    .await
-                    .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))
-                    .unwrap_or_else(|e| {
-                        log::error!("{:?}", e);
-                        0
-                    });
-                if n == 0 {
-                    log::error!(
-                        "listen Error: {}",
-                        CommonErrors::ServerConnectionError.to_string()
-                    );
-                    break;
-                }
-                let sep = util::separate(buf.as_slice());
-                for data in sep {
-                    if let Ok(msg) = serde_json::from_slice::<SocketMessage>(data.as_slice()) {
-                        let param = serde_json::from_slice::<Event>(msg.body()).unwrap();
-                        let call = callback.clone();
-                        match call(param.param).await {
-                            Ok(_) => {
-                                continue;
-                            }
-                            Err(err) => {
-                                log::error!("callback error: {err:?}");
-                                break;
-                            }
-                        }
-                    } else {
+                let msg = match socket.receive().await {
+                    Ok(msg) => msg,
+                    Err(Error::Serde(err)) => {
                         log::error!(
-                            " listenError: {}",
-                            CommonErrors::SerdeParseError.to_string()
+                            " listenError: {}: {}",
+                            CommonErrors::SerdeParseError.to_string(),
+                            err
+                        );
+                        continue;
+                    }
+                    Err(err) => {
+                        log::error!("{:?}", err);
+                        log::error!(
+                            "listen Error: {}",
+                            CommonErrors::ServerConnectionError.to_string()
                         );
                         break;
                     }
+                };
+                if msg.kind() != MessageType::SendEventRequest {
+                    log::warn!("listen: unexpected {}", msg);
+                    continue;
+                }
+                let param = match serde_json::from_slice::<Event>(msg.body()) {
+                    Ok(param) => param,
+                    Err(err) => {
+                        log::error!(
+                            " listenError: {}: {}",
+                            CommonErrors::SerdeParseError.to_string(),
+                            err
+                        );
+                        continue;
+                    }
+                };
+                let call = callback.clone();
+                if let Err(err) = call(param.param).await {
+                    log::error!("callback error: {err:?}");
                 }
             }
         });
@@ -1,18 +1,31 @@
+pub mod codec;
+pub mod config;
 pub mod connector;
 pub mod error;
 pub mod event;
+mod limiter;
 pub mod logger;
-mod message;
+pub mod message;
+pub mod metrics;
 mod objects;
+pub mod options;
+mod outbound;
 pub mod server;
 pub mod shared_object;
 mod socket;
+pub mod stream;
+pub mod trace;
 mod util;
 pub mod wait_for_object;
 
+pub use config::ServerConfig;
 pub use connector::Connector;
-pub use error::{Error, RemoteError};
+pub use error::{Error, ErrorCode, ErrorOrigin, RemoteError};
 pub use event::EventListener;
-pub use server::start_server;
-pub use shared_object::{SharedObject, SharedObjectDispatcher};
-pub use wait_for_object::wait_for_objects;
+pub use message::{Caller, EventReport, GoingAway, MethodDescription, ObjectDescription};
+pub use options::{ClientOptions, Heartbeat};
+pub use server::{start_server, start_server_with, ServerBuilder, ServerHandle};
+pub use shared_object::{CallContext, SharedObject, SharedObjectDispatcher};
+pub use stream::{RemoteStream, StreamSender};
+pub use trace::TraceContext;
+pub use wait_for_object::{wait_for_objects, wait_for_objects_with};
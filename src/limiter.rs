@@ -0,0 +1,93 @@
+use std::time::Instant;
+
+use crate::config::RateLimit;
+
+/// What to do with a request of a rate limited connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Refuse,
+    /// The peer kept sending past the limit, more than a burst of requests
+    /// in a row were refused.
+    Disconnect,
+}
+
+/// A token bucket holding at most `burst` requests, refilled at `rate`
+/// requests per second.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+    refused: u32,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            refilled: Instant::now(),
+            refused: 0,
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    pub fn check(&mut self) -> Verdict {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&mut self, now: Instant) -> Verdict {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate as f64).min(self.limit.burst as f64);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.refused = 0;
+            return Verdict::Allow;
+        }
+        self.refused += 1;
+        if self.refused > self.limit.burst {
+            Verdict::Disconnect
+        } else {
+            Verdict::Refuse
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::config::RateLimit;
+
+    use super::{RateLimiter, Verdict};
+
+    #[test]
+    fn rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(RateLimit { rate: 10, burst: 2 });
+        limiter.refilled = start;
+
+        assert_eq!(limiter.check_at(start), Verdict::Allow);
+        assert_eq!(limiter.check_at(start), Verdict::Allow);
+        assert_eq!(limiter.check_at(start), Verdict::Refuse);
+
+        // A token every 100ms.
+        let later = start + Duration::from_millis(100);
+        assert_eq!(limiter.check_at(later), Verdict::Allow);
+        assert_eq!(limiter.check_at(later), Verdict::Refuse);
+        assert_eq!(limiter.check_at(later), Verdict::Refuse);
+        assert_eq!(limiter.check_at(later), Verdict::Disconnect);
+
+        // Idle time refills the burst, not more.
+        let idle = later + Duration::from_secs(60);
+        assert_eq!(limiter.check_at(idle), Verdict::Allow);
+        assert_eq!(limiter.check_at(idle), Verdict::Allow);
+        assert_eq!(limiter.check_at(idle), Verdict::Refuse);
+    }
+}
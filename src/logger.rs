@@ -1,33 +1,455 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use strum::{AsRefStr, Display, EnumString};
+
 pub const ENV_LOGGER: &str = "RUST_LOG";
+pub const ENV_LOG_FORMAT: &str = "ENV_LOG_FORMAT";
+pub const ENV_LOG_FILE: &str = "ENV_LOG_FILE";
+pub const ENV_LOG_MAX_SIZE: &str = "ENV_LOG_MAX_SIZE";
+pub const ENV_LOG_ROTATION: &str = "ENV_LOG_ROTATION";
+pub const ENV_LOG_KEEP: &str = "ENV_LOG_KEEP";
+pub const LOG_KEEP: usize = 5;
+
+/// How every log record is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, EnumString, Display, AsRefStr)]
+pub enum LogFormat {
+    /// One human readable line, followed by the fields as `key=value`.
+    #[default]
+    #[strum(serialize = "text")]
+    Text,
+    /// One JSON object per line, with the fields as members.
+    #[strum(serialize = "json")]
+    Json,
+}
+
+/// When the log file is rotated, besides its size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, EnumString, Display, AsRefStr)]
+pub enum Rotation {
+    #[default]
+    #[strum(serialize = "never")]
+    Never,
+    #[strum(serialize = "hourly")]
+    Hourly,
+    #[strum(serialize = "daily")]
+    Daily,
+}
+
+impl Rotation {
+    /// The period a log line written now belongs to. The file is rotated
+    /// when the period changes.
+    fn period(&self) -> String {
+        let now = chrono::Local::now();
+        match self {
+            Rotation::Never => String::new(),
+            Rotation::Hourly => now.format("%Y%m%d%H").to_string(),
+            Rotation::Daily => now.format("%Y%m%d").to_string(),
+        }
+    }
+}
+
+/// The level, format and destination of the logs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogConfig {
+    pub level: log::LevelFilter,
+    pub format: LogFormat,
+    /// The file the logs are written to, stdout when there is none.
+    pub file: Option<PathBuf>,
+    /// Rotates the file once it grows past this size, in bytes.
+    pub max_size: Option<u64>,
+    pub rotation: Rotation,
+    /// The number of rotated files kept next to the log file.
+    pub keep: usize,
+}
+
+impl LogConfig {
+    /// Reads the log settings from `RUST_LOG`, `ENV_LOG_FORMAT`, `ENV_LOG_FILE`,
+    /// `ENV_LOG_MAX_SIZE`, `ENV_LOG_ROTATION` and `ENV_LOG_KEEP`, falling back
+    /// to the defaults.
+    pub fn from_env() -> Self {
+        let level = std::env::var(ENV_LOGGER)
+            .map(|var| match var.to_lowercase().as_str() {
+                "trace" => log::LevelFilter::Trace,
+                "debug" => log::LevelFilter::Debug,
+                "info" => log::LevelFilter::Info,
+                "warn" => log::LevelFilter::Warn,
+                "error" => log::LevelFilter::Error,
+                "off" => log::LevelFilter::Off,
+                _ => log::LevelFilter::Info,
+            })
+            .unwrap_or_else(|_| log::LevelFilter::Info);
+        let format = std::env::var(ENV_LOG_FORMAT)
+            .ok()
+            .and_then(|var| var.to_lowercase().parse::<LogFormat>().ok())
+            .unwrap_or_default();
+        let file = std::env::var(ENV_LOG_FILE)
+            .ok()
+            .filter(|var| !var.is_empty())
+            .map(PathBuf::from);
+        let max_size = std::env::var(ENV_LOG_MAX_SIZE)
+            .ok()
+            .and_then(|var| var.parse::<u64>().ok())
+            .filter(|size| *size > 0);
+        let rotation = std::env::var(ENV_LOG_ROTATION)
+            .ok()
+            .and_then(|var| var.to_lowercase().parse::<Rotation>().ok())
+            .unwrap_or_default();
+        let keep = std::env::var(ENV_LOG_KEEP)
+            .ok()
+            .and_then(|var| var.parse::<usize>().ok())
+            .unwrap_or(LOG_KEEP);
+
+        Self {
+            level,
+            format,
+            file,
+            max_size,
+            rotation,
+            keep,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: log::LevelFilter::Info,
+            format: LogFormat::default(),
+            file: None,
+            max_size: None,
+            rotation: Rotation::default(),
+            keep: LOG_KEEP,
+        }
+    }
+}
 
 pub fn setup_logger() {
-    let level = std::env::var(ENV_LOGGER)
-        .map(|var| match var.to_lowercase().as_str() {
-            "trace" => log::LevelFilter::Trace,
-            "debug" => log::LevelFilter::Debug,
-            "info" => log::LevelFilter::Info,
-            "warn" => log::LevelFilter::Warn,
-            "error" => log::LevelFilter::Error,
-            "off" => log::LevelFilter::Off,
-            _ => log::LevelFilter::Info,
+    setup_logger_with(LogConfig::from_env());
+}
+
+/// Installs the logger. Log records may carry fields, such as
+/// `log::info!(connection = peer, transaction = id; "...")`, which are
+/// written as `key=value` in text and as members in JSON.
+pub fn setup_logger_with(config: LogConfig) {
+    dispatch(&config).apply().unwrap_or_else(|e| {
+        panic!("{:?}", e);
+    });
+}
+
+/// The logger the config describes, writing to stdout or to the log file.
+fn dispatch(config: &LogConfig) -> fern::Dispatch {
+    let format = config.format;
+    let dispatch = fern::Dispatch::new()
+        .format(move |out, message, record| match format {
+            LogFormat::Text => {
+                let mut fields = String::new();
+                let _ = record.key_values().visit(&mut TextFields(&mut fields));
+                out.finish(format_args!(
+                    "[{}][{:<5}][{}:{:<3}]: {}{}",
+                    chrono::Local::now().format("%H:%M:%S%.9f"),
+                    record.level(),
+                    record.file().unwrap_or_default(),
+                    record.line().unwrap_or_default(),
+                    message,
+                    fields
+                ))
+            }
+            LogFormat::Json => {
+                let mut line = serde_json::Map::new();
+                line.insert("ts".into(), chrono::Local::now().to_rfc3339().into());
+                line.insert("level".into(), record.level().as_str().into());
+                line.insert("target".into(), record.target().into());
+                line.insert(
+                    "file".into(),
+                    record.file().unwrap_or_default().to_string().into(),
+                );
+                line.insert("line".into(), record.line().unwrap_or_default().into());
+                line.insert("message".into(), message.to_string().into());
+                let _ = record.key_values().visit(&mut JsonFields(&mut line));
+                out.finish(format_args!("{}", serde_json::Value::Object(line)))
+            }
         })
-        .unwrap_or_else(|_| log::LevelFilter::Info);
-
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}][{:<5}][{}:{:<3}]: {}",
-                chrono::Local::now().format("%H:%M:%S%.9f"),
-                record.level(),
-                record.file().unwrap_or_default(),
-                record.line().unwrap_or_default(),
-                message
-            ))
+        .level(config.level);
+
+    match &config.file {
+        Some(path) => match RotatingFile::open(path.clone(), config) {
+            Ok(file) => dispatch.chain(Box::new(file) as Box<dyn Write + Send>),
+            Err(err) => {
+                eprintln!("Cannot open the log file {}: {}", path.display(), err);
+                dispatch.chain(std::io::stdout())
+            }
+        },
+        None => dispatch.chain(std::io::stdout()),
+    }
+}
+
+struct TextFields<'a>(&'a mut String);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for TextFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        self.0.push_str(&format!(" {}={}", key, value));
+        Ok(())
+    }
+}
+
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_f64() {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// A log file that is renamed to `<file>.1` when it grows past its maximum
+/// size or when its period is over. The older files are shifted to `.2`,
+/// `.3` and so on, and the ones past `keep` are deleted.
+///
+/// A record is written in many pieces, and flushed once complete. The
+/// pieces are held until then, so that the record is written at once and
+/// never split across two files.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    record: Vec<u8>,
+    size: u64,
+    period: String,
+    max_size: Option<u64>,
+    rotation: Rotation,
+    keep: usize,
+    /// Whether the last rotation failed, so that a failure that repeats is
+    /// reported once.
+    failing: bool,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, config: &LogConfig) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file,
+            record: Vec::new(),
+            size,
+            period: config.rotation.period(),
+            max_size: config.max_size,
+            rotation: config.rotation,
+            keep: config.keep,
+            failing: false,
         })
-        .level(level)
-        .chain(std::io::stdout())
-        .apply()
-        .unwrap_or_else(|e| {
-            panic!("{:?}", e);
-        });
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            let _ = fs::remove_file(&self.path);
+        } else {
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for index in (1..self.keep).rev() {
+                let _ = fs::rename(self.rotated(index), self.rotated(index + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Carries on after a failed rotation, such as when the file has been
+    /// moved or removed by another tool. The file is opened again at its
+    /// path, or kept when that fails too.
+    fn recover(&mut self, err: std::io::Error) {
+        if !self.failing {
+            eprintln!(
+                "Cannot rotate the log file {}: {}",
+                self.path.display(),
+                err
+            );
+            self.failing = true;
+        }
+        let reopened = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path);
+        if let Ok(file) = reopened {
+            self.file = file;
+        }
+        // Counted anew, rather than retried on every record.
+        self.size = self.file.metadata().map_or(0, |metadata| metadata.len());
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.record.is_empty() {
+            return self.file.flush();
+        }
+        let period = self.rotation.period();
+        let size = self.record.len() as u64;
+        let full = self
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + size > max_size);
+        if full || period != self.period {
+            self.period = period;
+            match self.rotate() {
+                Ok(()) => self.failing = false,
+                Err(err) => self.recover(err),
+            }
+        }
+
+        let written = self.file.write_all(&self.record);
+        self.record.clear();
+        written?;
+        self.size += size;
+        self.file.flush()
+    }
+}
+
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{dispatch, LogConfig, LogFormat, RotatingFile};
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("remote-call-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.log");
+        let config = LogConfig {
+            max_size: Some(10),
+            keep: 2,
+            ..LogConfig::default()
+        };
+
+        let mut file = RotatingFile::open(path.clone(), &config).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+            file.flush().unwrap();
+        }
+        // The pieces of a record are written once the record is complete.
+        file.write_all(b"fif").unwrap();
+        file.write_all(b"th\n").unwrap();
+        file.flush().unwrap();
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("server.log"), "fifth\n");
+        assert_eq!(read("server.log.1"), "fourth\n");
+        assert_eq!(read("server.log.2"), "third\n");
+        assert!(!dir.join("server.log.3").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_removed_file() {
+        let dir = std::env::temp_dir().join(format!("remote-call-removed-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.log");
+        let config = LogConfig {
+            max_size: Some(10),
+            keep: 2,
+            ..LogConfig::default()
+        };
+
+        let mut file = RotatingFile::open(path.clone(), &config).unwrap();
+        file.write_all(b"first line\n").unwrap();
+        file.flush().unwrap();
+        // Removed by another tool, the file cannot be rotated.
+        std::fs::remove_file(&path).unwrap();
+        for line in ["second line\n", "third line\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+            file.flush().unwrap();
+            assert!(file.record.is_empty());
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("server.log"), "third line\n");
+        assert_eq!(read("server.log.1"), "second line\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_whole_records() {
+        let dir = std::env::temp_dir().join(format!("remote-call-records-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = LogConfig {
+            level: log::LevelFilter::Info,
+            format: LogFormat::Json,
+            file: Some(dir.join("server.log")),
+            max_size: Some(300),
+            keep: 100,
+            ..LogConfig::default()
+        };
+
+        let (_, logger) = dispatch(&config).into_log();
+        for index in 0..20 {
+            logger.log(
+                &log::Record::builder()
+                    .level(log::Level::Info)
+                    .args(format_args!("record {}", index))
+                    .build(),
+            );
+        }
+        drop(logger);
+
+        let mut records = 0;
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let content = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            assert!(content.ends_with('\n'), "{:?}", content);
+            for line in content.lines() {
+                serde_json::from_str::<serde_json::Value>(line).unwrap();
+                records += 1;
+            }
+        }
+        assert_eq!(records, 20);
+        assert!(dir.join("server.log.1").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
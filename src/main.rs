@@ -1,15 +1,62 @@
-use remote_call::{logger::setup_logger, server::start_server};
+use std::process::ExitCode;
 
-#[ctor::ctor]
-fn set_log() {
-    setup_logger();
-}
+use remote_call::{
+    config::{ServerConfig, USAGE},
+    logger::setup_logger_with,
+    server::start_server_with,
+};
+
+const EXIT_FAILED: u8 = 1;
+const EXIT_USAGE: u8 = 2;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
+fn main() -> ExitCode {
     let version = env!("CARGO_PKG_VERSION");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    if args.iter().any(|arg| arg == "-V" || arg == "--version") {
+        println!("remote-call {}", version);
+        return ExitCode::SUCCESS;
+    }
+
+    let config = match ServerConfig::from_args(args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    // The logs may go to a file, the errors are reported on stderr as well.
+    let logs_to_file = config.log.file.is_some();
+    setup_logger_with(config.log.clone());
+
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(workers) = config.limits.worker_threads {
+        builder.worker_threads(workers);
+    }
+    let runtime = match builder.enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            eprintln!("Cannot start the runtime: {}", err);
+            return ExitCode::from(EXIT_FAILED);
+        }
+    };
 
-    log::info!("Starting remote-call v.{}", version);
-    start_server().await;
-    log::info!("Ending remote-call v.{}", version);
+    runtime.block_on(async {
+        log::info!("Starting remote-call v.{}", version);
+        let result = start_server_with(config).await;
+        log::info!("Ending remote-call v.{}", version);
+        match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                log::error!("{}", err);
+                if logs_to_file {
+                    eprintln!("{}", err);
+                }
+                ExitCode::from(EXIT_FAILED)
+            }
+        }
+    })
 }
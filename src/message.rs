@@ -1,7 +1,32 @@
-use std::fmt::Display;
+use std::{borrow::Cow, fmt::Display};
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use json_elem::JsonElem;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::RawValue;
+
+use crate::trace::TraceContext;
+
+/// The highest protocol version this library speaks.
+/// - 1: the body is a JSON array of byte numbers.
+/// - 2: the body is embedded as JSON, or as base64 when it is not JSON.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// The protocol version of peers that do not advertise one.
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+/// The lowest protocol version accepted from a peer that sends a handshake.
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+/// The feature of the clients that understand the [`GoingAway`] notice.
+pub const GOING_AWAY: &str = "going-away";
+/// The feature of the peers that answer a `DescribeRequest`.
+pub const DESCRIBE: &str = "describe";
+/// The feature of the peers that answer a `Ping` with a `Pong`. A client
+/// announces it only when it pings the server itself.
+pub const HEARTBEAT: &str = "heartbeat";
+/// The feature of the peers that understand the streaming calls, see
+/// [`StreamCall`].
+pub const STREAMING: &str = "streaming";
+/// The version of this library, sent in the handshake.
+pub const LIBRARY_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Clone, Copy, PartialEq, Debug, Default)]
 pub enum MessageType {
@@ -17,6 +42,18 @@ pub enum MessageType {
     RemoveShareObjectRequest,
     RemoveShareObjectResponse,
     WaitForObject,
+    Hello,
+    Welcome,
+    DescribeRequest,
+    DescribeResponse,
+    GoingAway,
+    Ping,
+    Pong,
+    StreamRequest,
+    StreamChunk,
+    StreamEnd,
+    StreamCredit,
+    StreamCancel,
 }
 
 impl Serialize for MessageType {
@@ -36,6 +73,18 @@ impl Serialize for MessageType {
             MessageType::RemoveShareObjectRequest => 8,
             MessageType::RemoveShareObjectResponse => 9,
             MessageType::WaitForObject => 10,
+            MessageType::Hello => 11,
+            MessageType::Welcome => 12,
+            MessageType::DescribeRequest => 13,
+            MessageType::DescribeResponse => 14,
+            MessageType::GoingAway => 15,
+            MessageType::Ping => 16,
+            MessageType::Pong => 17,
+            MessageType::StreamRequest => 18,
+            MessageType::StreamChunk => 19,
+            MessageType::StreamEnd => 20,
+            MessageType::StreamCredit => 21,
+            MessageType::StreamCancel => 22,
         };
         serializer.serialize_u32(value_str)
     }
@@ -59,19 +108,39 @@ impl<'de> Deserialize<'de> for MessageType {
             8 => Ok(MessageType::RemoveShareObjectRequest),
             9 => Ok(MessageType::RemoveShareObjectResponse),
             10 => Ok(MessageType::WaitForObject),
+            11 => Ok(MessageType::Hello),
+            12 => Ok(MessageType::Welcome),
+            13 => Ok(MessageType::DescribeRequest),
+            14 => Ok(MessageType::DescribeResponse),
+            15 => Ok(MessageType::GoingAway),
+            16 => Ok(MessageType::Ping),
+            17 => Ok(MessageType::Pong),
+            18 => Ok(MessageType::StreamRequest),
+            19 => Ok(MessageType::StreamChunk),
+            20 => Ok(MessageType::StreamEnd),
+            21 => Ok(MessageType::StreamCredit),
+            22 => Ok(MessageType::StreamCancel),
             _ => Err(serde::de::Error::custom(format!(
-                "Invalid value for MessageType(0,1,2,3,4,5,6,7,8,9,10): {}",
+                "Invalid value for MessageType(0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22): {}",
                 value
             ))),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Default, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug, Clone)]
 pub struct SocketMessage {
     id: u64,
     kind: MessageType,
     msg: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trace: Option<TraceContext>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    caller: Option<Caller>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deadline: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<ResponseStatus>,
 }
 
 impl SocketMessage {
@@ -94,6 +163,26 @@ impl SocketMessage {
         self
     }
 
+    pub fn set_trace(mut self, trace: Option<TraceContext>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    pub fn set_caller(mut self, caller: Option<Caller>) -> Self {
+        self.caller = caller;
+        self
+    }
+
+    pub fn set_deadline(mut self, deadline: Option<u64>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn set_status(mut self, status: Option<ResponseStatus>) -> Self {
+        self.status = status;
+        self
+    }
+
     pub fn body(&self) -> &[u8] {
         &self.msg
     }
@@ -106,9 +195,124 @@ impl SocketMessage {
         self.kind
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
-        serde_json::to_vec(self).unwrap()
+    /// The trace context of the remote call this message belongs to.
+    pub fn trace(&self) -> Option<&TraceContext> {
+        self.trace.as_ref()
+    }
+
+    /// The connection that made the remote call, as stamped by the server.
+    pub fn caller(&self) -> Option<&Caller> {
+        self.caller.as_ref()
     }
+
+    /// When the caller stops waiting for the response, in milliseconds
+    /// since the Unix epoch.
+    pub fn deadline(&self) -> Option<u64> {
+        self.deadline
+    }
+
+    /// How the body of a response is to be read. Legacy peers do not tell.
+    pub fn status(&self) -> Option<ResponseStatus> {
+        self.status
+    }
+
+    /// Encodes the message in the wire format of the given protocol version.
+    /// Every frame advertises the highest version this library speaks, so
+    /// the peer can upgrade to it.
+    pub fn encode(&self, version: u8) -> Vec<u8> {
+        let mut frame = Frame {
+            v: PROTOCOL_VERSION,
+            id: self.id,
+            kind: self.kind,
+            msg: None,
+            body: None,
+            b64: None,
+            trace: self.trace.as_ref().map(Cow::Borrowed),
+            caller: self.caller.as_ref().map(Cow::Borrowed),
+            deadline: self.deadline,
+            status: self.status,
+        };
+
+        if version < PROTOCOL_VERSION {
+            frame.msg = Some(Cow::Borrowed(&self.msg));
+        } else if !self.msg.is_empty() {
+            match serde_json::from_slice::<&RawValue>(&self.msg) {
+                Ok(body) if body.get().len() == self.msg.len() => frame.body = Some(body),
+                _ => frame.b64 = Some(STANDARD.encode(&self.msg)),
+            }
+        }
+        serde_json::to_vec(&frame).unwrap()
+    }
+
+    /// Decodes a frame of any protocol version. It returns the message and
+    /// the protocol version advertised by the peer.
+    pub fn decode(data: &[u8]) -> Result<(Self, u8), serde_json::Error> {
+        let frame = serde_json::from_slice::<Frame>(data)?;
+        let msg = if let Some(msg) = frame.msg {
+            msg.into_owned()
+        } else if let Some(body) = frame.body {
+            body.get().as_bytes().to_vec()
+        } else if let Some(b64) = frame.b64 {
+            STANDARD
+                .decode(b64)
+                .map_err(<serde_json::Error as serde::de::Error>::custom)?
+        } else {
+            Vec::new()
+        };
+
+        Ok((
+            Self {
+                id: frame.id,
+                kind: frame.kind,
+                msg,
+                trace: frame.trace.map(Cow::into_owned),
+                caller: frame.caller.map(Cow::into_owned),
+                deadline: frame.deadline,
+                status: frame.status,
+            },
+            frame.v,
+        ))
+    }
+}
+
+/// A [`SocketMessage`] as it travels on the wire.
+#[derive(Serialize, Deserialize)]
+struct Frame<'a> {
+    #[serde(default = "legacy_protocol_version")]
+    v: u8,
+    id: u64,
+    kind: MessageType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    msg: Option<Cow<'a, [u8]>>,
+    #[serde(
+        default,
+        borrow,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    body: Option<&'a RawValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    b64: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trace: Option<Cow<'a, TraceContext>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    caller: Option<Cow<'a, Caller>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deadline: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<ResponseStatus>,
+}
+
+fn legacy_protocol_version() -> u8 {
+    LEGACY_PROTOCOL_VERSION
+}
+
+/// Keeps a `null` body as the JSON value it is, instead of a missing body.
+fn present<'de, D>(deserializer: D) -> Result<Option<&'de RawValue>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    <&RawValue>::deserialize(deserializer).map(Some)
 }
 
 impl Display for SocketMessage {
@@ -116,18 +320,37 @@ impl Display for SocketMessage {
         write!(f, "{:?} id: {} ", self.kind, self.id)
     }
 }
-pub fn result_to_socket_message(
-    res: Result<Option<SocketMessage>, atticus::Error>,
-    id: u64,
-    msg_type: MessageType,
-) -> SocketMessage {
-    match res {
-        Ok(respon) => respon.unwrap(),
-        Err(err) => SocketMessage::new()
-            .set_id(id)
-            .set_kind(msg_type)
-            .set_body(err.to_string().as_bytes()),
-    }
+/// How the body of a remote call response is to be read, so that a result
+/// is never mistaken for an error.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseStatus {
+    /// The body is the result of the method.
+    Ok,
+    /// The body is a [`RemoteError`] from the shared object or its dispatcher.
+    ///
+    /// [`RemoteError`]: crate::RemoteError
+    Error,
+    /// The body is a [`RemoteError`] from the server.
+    ///
+    /// [`RemoteError`]: crate::RemoteError
+    Broker,
+    /// A status of a newer peer.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The connection a remote call comes from. The server stamps it on every
+/// request it forwards, so the shared object cannot be lied to.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct Caller {
+    /// The address of the connection on the server.
+    pub connection: String,
+    /// The name the caller announced in the handshake, empty for legacy clients.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    #[serde(default)]
+    pub pid: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -143,10 +366,45 @@ impl CallMethod {
     }
 }
 
+/// A call whose result is streamed back as `StreamChunk` messages, ended
+/// by a `StreamEnd` carrying the error if any. The owner sends at most
+/// `window` chunks ahead of the [`StreamCredit`] the caller gives back.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StreamCall {
+    pub object: String,
+    pub method: String,
+    pub param: JsonElem,
+    pub window: u32,
+}
+
+impl StreamCall {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+/// The chunks a caller is ready for, on top of those it was given.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StreamCredit {
+    pub credit: u32,
+}
+
+impl StreamCredit {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Event {
     pub event: String,
     pub param: JsonElem,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ack: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 impl Event {
@@ -155,14 +413,217 @@ impl Event {
     }
 }
 
+/// The delivery report of an acknowledged event. It is returned to the
+/// publisher once the server has queued the event for all of its subscribers.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct EventReport {
+    /// The number of subscribers the event was queued for.
+    pub delivered: usize,
+    /// The addresses of the subscribers whose queue rejected the event.
+    pub failed: Vec<String>,
+}
+
+impl EventReport {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+/// The notice a server sends to its clients when it shuts down. It stops
+/// accepting connections and new calls, answers the calls in flight for at
+/// most `drain_ms`, then closes the connections. Clients are expected to
+/// reconnect, to another server or to the same one once it is back.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct GoingAway {
+    pub reason: String,
+    pub drain_ms: u64,
+}
+
+impl GoingAway {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+/// A method of a shared object, as returned by [`SharedObject::describe`].
+///
+/// [`SharedObject::describe`]: crate::SharedObject::describe
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct MethodDescription {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub doc: String,
+    /// The JSON schema of the parameter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<JsonElem>,
+    /// The JSON schema of the result.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub returns: Option<JsonElem>,
+}
+
+impl MethodDescription {
+    pub fn new(name: &str, doc: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            doc: doc.to_string(),
+            params: None,
+            returns: None,
+        }
+    }
+
+    pub fn with_params(mut self, schema: JsonElem) -> Self {
+        self.params = Some(schema);
+        self
+    }
+
+    pub fn with_returns(mut self, schema: JsonElem) -> Self {
+        self.returns = Some(schema);
+        self
+    }
+}
+
+/// The methods a shared object supports.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct ObjectDescription {
+    pub methods: Vec<MethodDescription>,
+}
+
+impl ObjectDescription {
+    pub fn new(methods: Vec<MethodDescription>) -> Self {
+        Self { methods }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+impl Display for ObjectDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let schema = |schema: &Option<JsonElem>| {
+            schema
+                .as_ref()
+                .and_then(|schema| serde_json::to_string(schema).ok())
+                .unwrap_or_else(|| "any".to_string())
+        };
+        for method in &self.methods {
+            writeln!(
+                f,
+                "{}({}) -> {}",
+                method.name,
+                schema(&method.params),
+                schema(&method.returns)
+            )?;
+            if !method.doc.is_empty() {
+                writeln!(f, "    {}", method.doc)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The optional messages this library understands, such as
+/// [`GOING_AWAY`], and the cargo features it was built with, sent in the
+/// handshake.
+pub fn features() -> Vec<String> {
+    let features: Vec<&str> = vec![
+        GOING_AWAY,
+        DESCRIBE,
+        HEARTBEAT,
+        STREAMING,
+        #[cfg(feature = "msgpack")]
+        "msgpack",
+        #[cfg(feature = "cbor")]
+        "cbor",
+        #[cfg(feature = "tracing")]
+        "tracing",
+    ];
+    features.into_iter().map(String::from).collect()
+}
+
+/// The first message a client sends, before any other.
+/// The server answers with a [`Welcome`].
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Hello {
+    /// The highest protocol version the client speaks.
+    pub protocol: u8,
+    /// The library version of the client.
+    pub version: String,
+    /// The codecs the client can use, in order of preference.
+    pub codecs: Vec<String>,
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// The name the client is known by in the server.
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub pid: u32,
+}
+
+impl Hello {
+    pub fn new(codecs: Vec<String>) -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            version: LIBRARY_VERSION.to_string(),
+            codecs,
+            features: features(),
+            name: String::new(),
+            pid: std::process::id(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+/// The server answer to a [`Hello`], with the codec used from then on.
+/// A peer that cannot be served gets the reason in `error`, and the
+/// connection is closed right after.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Welcome {
+    /// The highest protocol version the server speaks.
+    pub protocol: u8,
+    /// The library version of the server.
+    pub version: String,
+    pub codec: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Welcome {
+    pub fn new(codec: &str) -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            version: LIBRARY_VERSION.to_string(),
+            codec: codec.to_string(),
+            features: features(),
+            error: None,
+        }
+    }
+
+    pub fn reject(error: String) -> Self {
+        Self {
+            error: Some(error),
+            ..Self::new(crate::codec::JSON)
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use json_elem::JsonElem;
     use test_case::test_case;
 
-    use crate::message::CallMethod;
+    use crate::message::{CallMethod, Event};
 
-    use super::{MessageType, SocketMessage};
+    use super::{MessageType, SocketMessage, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION};
 
     #[test_case(0, MessageType::AddShareObjectRequest, "my_object".as_bytes(),
         r#"{"id":0,"kind":0,"msg":[109,121,95,111,98,106,101,99,116]}"#; "ShareObject")]
@@ -208,4 +669,47 @@ mod tests {
 
         assert_eq!(msg, expected);
     }
+
+    #[test_case(PROTOCOL_VERSION, br#"{"object":"my_object"}"#,
+        r#"{"v":2,"id":7,"kind":2,"body":{"object":"my_object"}}"#; "JSON body")]
+    #[test_case(PROTOCOL_VERSION, b"null", r#"{"v":2,"id":7,"kind":2,"body":null}"#; "Null body")]
+    #[test_case(PROTOCOL_VERSION, b"my_object", r#"{"v":2,"id":7,"kind":2,"b64":"bXlfb2JqZWN0"}"#; "Binary body")]
+    #[test_case(PROTOCOL_VERSION, b"", r#"{"v":2,"id":7,"kind":2}"#; "Empty body")]
+    #[test_case(LEGACY_PROTOCOL_VERSION, b"null", r#"{"v":2,"id":7,"kind":2,"msg":[110,117,108,108]}"#; "Legacy body")]
+    fn sock_message_encode(version: u8, body: &[u8], expected: &str) {
+        let message = SocketMessage::new()
+            .set_body(body)
+            .set_id(7)
+            .set_kind(MessageType::RemoteCallRequest);
+        let encoded = message.encode(version);
+
+        assert_eq!(std::str::from_utf8(&encoded).unwrap(), expected);
+
+        let (decoded, advertised) = SocketMessage::decode(&encoded).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(advertised, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn sock_message_decode_legacy() {
+        let (msg, version) =
+            SocketMessage::decode(br#"{"id":0,"kind":4,"msg":[104,105]}"#).unwrap();
+
+        assert_eq!(version, LEGACY_PROTOCOL_VERSION);
+        assert_eq!(msg.body(), b"hi");
+        assert_eq!(msg.kind(), MessageType::SendEventRequest);
+    }
+
+    #[test_case(false, r#"{"event":"my_event","param":null}"#; "Fire and forget")]
+    #[test_case(true, r#"{"event":"my_event","param":null,"ack":true}"#; "Acknowledged")]
+    fn event_serialize(ack: bool, expected: &str) {
+        let event = Event {
+            event: "my_event".to_string(),
+            param: JsonElem::Null,
+            ack,
+        };
+
+        assert_eq!(event.as_bytes().as_slice(), expected.as_bytes());
+        assert_eq!(serde_json::from_str::<Event>(expected).unwrap(), event);
+    }
 }
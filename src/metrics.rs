@@ -0,0 +1,369 @@
+use std::{
+    fmt::Write,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::{
+    error::{CommonErrors, ErrorCode, RemoteError},
+    server::TransactionList,
+};
+
+/// The local address of the metrics HTTP endpoint, such as `127.0.0.1:9186`.
+/// The endpoint is disabled when it is not set.
+pub const ENV_METRICS_ADDRESS: &str = "ENV_METRICS_ADDRESS";
+
+/// How long a client of the endpoint may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The upper bounds of the call latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+/// The most `(object, method)` pairs counted apart. The calls past it, such
+/// as the ones of a client making up method names, are counted together
+/// under [`OTHER`].
+const MAX_SERIES: usize = 1000;
+const OTHER: &str = "_other";
+
+/// A latency histogram with the buckets of [`LATENCY_BUCKETS`].
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// The counters and histograms of the server, shared by all connections.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    calls: DashMap<(String, String), AtomicU64>,
+    latency: DashMap<(String, String), Histogram>,
+    errors: DashMap<String, AtomicU64>,
+    events_published: AtomicU64,
+    events_delivered: AtomicU64,
+    connections: AtomicI64,
+}
+
+/// The labels of a call, folded into [`OTHER`] once there are too many.
+fn series<V>(map: &DashMap<(String, String), V>, object: &str, method: &str) -> (String, String) {
+    let key = (object.to_string(), method.to_string());
+    if map.len() < MAX_SERIES || map.contains_key(&key) {
+        key
+    } else {
+        (OTHER.to_string(), OTHER.to_string())
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a remote call forwarded to the owner of the object.
+    pub fn call(&self, object: &str, method: &str) {
+        self.calls
+            .entry(series(&self.calls, object, method))
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the time from the request of a remote call to its response.
+    pub fn call_latency(&self, object: &str, method: &str, duration: Duration) {
+        self.latency
+            .entry(series(&self.latency, object, method))
+            .or_default()
+            .observe(duration);
+    }
+
+    /// Counts an error, by the kind found in the response body.
+    pub fn error(&self, body: &[u8]) {
+        self.errors
+            .entry(error_kind(body))
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an event published and the subscribers it was queued for.
+    pub fn event(&self, delivered: usize) {
+        self.events_published.fetch_add(1, Ordering::Relaxed);
+        self.events_delivered
+            .fetch_add(delivered as u64, Ordering::Relaxed);
+    }
+
+    pub fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self, pending_transactions: usize) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "remote_call_calls_total",
+            "counter",
+            "Remote calls forwarded, per object and method.",
+        );
+        for entry in self.calls.iter() {
+            let (object, method) = entry.key();
+            let _ = writeln!(
+                out,
+                "remote_call_calls_total{{object=\"{}\",method=\"{}\"}} {}",
+                escape(object),
+                escape(method),
+                entry.value().load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "remote_call_call_duration_seconds",
+            "histogram",
+            "Time from a remote call request to its response.",
+        );
+        for entry in self.latency.iter() {
+            let (object, method) = entry.key();
+            let labels = format!(
+                "object=\"{}\",method=\"{}\"",
+                escape(object),
+                escape(method)
+            );
+            let histogram = entry.value();
+            let mut cumulative = 0;
+            for (le, bucket) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "remote_call_call_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, cumulative
+                );
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "remote_call_call_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, count
+            );
+            let _ = writeln!(
+                out,
+                "remote_call_call_duration_seconds_sum{{{}}} {}",
+                labels,
+                histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            );
+            let _ = writeln!(
+                out,
+                "remote_call_call_duration_seconds_count{{{}}} {}",
+                labels, count
+            );
+        }
+
+        header(
+            &mut out,
+            "remote_call_errors_total",
+            "counter",
+            "Errors returned to callers, per kind.",
+        );
+        for entry in self.errors.iter() {
+            let _ = writeln!(
+                out,
+                "remote_call_errors_total{{kind=\"{}\"}} {}",
+                escape(entry.key()),
+                entry.value().load(Ordering::Relaxed)
+            );
+        }
+
+        let scalars = [
+            (
+                "remote_call_events_published_total",
+                "counter",
+                "Events published.",
+                self.events_published.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "remote_call_events_delivered_total",
+                "counter",
+                "Events queued for the subscribers.",
+                self.events_delivered.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "remote_call_active_connections",
+                "gauge",
+                "Connected clients.",
+                self.connections.load(Ordering::Relaxed),
+            ),
+            (
+                "remote_call_pending_transactions",
+                "gauge",
+                "Remote calls waiting for their response.",
+                pending_transactions as i64,
+            ),
+        ];
+        for (name, kind, help, value) in scalars {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The [`ErrorCode`] of an error response, or `RemoteError` for the errors
+/// returned by the shared objects themselves.
+fn error_kind(body: &[u8]) -> String {
+    let code = match serde_json::from_slice::<RemoteError>(body) {
+        Ok(err) => err.code,
+        Err(_) => CommonErrors::from_str(&String::from_utf8_lossy(body))
+            .map(|common| ErrorCode::from(&common))
+            .unwrap_or(ErrorCode::Unknown),
+    };
+    match code {
+        ErrorCode::Application | ErrorCode::Unknown => "RemoteError".to_string(),
+        code => format!("{:?}", code),
+    }
+}
+
+/// Serves the metrics on `GET /metrics` until the server stops.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>, transactions: TransactionList) {
+    if let Ok(address) = listener.local_addr() {
+        log::info!("Metrics endpoint listening on {}", address);
+    }
+
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log::error!("Metrics accept: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        let transactions = transactions.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            let Ok(Ok(n)) = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buffer)).await
+            else {
+                return;
+            };
+            let request = String::from_utf8_lossy(&buffer[..n]);
+            let response = if request.starts_with("GET /metrics ") {
+                let body = metrics.render(transactions.len());
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use json_elem::JsonElem;
+
+    use crate::error::{CommonErrors, RemoteError};
+
+    use super::{Metrics, MAX_SERIES};
+
+    #[test]
+    fn metrics_render() {
+        let metrics = Metrics::new();
+        metrics.call("mango", "login");
+        metrics.call("mango", "login");
+        metrics.call_latency("mango", "login", Duration::from_millis(3));
+        metrics.error(&RemoteError::from(CommonErrors::ObjectNotFound).as_bytes());
+        metrics.error(&RemoteError::new(JsonElem::String("boom".into())).as_bytes());
+        metrics.error(b"remote connection error");
+        metrics.event(2);
+        metrics.connected();
+
+        let out = metrics.render(1);
+        for line in [
+            "# TYPE remote_call_calls_total counter",
+            "remote_call_calls_total{object=\"mango\",method=\"login\"} 2",
+            "remote_call_call_duration_seconds_bucket{object=\"mango\",method=\"login\",le=\"0.0025\"} 0",
+            "remote_call_call_duration_seconds_bucket{object=\"mango\",method=\"login\",le=\"0.005\"} 1",
+            "remote_call_call_duration_seconds_bucket{object=\"mango\",method=\"login\",le=\"+Inf\"} 1",
+            "remote_call_call_duration_seconds_sum{object=\"mango\",method=\"login\"} 0.003",
+            "remote_call_call_duration_seconds_count{object=\"mango\",method=\"login\"} 1",
+            "remote_call_errors_total{kind=\"ObjectNotFound\"} 1",
+            "remote_call_errors_total{kind=\"RemoteError\"} 1",
+            "remote_call_errors_total{kind=\"RemoteConnectionError\"} 1",
+            "remote_call_events_published_total 1",
+            "remote_call_events_delivered_total 2",
+            "remote_call_active_connections 1",
+            "remote_call_pending_transactions 1",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn metrics_series_limit() {
+        let metrics = Metrics::new();
+        for index in 0..MAX_SERIES + 10 {
+            metrics.call("mango", &format!("method{}", index));
+        }
+        metrics.call("mango", "method0");
+
+        let out = metrics.render(0);
+        let series = out
+            .lines()
+            .filter(|line| line.starts_with("remote_call_calls_total{"))
+            .count();
+        assert_eq!(series, MAX_SERIES + 1);
+        assert!(out
+            .lines()
+            .any(|l| l == "remote_call_calls_total{object=\"_other\",method=\"_other\"} 10"));
+        assert!(out
+            .lines()
+            .any(|l| l == "remote_call_calls_total{object=\"mango\",method=\"method0\"} 2"));
+    }
+}
@@ -1,30 +1,72 @@
-use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
-use async_trait::async_trait;
-use atticus::Actor;
-use json_elem::JsonElem;
+use dashmap::DashMap;
 use serde::Serialize;
 
 use crate::{
-    error::CommonErrors,
-    message::{CallMethod, Event, MessageType, SocketMessage},
-    socket::Socket,
+    error::{CommonErrors, ErrorCode, ErrorOrigin},
+    message::{
+        Event, EventReport, Hello, MessageType, ResponseStatus, SocketMessage, StreamCall,
+        DESCRIBE, STREAMING,
+    },
+    outbound::Outbound,
     RemoteError,
 };
 
+/// The registry of shared objects and event subscriptions of the server.
+/// It is shared by all connections; the maps are sharded so that lookups
+/// run in parallel with registrations.
 pub struct ListObjects {
-    objects: HashMap<String, Socket>,
-    events: HashMap<String, Socket>,
+    objects: DashMap<String, Registration>,
+    events: DashMap<String, Vec<Outbound>>,
+    connections: DashMap<String, Connection>,
+}
+
+/// A client connected to the server, keyed by its address.
+#[derive(Serialize, Clone, Debug)]
+pub struct Connection {
+    pub address: String,
+    pub name: String,
+    pub pid: u32,
+    pub version: String,
+    pub connected_at: String,
+    pub objects: Vec<String>,
+    pub subscriptions: Vec<String>,
 }
 
-pub enum RequestListObjects {
-    Add(SocketMessage, Socket),
-    Remove(Socket),
-    CallMethod(SocketMessage),
-    WaitForObject(SocketMessage),
-    SubscribeEvent(SocketMessage, Socket),
-    SendEvent(SocketMessage),
-    ListObject,
+/// A shared object registered by one of the connections.
+#[derive(Clone, Debug)]
+struct Registration {
+    owner: Outbound,
+    registered_at: String,
+    counters: Arc<CallCounters>,
+}
+
+/// The remote calls made on a shared object since it was registered.
+#[derive(Default, Debug)]
+struct CallCounters {
+    /// The calls forwarded to the owner.
+    calls: AtomicU64,
+    /// The calls the owner answered with a result.
+    completed: AtomicU64,
+    /// The calls the owner answered with an error.
+    errors: AtomicU64,
+}
+
+/// The owner and the call counters of a shared object, as returned by `objectInfo`.
+#[derive(Serialize, Debug)]
+pub struct ObjectInfo {
+    pub object: String,
+    pub owner: String,
+    pub owner_name: String,
+    pub owner_pid: u32,
+    pub registered_at: String,
+    pub calls: u64,
+    pub completed: u64,
+    pub errors: u64,
 }
 
 pub const SUCCESS: &str = "success";
@@ -33,15 +75,49 @@ pub const FAILED: &str = "failed";
 impl ListObjects {
     pub fn new() -> Self {
         Self {
-            objects: HashMap::new(),
-            events: HashMap::new(),
+            objects: DashMap::new(),
+            events: DashMap::new(),
+            connections: DashMap::new(),
         }
     }
 
-    pub fn add(&mut self, msg: SocketMessage, socket: Socket) -> SocketMessage {
+    /// Registers a connection just accepted by the server. It stays
+    /// unnamed until the client introduces itself.
+    pub fn connect(&self, address: String) {
+        self.connections.insert(
+            address.clone(),
+            Connection {
+                address,
+                name: String::new(),
+                pid: 0,
+                version: String::new(),
+                connected_at: chrono::Local::now().to_rfc3339(),
+                objects: Vec::new(),
+                subscriptions: Vec::new(),
+            },
+        );
+    }
+
+    /// Records the name, PID and library version a client sent in its handshake.
+    pub fn identify(&self, address: &str, hello: &Hello) {
+        if let Some(mut connection) = self.connections.get_mut(address) {
+            connection.name = hello.name.clone();
+            connection.pid = hello.pid;
+            connection.version = hello.version.clone();
+        }
+    }
+
+    pub fn add(&self, msg: SocketMessage, socket: Outbound) -> SocketMessage {
         match String::from_utf8(msg.body().into()) {
             Ok(object) => {
-                self.objects.insert(object, socket);
+                self.objects.insert(
+                    object,
+                    Registration {
+                        owner: socket,
+                        registered_at: chrono::Local::now().to_rfc3339(),
+                        counters: Arc::default(),
+                    },
+                );
                 msg.set_body(SUCCESS.as_bytes())
                     .set_kind(MessageType::AddShareObjectResponse)
             }
@@ -53,52 +129,156 @@ impl ListObjects {
         }
     }
 
-    pub fn remove(&mut self, socket: Socket) -> SocketMessage {
+    pub fn remove(&self, socket: Outbound) -> SocketMessage {
         self.objects
-            .retain(|_key, value| value.ip_address() != socket.ip_address());
+            .retain(|_key, value| value.owner.ip_address() != socket.ip_address());
 
-        self.events
-            .retain(|_key, value| value.ip_address() != socket.ip_address());
+        self.events.retain(|_key, value| {
+            value.retain(|subscriber| subscriber.ip_address() != socket.ip_address());
+            !value.is_empty()
+        });
+        self.connections.remove(&socket.ip_address());
         SocketMessage::new().set_kind(MessageType::RemoveShareObjectResponse)
     }
 
-    pub async fn call_method(&mut self, msg: SocketMessage) -> SocketMessage {
-        match serde_json::from_slice::<CallMethod>(msg.body()) {
-            Ok(call_method) => {
-                if let Some(remote) = self.objects.get(&call_method.object) {
-                    match remote.write(&msg.as_bytes()).await {
-                        Ok(_) => msg
-                            .set_body(SUCCESS.as_bytes())
-                            .set_kind(MessageType::RemoteCallResponse),
-                        Err(err) => {
-                            log::error!("ListObjects::call_method: {}", err);
-                            let _ = self.remove(remote.clone());
-                            msg.set_body("remote connection error".as_bytes())
-                                .set_kind(MessageType::RemoteCallResponse)
-                        }
-                    }
-                } else {
-                    let err = RemoteError::new(JsonElem::String(
-                        CommonErrors::ObjectNotFound.to_string(),
-                    ));
+    /// The objects registered by the connection.
+    pub fn owned_by(&self, socket: &Outbound) -> Vec<String> {
+        self.objects
+            .iter()
+            .filter(|entry| entry.owner.ip_address() == socket.ip_address())
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Forwards a call to the owner of its object, which the caller has read
+    /// from the call. The call itself is forwarded as it came.
+    pub fn call_method(&self, object: &str, msg: SocketMessage) -> SocketMessage {
+        let remote = self
+            .objects
+            .get(object)
+            .map(|remote| remote.value().clone());
+        if let Some(remote) = remote {
+            match remote.owner.send(msg.clone()) {
+                Ok(_) => {
+                    remote.counters.calls.fetch_add(1, Ordering::Relaxed);
+                    msg.set_body(SUCCESS.as_bytes())
+                        .set_kind(MessageType::RemoteCallResponse)
+                }
+                Err(err) => {
+                    log::error!("ListObjects::call_method: {}", err);
+                    let _ = self.remove(remote.owner);
+                    let err = RemoteError::from(CommonErrors::RemoteConnectionError);
                     msg.set_body(&err.as_bytes())
                         .set_kind(MessageType::RemoteCallResponse)
+                        .set_status(Some(ResponseStatus::Broker))
                 }
             }
+        } else {
+            let err = RemoteError::from(CommonErrors::ObjectNotFound);
+            msg.set_body(&err.as_bytes())
+                .set_kind(MessageType::RemoteCallResponse)
+                .set_status(Some(ResponseStatus::Broker))
+        }
+    }
+
+    /// Forwards a streaming call to the owner of the object, if the owner
+    /// streams. The error is returned as the end of the stream.
+    pub fn stream(&self, msg: SocketMessage) -> SocketMessage {
+        let object = match serde_json::from_slice::<StreamCall>(msg.body()) {
+            Ok(call) => call.object,
             Err(err) => {
-                log::error!("ListObjects::call_method(): {}", err);
-                let err =
-                    RemoteError::new(JsonElem::String(CommonErrors::SerdeParseError.to_string()));
-                msg.set_body(&err.as_bytes())
-                    .set_kind(MessageType::AddShareObjectResponse)
+                log::error!("ListObjects::stream(): {}", err);
+                return stream_error(msg, RemoteError::from(CommonErrors::SerdeParseError));
+            }
+        };
+        let Some(remote) = self
+            .objects
+            .get(&object)
+            .map(|remote| remote.value().clone())
+        else {
+            return stream_error(msg, RemoteError::from(CommonErrors::ObjectNotFound));
+        };
+        if !remote.owner.supports(STREAMING) {
+            let err = RemoteError::from_code(
+                ErrorCode::MethodNotFound,
+                ErrorOrigin::Broker,
+                format!("The owner of {} does not stream.", object),
+            );
+            return stream_error(msg, err);
+        }
+        match remote.owner.send(msg.clone()) {
+            Ok(_) => {
+                remote.counters.calls.fetch_add(1, Ordering::Relaxed);
+                msg.set_body(SUCCESS.as_bytes())
+                    .set_kind(MessageType::StreamEnd)
+            }
+            Err(err) => {
+                log::error!("ListObjects::stream: {}", err);
+                let _ = self.remove(remote.owner);
+                stream_error(msg, RemoteError::from(CommonErrors::RemoteConnectionError))
+            }
+        }
+    }
+
+    /// The connection that registered the object.
+    pub fn owner(&self, object: &str) -> Option<Outbound> {
+        self.objects.get(object).map(|remote| remote.owner.clone())
+    }
+
+    /// Forwards a request to describe an object to the owner of the object,
+    /// if the owner can answer it.
+    pub fn describe(&self, msg: SocketMessage) -> SocketMessage {
+        let object = String::from_utf8_lossy(msg.body()).to_string();
+        let remote = self
+            .objects
+            .get(&object)
+            .map(|remote| remote.value().clone());
+        let err = match remote {
+            Some(remote) if !remote.owner.supports(DESCRIBE) => {
+                let err = RemoteError::from_code(
+                    ErrorCode::MethodNotFound,
+                    ErrorOrigin::Broker,
+                    format!("The owner of {} cannot describe it.", object),
+                );
+                return msg
+                    .set_body(&err.as_bytes())
+                    .set_kind(MessageType::DescribeResponse);
             }
+            Some(remote) => match remote.owner.send(msg.clone()) {
+                Ok(_) => {
+                    return msg
+                        .set_body(SUCCESS.as_bytes())
+                        .set_kind(MessageType::DescribeResponse)
+                }
+                Err(err) => {
+                    log::error!("ListObjects::describe: {}", err);
+                    let _ = self.remove(remote.owner);
+                    CommonErrors::RemoteConnectionError
+                }
+            },
+            None => CommonErrors::ObjectNotFound,
+        };
+        let err = RemoteError::from(err);
+        msg.set_body(&err.as_bytes())
+            .set_kind(MessageType::DescribeResponse)
+    }
+
+    /// Counts the response of the owner of the object to a remote call.
+    pub fn record_response(&self, object: &str, is_error: bool) {
+        if let Some(remote) = self.objects.get(object) {
+            let counter = if is_error {
+                &remote.counters.errors
+            } else {
+                &remote.counters.completed
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
         }
     }
 
-    pub async fn wait_for_object(&mut self, msg: SocketMessage) -> SocketMessage {
+    pub fn wait_for_object(&self, msg: SocketMessage) -> SocketMessage {
         match String::from_utf8(msg.body().into()) {
             Ok(object) => {
-                if self.objects.get(object.as_str()).is_some() {
+                if self.objects.contains_key(object.as_str()) {
                     msg.set_body(SUCCESS.as_bytes())
                         .set_kind(MessageType::WaitForObject)
                 } else {
@@ -114,10 +294,17 @@ impl ListObjects {
         }
     }
 
-    pub fn subscribe_event(&mut self, msg: SocketMessage, socket: Socket) -> SocketMessage {
+    pub fn subscribe_event(&self, msg: SocketMessage, socket: Outbound) -> SocketMessage {
         match String::from_utf8(msg.body().into()) {
             Ok(event_name) => {
-                self.events.insert(event_name, socket);
+                let mut subscribers = self.events.entry(event_name).or_default();
+                let address = socket.ip_address();
+                if !subscribers
+                    .iter()
+                    .any(|subscriber| subscriber.ip_address() == address)
+                {
+                    subscribers.push(socket);
+                }
                 msg.set_body(SUCCESS.as_bytes())
                     .set_kind(MessageType::SubscribeEventResponse)
             }
@@ -129,21 +316,32 @@ impl ListObjects {
         }
     }
 
-    pub async fn send_event(&mut self, msg: SocketMessage) -> SocketMessage {
+    pub fn send_event(&self, msg: SocketMessage) -> SocketMessage {
         match serde_json::from_slice::<Event>(msg.body()) {
             Ok(event) => {
-                for (event_name, socket) in &self.events {
-                    if *event_name == event.event {
-                        let ret = socket.write(&msg.as_bytes()).await;
-                        log::trace!("ListObjects::send_event: {:?}", ret);
+                let mut report = EventReport::default();
+
+                let subscribers = self
+                    .events
+                    .get(&event.event)
+                    .map(|subscribers| subscribers.value().clone())
+                    .unwrap_or_default();
+                for socket in subscribers {
+                    match socket.send_event(msg.clone()) {
+                        Ok(_) => report.delivered += 1,
+                        Err(err) => {
+                            log::error!("ListObjects::send_event: {}", err);
+                            report.failed.push(socket.ip_address());
+                        }
                     }
                 }
-                msg.set_body(SUCCESS.as_bytes())
+                msg.set_body(&report.as_bytes())
                     .set_kind(MessageType::SendEventResponse)
             }
             Err(err) => {
                 log::error!("ListObjects::send_event(): {}", err);
-                msg.set_body(FAILED.as_bytes())
+                let err = RemoteError::from(CommonErrors::SerdeParseError);
+                msg.set_body(&err.as_bytes())
                     .set_kind(MessageType::SendEventResponse)
             }
         }
@@ -156,8 +354,8 @@ impl ListObjects {
         }
         let mut objects = Vec::new();
 
-        for (key, _) in self.objects.iter() {
-            objects.push(key.to_string());
+        for entry in self.objects.iter() {
+            objects.push(entry.key().to_string());
         }
         let object = Object { objects };
         let slice =
@@ -165,27 +363,106 @@ impl ListObjects {
 
         SocketMessage::new().set_body(slice.as_slice())
     }
-}
-#[async_trait]
-impl Actor for ListObjects {
-    type Request = RequestListObjects;
-    type Response = SocketMessage;
-
-    async fn handle(&mut self, message: Self::Request) -> Option<Self::Response> {
-        match message {
-            RequestListObjects::Add(msg, socket) => Some(self.add(msg, socket)),
-            RequestListObjects::Remove(msg) => Some(self.remove(msg)),
-            RequestListObjects::CallMethod(msg) => Some(self.call_method(msg).await),
-            RequestListObjects::WaitForObject(msg) => Some(self.wait_for_object(msg).await),
-            RequestListObjects::SubscribeEvent(msg, socket) => {
-                Some(self.subscribe_event(msg, socket))
+
+    /// Lists the subscribers of every event, by their address.
+    pub fn list_events(&self) -> SocketMessage {
+        #[derive(Serialize)]
+        struct Subscription {
+            event: String,
+            subscribers: Vec<String>,
+        }
+        #[derive(Serialize)]
+        struct Events {
+            events: Vec<Subscription>,
+        }
+        let mut events = Vec::new();
+
+        for entry in self.events.iter() {
+            events.push(Subscription {
+                event: entry.key().to_string(),
+                subscribers: entry.value().iter().map(Outbound::ip_address).collect(),
+            });
+        }
+        let slice = serde_json::to_vec(&Events { events })
+            .unwrap_or_else(|err| err.to_string().as_bytes().to_vec());
+
+        SocketMessage::new().set_body(slice.as_slice())
+    }
+
+    /// Returns the owner, the registration time and the call counters of the object.
+    pub fn object_info(&self, object: &str) -> Result<SocketMessage, RemoteError> {
+        let remote = self
+            .objects
+            .get(object)
+            .map(|remote| remote.value().clone())
+            .ok_or_else(|| RemoteError::from(CommonErrors::ObjectNotFound))?;
+        let (owner_name, owner_pid) = self
+            .connections
+            .get(&remote.owner.ip_address())
+            .map(|connection| (connection.name.clone(), connection.pid))
+            .unwrap_or_default();
+
+        let info = ObjectInfo {
+            object: object.to_string(),
+            owner: remote.owner.ip_address(),
+            owner_name,
+            owner_pid,
+            registered_at: remote.registered_at,
+            calls: remote.counters.calls.load(Ordering::Relaxed),
+            completed: remote.counters.completed.load(Ordering::Relaxed),
+            errors: remote.counters.errors.load(Ordering::Relaxed),
+        };
+        let slice =
+            serde_json::to_vec(&info).unwrap_or_else(|err| err.to_string().as_bytes().to_vec());
+
+        Ok(SocketMessage::new().set_body(slice.as_slice()))
+    }
+
+    /// Lists the connected clients, with the objects they own and the
+    /// events they subscribed to.
+    pub fn list_connections(&self) -> SocketMessage {
+        #[derive(Serialize)]
+        struct Connections {
+            connections: Vec<Connection>,
+        }
+        let mut connections: Vec<Connection> = self
+            .connections
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+
+        for connection in connections.iter_mut() {
+            for entry in self.objects.iter() {
+                if entry.value().owner.ip_address() == connection.address {
+                    connection.objects.push(entry.key().to_string());
+                }
+            }
+            for entry in self.events.iter() {
+                if entry
+                    .value()
+                    .iter()
+                    .any(|subscriber| subscriber.ip_address() == connection.address)
+                {
+                    connection.subscriptions.push(entry.key().to_string());
+                }
             }
-            RequestListObjects::SendEvent(msg) => Some(self.send_event(msg).await),
-            RequestListObjects::ListObject => Some(self.list_objects()),
         }
+        connections.sort_by(|a, b| a.connected_at.cmp(&b.connected_at));
+
+        let slice = serde_json::to_vec(&Connections { connections })
+            .unwrap_or_else(|err| err.to_string().as_bytes().to_vec());
+
+        SocketMessage::new().set_body(slice.as_slice())
     }
 }
 
+/// Ends a streaming call that could not be forwarded.
+fn stream_error(msg: SocketMessage, err: RemoteError) -> SocketMessage {
+    msg.set_body(&err.as_bytes())
+        .set_kind(MessageType::StreamEnd)
+        .set_status(Some(ResponseStatus::Broker))
+}
+
 impl Default for ListObjects {
     fn default() -> Self {
         Self::new()
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use crate::{
+    codec,
+    socket::{ENV_SERVER_ADDRESS, SERVER_ADDRESS},
+};
+
+/// How often the peers are pinged, in seconds, 0 to disable the heartbeat.
+pub const ENV_HEARTBEAT_INTERVAL: &str = "ENV_HEARTBEAT_INTERVAL";
+/// How many heartbeats may be missed before a peer is taken for dead.
+pub const ENV_HEARTBEAT_MISSES: &str = "ENV_HEARTBEAT_MISSES";
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+pub const HEARTBEAT_MISSES: u32 = 3;
+
+/// Pings the peer every `interval`, and drops the connection when nothing
+/// came from the peer for `misses` intervals, such as a peer that froze or
+/// a network path that was cut.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub misses: u32,
+}
+
+impl Heartbeat {
+    /// Reads the heartbeat from `ENV_HEARTBEAT_INTERVAL` and
+    /// `ENV_HEARTBEAT_MISSES`, falling back to the defaults. An interval of
+    /// 0 disables it.
+    pub fn from_env() -> Option<Self> {
+        let interval = std::env::var(ENV_HEARTBEAT_INTERVAL)
+            .ok()
+            .and_then(|var| var.parse::<f64>().ok())
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .unwrap_or(HEARTBEAT_INTERVAL);
+        let misses = std::env::var(ENV_HEARTBEAT_MISSES)
+            .ok()
+            .and_then(|var| var.parse::<u32>().ok())
+            .filter(|misses| *misses > 0)
+            .unwrap_or(HEARTBEAT_MISSES);
+
+        Some(Self { interval, misses }).filter(|heartbeat| !heartbeat.interval.is_zero())
+    }
+
+    /// How long a silent peer is waited for.
+    pub fn dead_after(&self) -> Duration {
+        self.interval * self.misses
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: HEARTBEAT_INTERVAL,
+            misses: HEARTBEAT_MISSES,
+        }
+    }
+}
+
+/// How a client connects to the IPC server. The defaults come from
+/// `ENV_SERVER_ADDRESS`, `ENV_CODEC` and [`Heartbeat::from_env`], and the
+/// name defaults to the name of the executable.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    pub address: String,
+    pub name: String,
+    pub codec: String,
+    pub heartbeat: Option<Heartbeat>,
+}
+
+impl ClientOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address of the IPC server.
+    pub fn with_address(mut self, address: &str) -> Self {
+        self.address = address.to_string();
+        self
+    }
+
+    /// Sets the name this client is shown with in the server logs and
+    /// in `listConnections`.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Sets the codec asked for in the handshake.
+    pub fn with_codec(mut self, codec: &str) -> Self {
+        self.codec = codec.to_string();
+        self
+    }
+
+    /// Sets the heartbeat with the server, `None` to disable it. It is only
+    /// used with the servers that support it.
+    pub fn with_heartbeat(mut self, heartbeat: Option<Heartbeat>) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        let name = std::env::current_exe()
+            .ok()
+            .and_then(|path| {
+                path.file_stem()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+            .unwrap_or_default();
+
+        Self {
+            address: std::env::var(ENV_SERVER_ADDRESS).unwrap_or(SERVER_ADDRESS.to_owned()),
+            name,
+            codec: codec::preferred(),
+            heartbeat: Heartbeat::from_env(),
+        }
+    }
+}
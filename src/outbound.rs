@@ -0,0 +1,389 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use strum::{AsRefStr, Display, EnumString};
+use tokio::sync::{watch, Notify};
+
+use crate::{
+    codec::Codec,
+    message::{Caller, SocketMessage},
+    socket::Socket,
+};
+
+pub const ENV_QUEUE_CAPACITY: &str = "ENV_QUEUE_CAPACITY";
+pub const ENV_QUEUE_OVERFLOW: &str = "ENV_QUEUE_OVERFLOW";
+pub const QUEUE_CAPACITY: usize = 1024;
+
+/// What to do with an event delivery when a connection's outbound queue is
+/// full. The other messages, such as calls and their responses, are never
+/// dropped: they take the place of a queued event, or the peer is
+/// disconnected when there is none.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, EnumString, Display, AsRefStr)]
+pub enum OverflowPolicy {
+    /// Discards the oldest queued event to make room for the new one.
+    #[default]
+    #[strum(serialize = "drop-oldest")]
+    DropOldest,
+    /// Discards the new event and keeps the queued ones.
+    #[strum(serialize = "drop-newest")]
+    DropNewest,
+    /// Disconnects the peer that cannot keep up.
+    #[strum(serialize = "disconnect")]
+    Disconnect,
+}
+
+/// The capacity and overflow policy of the outbound queue of every
+/// connection accepted by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl QueueConfig {
+    /// Reads the queue settings from `ENV_QUEUE_CAPACITY` and
+    /// `ENV_QUEUE_OVERFLOW`, falling back to the defaults.
+    pub fn from_env() -> Self {
+        let capacity = std::env::var(ENV_QUEUE_CAPACITY)
+            .ok()
+            .and_then(|var| var.parse::<usize>().ok())
+            .filter(|capacity| *capacity > 0)
+            .unwrap_or(QUEUE_CAPACITY);
+        let overflow = std::env::var(ENV_QUEUE_OVERFLOW)
+            .ok()
+            .and_then(|var| var.to_lowercase().parse::<OverflowPolicy>().ok())
+            .unwrap_or_default();
+
+        Self { capacity, overflow }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: QUEUE_CAPACITY,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Push {
+    Queued,
+    DroppedOldest,
+    DroppedNewest,
+    Overflow,
+}
+
+#[derive(Debug)]
+struct Queued<T> {
+    data: T,
+    droppable: bool,
+}
+
+#[derive(Debug)]
+struct Queue<T> {
+    items: Mutex<VecDeque<Queued<T>>>,
+    config: QueueConfig,
+}
+
+impl<T> Queue<T> {
+    fn new(config: QueueConfig) -> Self {
+        Self {
+            items: Mutex::new(VecDeque::new()),
+            config,
+        }
+    }
+
+    /// Queues the data, applying the overflow policy when the queue is
+    /// full. Only the droppable data is ever dropped.
+    fn push(&self, data: T, droppable: bool) -> Push {
+        let mut items = self.items.lock().unwrap();
+        let item = Queued { data, droppable };
+
+        if items.len() < self.config.capacity {
+            items.push_back(item);
+            return Push::Queued;
+        }
+        let oldest = items.iter().position(|item| item.droppable);
+        match (self.config.overflow, droppable, oldest) {
+            (OverflowPolicy::Disconnect, _, _) => Push::Overflow,
+            (OverflowPolicy::DropNewest, true, _) | (OverflowPolicy::DropOldest, true, None) => {
+                Push::DroppedNewest
+            }
+            (_, _, Some(oldest)) => {
+                items.remove(oldest);
+                items.push_back(item);
+                Push::DroppedOldest
+            }
+            (_, false, None) => Push::Overflow,
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.items.lock().unwrap().pop_front().map(|item| item.data)
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Queue<SocketMessage>,
+    notify: Notify,
+    closing: AtomicBool,
+    closed: watch::Sender<bool>,
+    name: Mutex<Option<(String, u32)>>,
+    features: Mutex<Vec<String>>,
+    pending: AtomicUsize,
+}
+
+/// The sending side of a connection accepted by the server.
+/// Messages are queued and written to the peer by a dedicated tokio task,
+/// so a slow or stuck peer never blocks the sender.
+#[derive(Clone, Debug)]
+pub struct Outbound {
+    socket: Socket,
+    shared: Arc<Shared>,
+}
+
+impl Outbound {
+    /// Creates the outbound queue of the connection and spawns its writer task.
+    pub fn spawn(socket: Socket, config: QueueConfig) -> Self {
+        let (closed, _) = watch::channel(false);
+        let outbound = Self {
+            socket,
+            shared: Arc::new(Shared {
+                queue: Queue::new(config),
+                notify: Notify::new(),
+                closing: AtomicBool::new(false),
+                closed,
+                name: Mutex::new(None),
+                features: Mutex::new(Vec::new()),
+                pending: AtomicUsize::new(0),
+            }),
+        };
+
+        let writer = outbound.clone();
+        tokio::spawn(async move {
+            writer.run().await;
+        });
+        outbound
+    }
+
+    async fn run(&self) {
+        loop {
+            if self.is_closed() {
+                break;
+            }
+            match self.shared.queue.pop() {
+                Some(msg) => {
+                    if let Err(err) = self.socket.send(&msg).await {
+                        log::error!("[{}] Outbound::run: {}", self.peer(), err);
+                        self.close();
+                    }
+                }
+                None if self.shared.closing.load(Ordering::Acquire) => self.close(),
+                None => self.shared.notify.notified().await,
+            }
+        }
+    }
+
+    /// Queues the message to be written to the peer. It never waits for the
+    /// peer, and the message is never dropped: the peer is disconnected
+    /// when its queue is full of messages that cannot be dropped.
+    pub fn send(&self, msg: SocketMessage) -> Result<(), std::io::Error> {
+        self.push(msg, false)
+    }
+
+    /// Queues an event delivery, which the overflow policy of the queue may
+    /// drop when the peer cannot keep up.
+    pub fn send_event(&self, msg: SocketMessage) -> Result<(), std::io::Error> {
+        self.push(msg, true)
+    }
+
+    fn push(&self, msg: SocketMessage, droppable: bool) -> Result<(), std::io::Error> {
+        if self.is_closed() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "The connection was closed.",
+            ));
+        }
+        let ret = match self.shared.queue.push(msg, droppable) {
+            Push::Queued => Ok(()),
+            Push::DroppedOldest => {
+                log::warn!("[{}] Outbound queue full, dropped oldest", self.peer());
+                Ok(())
+            }
+            Push::DroppedNewest => {
+                log::warn!("[{}] Outbound queue full, dropped newest", self.peer());
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::WouldBlock,
+                    "The outbound queue is full.",
+                ))
+            }
+            Push::Overflow => {
+                log::warn!("[{}] Outbound queue full, disconnecting", self.peer());
+                self.close();
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "The outbound queue is full.",
+                ))
+            }
+        };
+        self.shared.notify.notify_one();
+        ret
+    }
+
+    /// Stops the writer task and wakes up everyone waiting on [`Outbound::closed`].
+    pub fn close(&self) {
+        self.shared.closed.send_replace(true);
+        self.shared.notify.notify_one();
+    }
+
+    /// Closes the connection once the messages already queued have been written.
+    pub fn close_when_flushed(&self) {
+        self.shared.closing.store(true, Ordering::Release);
+        self.shared.notify.notify_one();
+    }
+
+    /// Completes once the connection has been closed.
+    pub async fn closed(&self) {
+        let mut closed = self.shared.closed.subscribe();
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.shared.closed.borrow()
+    }
+
+    /// Switches the codec of the connection, for the messages queued from now on.
+    pub fn set_codec(&self, codec: Arc<dyn Codec>) {
+        self.socket.set_codec(codec);
+    }
+
+    /// Names the peer once it introduced itself in the handshake.
+    pub fn set_name(&self, name: &str, pid: u32) {
+        *self.shared.name.lock().unwrap() = Some((name.to_string(), pid));
+    }
+
+    /// Keeps the features the peer announced in the handshake.
+    pub fn set_features(&self, features: &[String]) {
+        *self.shared.features.lock().unwrap() = features.to_vec();
+    }
+
+    /// Whether the peer announced the feature, such as the messages it understands.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.shared
+            .features
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|supported| supported == feature)
+    }
+
+    /// The calls and describes of the peer still waiting for their response.
+    pub fn pending_calls(&self) -> usize {
+        self.shared.pending.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request of the peer waiting for its response.
+    pub fn call_started(&self) {
+        self.shared.pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Forgets a request of the peer once it has been answered.
+    pub fn call_ended(&self) {
+        let _ = self
+            .shared
+            .pending
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| {
+                pending.checked_sub(1)
+            });
+    }
+
+    /// The peer as shown in the logs, its name and PID followed by its address.
+    pub fn peer(&self) -> String {
+        match &*self.shared.name.lock().unwrap() {
+            Some((name, pid)) => format!("{}({})@{}", name, pid, self.ip_address()),
+            None => self.ip_address(),
+        }
+    }
+
+    /// The peer as told to the shared objects it calls.
+    pub fn caller(&self) -> Caller {
+        let (name, pid) = self.shared.name.lock().unwrap().clone().unwrap_or_default();
+        Caller {
+            connection: self.ip_address(),
+            name,
+            pid,
+        }
+    }
+
+    pub fn ip_address(&self) -> String {
+        self.socket.ip_address()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::{OverflowPolicy, Push, Queue, QueueConfig};
+
+    #[test_case(OverflowPolicy::DropOldest, true, Push::DroppedOldest, vec![b"2".to_vec(), b"3".to_vec()]; "DropOldest")]
+    #[test_case(OverflowPolicy::DropNewest, true, Push::DroppedNewest, vec![b"1".to_vec(), b"2".to_vec()]; "DropNewest")]
+    #[test_case(OverflowPolicy::Disconnect, true, Push::Overflow, vec![b"1".to_vec(), b"2".to_vec()]; "Disconnect")]
+    #[test_case(OverflowPolicy::DropOldest, false, Push::DroppedOldest, vec![b"2".to_vec(), b"3".to_vec()]; "DropOldest for a call")]
+    #[test_case(OverflowPolicy::DropNewest, false, Push::DroppedOldest, vec![b"2".to_vec(), b"3".to_vec()]; "DropNewest for a call")]
+    #[test_case(OverflowPolicy::Disconnect, false, Push::Overflow, vec![b"1".to_vec(), b"2".to_vec()]; "Disconnect for a call")]
+    fn queue_overflow(
+        overflow: OverflowPolicy,
+        droppable: bool,
+        expected: Push,
+        remaining: Vec<Vec<u8>>,
+    ) {
+        let queue = Queue::new(QueueConfig {
+            capacity: 2,
+            overflow,
+        });
+
+        assert_eq!(queue.push(b"1".to_vec(), true), Push::Queued);
+        assert_eq!(queue.push(b"2".to_vec(), true), Push::Queued);
+        assert_eq!(queue.push(b"3".to_vec(), droppable), expected);
+
+        let mut actual = Vec::new();
+        while let Some(data) = queue.pop() {
+            actual.push(data);
+        }
+        assert_eq!(actual, remaining);
+    }
+
+    #[test_case(OverflowPolicy::DropOldest; "DropOldest")]
+    #[test_case(OverflowPolicy::DropNewest; "DropNewest")]
+    fn queue_never_drops_calls(overflow: OverflowPolicy) {
+        let queue = Queue::new(QueueConfig {
+            capacity: 2,
+            overflow,
+        });
+
+        assert_eq!(queue.push(b"1".to_vec(), false), Push::Queued);
+        assert_eq!(queue.push(b"2".to_vec(), false), Push::Queued);
+        assert_eq!(queue.push(b"3".to_vec(), true), Push::DroppedNewest);
+        assert_eq!(queue.push(b"4".to_vec(), false), Push::Overflow);
+
+        assert_eq!(queue.pop(), Some(b"1".to_vec()));
+        assert_eq!(queue.pop(), Some(b"2".to_vec()));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test_case("drop-oldest", OverflowPolicy::DropOldest; "DropOldest")]
+    #[test_case("drop-newest", OverflowPolicy::DropNewest; "DropNewest")]
+    #[test_case("disconnect", OverflowPolicy::Disconnect; "Disconnect")]
+    fn overflow_policy_parse(value: &str, expected: OverflowPolicy) {
+        assert_eq!(value.parse::<OverflowPolicy>().unwrap(), expected);
+    }
+}
@@ -1,260 +1,1359 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
-use atticus::{actor, Requestor};
+use dashmap::DashMap;
 use json_elem::JsonElem;
-use tokio::{net::TcpListener, sync::Mutex};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{watch, Notify},
+    task::{JoinHandle, JoinSet},
+};
 
 use crate::{
-    error::Error,
-    message::{self, MessageType, SocketMessage},
+    codec::{self, Codec},
+    config::{AclPolicy, Limits, Listen, ServerConfig, Transport},
+    error::{CommonErrors, Error, ErrorCode, ErrorOrigin},
+    limiter::{RateLimiter, Verdict},
+    message::{
+        Event, EventReport, GoingAway, Hello, MessageType, MethodDescription, ObjectDescription,
+        ResponseStatus, SocketMessage, Welcome, GOING_AWAY, HEARTBEAT, MIN_PROTOCOL_VERSION,
+    },
+    metrics::{self, Metrics},
     objects::SUCCESS,
-    socket::{Socket, ENV_SERVER_ADDRESS, SERVER_ADDRESS},
-    util, RemoteError, SharedObject, SharedObjectDispatcher,
+    options::{ClientOptions, Heartbeat},
+    outbound::{Outbound, QueueConfig},
+    socket::Socket,
+    RemoteError, SharedObject, SharedObjectDispatcher,
 };
 
-use crate::objects::{ListObjects, RequestListObjects};
+use crate::objects::ListObjects;
+
+/// The number of worker threads of the server runtime, defaults to the number of CPU cores.
+pub const ENV_WORKER_THREADS: &str = "ENV_WORKER_THREADS";
+/// How long a server shutting down waits for the calls in flight, in seconds.
+pub const ENV_DRAIN_TIMEOUT: &str = "ENV_DRAIN_TIMEOUT";
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a closing connection may take to write what is left in its queue.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The in-flight remote calls, keyed by transaction id. The map is sharded
+/// so that independent calls never wait on each other.
+pub type TransactionList = Arc<Transactions>;
+
+/// The map of the in-flight remote calls, which wakes a draining server
+/// whenever one of them is answered.
+#[derive(Debug, Default)]
+pub struct Transactions {
+    calls: DashMap<u64, PendingCall>,
+    answered: Notify,
+}
+
+impl Deref for Transactions {
+    type Target = DashMap<u64, PendingCall>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.calls
+    }
+}
+
+/// A remote call forwarded to the object owner and waiting for its response.
+#[derive(Clone, Debug)]
+pub struct PendingCall {
+    pub caller: Outbound,
+    pub object: String,
+    pub method: String,
+    pub started: Instant,
+    /// The kind of the response the caller waits for.
+    pub response: MessageType,
+}
+
+/// The target of a remote call, read without its parameter.
+#[derive(Deserialize)]
+struct CallTarget<'a> {
+    #[serde(borrow)]
+    object: Cow<'a, str>,
+    #[serde(borrow)]
+    method: Cow<'a, str>,
+}
+
+pub type TransactionId = Arc<AtomicU64>;
 
-pub type TransactionList = Arc<Mutex<HashMap<u64, Socket>>>;
-pub type TransactionId = Arc<Mutex<u64>>;
+fn next_transaction_id(id_count: &TransactionId) -> u64 {
+    id_count.fetch_add(1, Ordering::Relaxed) + 1
+}
 
+/// Starts the server with the settings of the environment, logging the
+/// error when the settings are invalid or it cannot listen.
 pub async fn start_server() {
-    let server_address = std::env::var(ENV_SERVER_ADDRESS).unwrap_or(SERVER_ADDRESS.to_owned());
-    let listener = TcpListener::bind(server_address.as_str()).await.unwrap();
+    let started = match ServerConfig::from_env() {
+        Ok(config) => start_server_with(config).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = started {
+        log::error!("{}", err);
+    }
+}
+
+/// Starts the server and serves until SIGINT or SIGTERM, then shuts it
+/// down gracefully, see [`ServerHandle::stop`].
+pub async fn start_server_with(config: ServerConfig) -> Result<(), Error> {
+    let server = ServerBuilder::from_config(config).start().await?;
+    shutdown_signal().await?;
+    log::info!("Shutting down");
+    server.shutdown().await;
+    Ok(())
+}
+
+/// Completes on Ctrl-C, or on SIGTERM as sent by service managers.
+async fn shutdown_signal() -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Builds a server to embed in a process, such as a supervisor or a test.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), remote_call::Error> {
+/// let server = remote_call::ServerBuilder::new()
+///     .with_address("127.0.0.1:0")
+///     .start()
+///     .await?;
+/// println!("Listening on {}", server.local_addr());
+/// server.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ServerBuilder {
+    config: ServerConfig,
+}
+
+impl ServerBuilder {
+    /// A server on `127.0.0.1:1986`, regardless of the environment.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_config(config: ServerConfig) -> Self {
+        Self { config }
+    }
+
+    /// Listens on this address only. Port 0 lets the system pick one, see
+    /// [`ServerHandle::local_addr`].
+    pub fn with_address(mut self, address: &str) -> Self {
+        self.config.listen = vec![Listen {
+            transport: Transport::Tcp,
+            address: address.to_string(),
+        }];
+        self
+    }
+
+    /// Serves the metrics on `GET /metrics` at the address.
+    pub fn with_metrics(mut self, address: &str) -> Self {
+        self.config.metrics = Some(address.to_string());
+        self
+    }
+
+    /// Sets the outbound queue of every connection.
+    pub fn with_queue(mut self, queue: QueueConfig) -> Self {
+        self.config.limits.queue = queue;
+        self
+    }
+
+    /// Sets how long a shutdown waits for the calls in flight.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.config.limits.drain_timeout = timeout;
+        self
+    }
+
+    /// Sets every limit at once, including the queue and the drain timeout.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    /// Sets the heartbeat with the clients that support it, `None` to
+    /// disable it.
+    pub fn with_heartbeat(mut self, heartbeat: Option<Heartbeat>) -> Self {
+        self.config.heartbeat = heartbeat;
+        self
+    }
+
+    /// Sets which peers may connect.
+    pub fn with_acl(mut self, acl: AclPolicy) -> Self {
+        self.config.acl = acl;
+        self
+    }
+
+    /// Binds every address and starts serving in the background. The
+    /// addresses are all bound before the first connection is accepted, so
+    /// that an address in use is returned as an error instead of a partial
+    /// server.
+    pub async fn start(self) -> Result<ServerHandle, Error> {
+        let config = self.config;
+        let mut listeners = Vec::new();
+        let mut addresses = Vec::new();
+        for listen in &config.listen {
+            let listener = match listen.transport {
+                Transport::Tcp => TcpListener::bind(listen.address.as_str()).await,
+            }
+            .and_then(|listener| Ok((listener.local_addr()?, listener)))
+            .map_err(|err| Error::Others(format!("Cannot listen on {}: {}", listen, err)))?;
+            addresses.push(listener.0);
+            listeners.push(listener);
+        }
+        let Some(local_address) = addresses.first().copied() else {
+            return Err(Error::Others("No listen address".into()));
+        };
+        let metrics_listener = match &config.metrics {
+            Some(address) => Some(
+                TcpListener::bind(address.as_str())
+                    .await
+                    .and_then(|listener| Ok((listener.local_addr()?, listener)))
+                    .map_err(|err| {
+                        Error::Others(format!("Cannot serve the metrics on {}: {}", address, err))
+                    })?,
+            ),
+            None => None,
+        };
+
+        // The connection of the built-in list object, queued until accepted.
+        let own = TcpStream::connect(loopback(local_address))
+            .await
+            .map_err(|err| {
+                Error::Others(format!("Cannot connect to {}: {}", local_address, err))
+            })?;
+        let own_address = own.local_addr()?;
+
+        let (shutdown, stop) = watch::channel(false);
+        let (phase, phase_signal) = watch::channel(Phase::Running);
+        let drain_timeout = config.limits.drain_timeout;
+        let state = ServerState {
+            limits: config.limits,
+            heartbeat: config.heartbeat,
+            acl: Arc::new(config.acl),
+            own_address,
+            connections: Arc::new(AtomicUsize::new(0)),
+            transactions: Arc::default(),
+            id_count: Arc::new(AtomicU64::new(0)),
+            list_objects: Arc::new(ListObjects::new()),
+            metrics: Arc::new(Metrics::new()),
+            phase: phase_signal,
+        };
+
+        let mut accepting = JoinSet::new();
+        for (address, listener) in listeners {
+            log::info!("Server listening on {}", address);
+            accepting.spawn(accept(listener, state.clone()));
+        }
+        // On an error, dropping the accepting tasks closes their connections.
+        let list_task =
+            start_share_list_objects(own, state.list_objects.clone(), state.transactions.clone())
+                .await?;
+
+        let metrics_address = metrics_listener.as_ref().map(|(address, _)| *address);
+        let metrics_task = metrics_listener.map(|(_, listener)| {
+            tokio::spawn(metrics::serve(
+                listener,
+                state.metrics.clone(),
+                state.transactions.clone(),
+            ))
+        });
+        let transactions = state.transactions.clone();
+        let task = tokio::spawn(async move {
+            stopping(stop).await;
+            log::info!("Server going away, {} calls in flight", transactions.len());
+            phase.send_replace(Phase::Draining);
+            drain(&transactions, drain_timeout).await;
+            phase.send_replace(Phase::Closed);
+
+            while accepting.join_next().await.is_some() {}
+            if let Some(metrics_task) = metrics_task {
+                metrics_task.abort();
+            }
+            list_task.abort();
+            log::info!("Server stopped");
+        });
+
+        Ok(ServerHandle {
+            addresses,
+            metrics_address,
+            shutdown,
+            task,
+        })
+    }
+}
+
+/// A running server. Dropping the handle leaves the server running.
+#[derive(Debug)]
+pub struct ServerHandle {
+    addresses: Vec<SocketAddr>,
+    metrics_address: Option<SocketAddr>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// The address of the first listener, with the port the system picked
+    /// when it was 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addresses[0]
+    }
+
+    /// The addresses of every listener, in the order they were given.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    /// The address of the metrics endpoint, if it is served.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
+
+    /// Asks the server to shut down, without waiting for it. The server stops
+    /// accepting connections and new calls, sends a [`GoingAway`] notice to
+    /// the clients that understand it, waits at most the drain timeout for
+    /// the calls in flight, then closes every connection.
+    pub fn stop(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Stops the server and waits until every connection is closed, see
+    /// [`ServerHandle::stop`].
+    pub async fn shutdown(self) {
+        self.stop();
+        self.join().await;
+    }
+
+    /// Waits until the server has stopped.
+    pub async fn join(self) {
+        if let Err(err) = self.task.await {
+            log::error!("Server: {}", err);
+        }
+    }
+}
+
+/// The state shared by the connections of every listener.
+#[derive(Clone)]
+struct ServerState {
+    limits: Limits,
+    heartbeat: Option<Heartbeat>,
+    acl: Arc<AclPolicy>,
+    /// The server's own connection, serving the built-in list object. The
+    /// ACL and the connection limit do not apply to it.
+    own_address: SocketAddr,
+    /// The connections being served, see [`Limits::max_connections`].
+    connections: Arc<AtomicUsize>,
+    transactions: TransactionList,
+    id_count: TransactionId,
+    list_objects: Arc<ListObjects>,
+    metrics: Arc<Metrics>,
+    phase: watch::Receiver<Phase>,
+}
+
+/// A connection counted against [`Limits::max_connections`] until it is dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(connections: &Arc<AtomicUsize>) -> Self {
+        connections.fetch_add(1, Ordering::Relaxed);
+        Self(connections.clone())
+    }
+
+    /// The connections being served, this one included.
+    fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The life of a server once it is started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Running,
+    /// No connection and no new call are accepted, the calls in flight are
+    /// still answered.
+    Draining,
+    Closed,
+}
+
+/// Resolves once the server is asked to stop. A handle dropped without
+/// stopping the server leaves it running.
+async fn stopping(mut shutdown: watch::Receiver<bool>) {
+    if shutdown.wait_for(|stop| *stop).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
 
-    log::trace!("Server listening on {}", server_address);
-    let list_call_object = Arc::new(Mutex::new(HashMap::<u64, Socket>::new()));
-    let id_count = Arc::new(Mutex::new(0_u64));
-    let res = actor::run(ListObjects::new(), 1);
+/// Resolves once the server has reached the phase.
+async fn reached(mut phase: watch::Receiver<Phase>, target: Phase) {
+    if phase.wait_for(|phase| *phase >= target).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
 
-    start_share_list_objects(res.requestor.clone()).await;
+/// Waits until every call in flight is answered, for at most the timeout.
+async fn drain(transactions: &TransactionList, timeout: Duration) {
+    let deadline = tokio::time::Instant::now() + timeout;
     loop {
-        let (socket, addr) = listener.accept().await.unwrap();
-        let socket = Socket::new(socket, addr);
-        let list_object_requestor = res.requestor.clone();
-        let inner_list_call_object = list_call_object.clone();
-        let inner_id_count = id_count.clone();
-        tokio::spawn(async move {
-            log::trace!("Connected: {}", socket.ip_address());
+        let answered = transactions.answered.notified();
+        if transactions.is_empty() || tokio::time::timeout_at(deadline, answered).await.is_err() {
+            break;
+        }
+    }
+    if !transactions.is_empty() {
+        log::warn!(
+            "Closing with {} calls in flight after {:?}",
+            transactions.len(),
+            timeout
+        );
+    }
+}
+
+/// The address the server connects to itself on, a loopback address when
+/// it listens on every interface.
+fn loopback(mut address: SocketAddr) -> SocketAddr {
+    if address.ip().is_unspecified() {
+        address.set_ip(match address.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    address
+}
 
-            loop {
-                let mut data = Vec::new();
+/// Accepts connections until the server drains, then waits for the
+/// connections it accepted to close.
+async fn accept(listener: TcpListener, state: ServerState) {
+    let stop = reached(state.phase.clone(), Phase::Draining);
+    tokio::pin!(stop);
+    let mut connections = JoinSet::new();
 
-                match socket.read(&mut data).await {
-                    Ok(usize) => {
-                        if usize == 0 {
-                            break;
-                        }
-                    }
-                    Err(err) => {
-                        log::error!("{}", err);
-                        break;
-                    }
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = &mut stop => break,
+        };
+        let (socket, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Such as running out of file descriptors, which may pass.
+                log::error!("Accept: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let socket = Socket::new(socket, addr).with_max_frame_size(state.limits.max_frame_size);
+        if addr == state.own_address {
+            connections.spawn(serve_connection(socket, state.clone(), None));
+            continue;
+        }
+        if !state.acl.permits(addr.ip()) {
+            log::warn!(connection = addr.to_string().as_str(); "Refused {} by the ACL", addr);
+            continue;
+        }
+        let slot = ConnectionSlot::take(&state.connections);
+        match state.limits.max_connections {
+            Some(max) if slot.count() > max => {
+                connections.spawn(reject(
+                    socket,
+                    format!("Too many connections, at most {} are served", max),
+                ));
+            }
+            _ => {
+                connections.spawn(serve_connection(socket, state.clone(), Some(slot)));
+            }
+        }
+    }
+
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+}
+
+/// Turns down a connection in its handshake, so that the client can tell
+/// why. A peer that does not open with a hello is just closed.
+async fn reject(socket: Socket, reason: String) {
+    log::warn!(
+        connection = socket.ip_address().as_str();
+        "Refused {}: {}", socket.ip_address(), reason
+    );
+    let hello = match tokio::time::timeout(FLUSH_TIMEOUT, socket.receive()).await {
+        Ok(Ok(msg)) if msg.kind() == MessageType::Hello => msg,
+        _ => return,
+    };
+    let welcome = SocketMessage::new()
+        .set_id(hello.id())
+        .set_kind(MessageType::Welcome)
+        .set_body(&Welcome::reject(reason).as_bytes());
+    if let Err(err) = socket.send(&welcome).await {
+        log::warn!("[{}] Reject: {}", socket.ip_address(), err);
+    }
+}
+
+async fn serve_connection(socket: Socket, state: ServerState, _slot: Option<ConnectionSlot>) {
+    let outbound = Outbound::spawn(socket.clone(), state.limits.queue);
+    let ServerState {
+        limits,
+        heartbeat,
+        transactions,
+        id_count,
+        list_objects,
+        metrics,
+        phase,
+        ..
+    } = state;
+    let drain_started = reached(phase.clone(), Phase::Draining);
+    let closed = reached(phase, Phase::Closed);
+    tokio::pin!(drain_started, closed);
+    let mut draining = false;
+    let mut flush = false;
+    let mut limiter = limits.rate_limit.map(RateLimiter::new);
+    // The peers are pinged once they announce the heartbeat in their hello.
+    let mut pinging = false;
+    let mut ticker = tokio::time::interval(heartbeat.unwrap_or_default().interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    list_objects.connect(socket.ip_address());
+    metrics.connected();
+    log::trace!("Connected: {}", socket.ip_address());
+
+    loop {
+        let msg = tokio::select! {
+            res = socket.receive() => match res {
+                Ok(msg) => msg,
+                Err(Error::Serde(err)) => {
+                    log::error!("Invalid message from {}: {}", outbound.peer(), err);
+                    metrics.error(CommonErrors::SerdeParseError.as_ref().as_bytes());
+                    continue;
                 }
-                let sep = util::separate(data.as_slice());
-                for data in sep {
-                    match serde_json::from_slice::<SocketMessage>(data.as_slice()) {
-                        Ok(msg) => {
-                            if let Err(err) = process_message(
-                                msg,
-                                socket.clone(),
-                                inner_id_count.clone(),
-                                inner_list_call_object.clone(),
-                                list_object_requestor.clone(),
-                            )
-                            .await
-                            {
-                                log::error!("Error process_message: {}", err);
-                                break;
-                            }
-                        }
-                        Err(error) => {
-                            log::error!(
-                                "Invalid message from {}: {}\nStream: {:?}",
-                                socket.ip_address(),
-                                error.to_string(),
-                                String::from_utf8(data)
-                            );
-                            break;
-                        }
-                    }
+                Err(Error::FrameTooLarge(limit)) => {
+                    log::warn!(
+                        connection = outbound.peer().as_str();
+                        "[{}] Disconnected, sent a frame over {} bytes", outbound.peer(), limit
+                    );
+                    // The frame is not read, the error has no transaction.
+                    let msg = SocketMessage::new().set_kind(MessageType::RemoteCallRequest);
+                    let err = limit_exceeded(format!("Frame larger than {} bytes.", limit));
+                    refuse(msg, &outbound, err);
+                    flush = true;
+                    break;
+                }
+                Err(err) => {
+                    log::error!("{}", err);
+                    break;
                 }
+            },
+            _ = outbound.closed() => break,
+            _ = &mut drain_started, if !draining => {
+                draining = true;
+                going_away(&outbound, limits.drain_timeout);
+                continue;
             }
-            log::trace!("Disconnected: {}", socket.ip_address());
-            let _ = list_object_requestor
-                .request(RequestListObjects::Remove(socket.clone()))
-                .await;
-        });
+            _ = &mut closed => {
+                flush = true;
+                break;
+            }
+            _ = ticker.tick(), if pinging => {
+                let ping = SocketMessage::new().set_kind(MessageType::Ping);
+                if let Err(err) = outbound.send(ping) {
+                    log::warn!("[{}] Heartbeat: {}", outbound.peer(), err);
+                }
+                continue;
+            }
+        };
+        if draining
+            && matches!(
+                msg.kind(),
+                MessageType::RemoteCallRequest | MessageType::StreamRequest
+            )
+        {
+            let err = RemoteError::from_code(
+                ErrorCode::ServerConnectionError,
+                ErrorOrigin::Broker,
+                "The server is going away.",
+            );
+            refuse(msg, &outbound, err);
+            continue;
+        }
+        let limited = matches!(
+            msg.kind(),
+            MessageType::RemoteCallRequest
+                | MessageType::DescribeRequest
+                | MessageType::StreamRequest
+                | MessageType::SendEventRequest
+        );
+        if let Some(limiter) = limiter.as_mut().filter(|_| limited) {
+            let verdict = limiter.check();
+            if verdict != Verdict::Allow {
+                let err = limit_exceeded(format!(
+                    "Too many requests, at most {} per second.",
+                    limiter.limit().rate
+                ));
+                refuse(msg, &outbound, err);
+                if verdict == Verdict::Disconnect {
+                    log::warn!(
+                        connection = outbound.peer().as_str();
+                        "[{}] Disconnected, kept sending past the rate limit", outbound.peer()
+                    );
+                    flush = true;
+                    break;
+                }
+                continue;
+            }
+        }
+        let pending = matches!(
+            msg.kind(),
+            MessageType::RemoteCallRequest
+                | MessageType::DescribeRequest
+                | MessageType::StreamRequest
+        );
+        if let Some(max) = limits.max_pending_calls.filter(|_| pending) {
+            if outbound.pending_calls() >= max {
+                let err = limit_exceeded(format!("Too many pending calls, at most {}.", max));
+                refuse(msg, &outbound, err);
+                continue;
+            }
+        }
+        let hello = msg.kind() == MessageType::Hello;
+        if let Err(err) = process_message(
+            msg,
+            outbound.clone(),
+            id_count.clone(),
+            transactions.clone(),
+            list_objects.clone(),
+            metrics.clone(),
+        )
+        .await
+        {
+            if let Error::UnexpectedMessage(kind) = err {
+                log::warn!(
+                    connection = outbound.peer().as_str();
+                    "[{}] Disconnected, sent an unexpected {:?}", outbound.peer(), kind
+                );
+                break;
+            }
+            log::error!("Error process_message: {}", err);
+        }
+        if let Some(heartbeat) = heartbeat.filter(|_| hello && outbound.supports(HEARTBEAT)) {
+            socket.set_heartbeat(&heartbeat);
+            ticker.reset();
+            pinging = true;
+        }
+    }
+    log::trace!("Disconnected: {}", outbound.peer());
+    if flush {
+        // Lets the notice, the last responses or the error reach a peer that reads.
+        outbound.close_when_flushed();
+        let _ = tokio::time::timeout(FLUSH_TIMEOUT, outbound.closed()).await;
+    }
+    outbound.close();
+    abandon(&transactions, &list_objects.owned_by(&outbound), &metrics);
+    cancel_streams(&transactions, &outbound, &list_objects);
+    list_objects.remove(outbound);
+    metrics.disconnected();
+}
+
+/// Tells the peer that the server is going away, if it understands it.
+fn going_away(outbound: &Outbound, drain_timeout: Duration) {
+    if !outbound.supports(GOING_AWAY) {
+        return;
+    }
+    let notice = GoingAway {
+        reason: "The server is shutting down.".to_string(),
+        drain_ms: drain_timeout.as_millis() as u64,
+    };
+    let msg = SocketMessage::new()
+        .set_kind(MessageType::GoingAway)
+        .set_body(&notice.as_bytes());
+    if let Err(err) = outbound.send(msg) {
+        log::warn!("[{}] Going away: {}", outbound.peer(), err);
+    }
+}
+
+fn limit_exceeded(message: String) -> RemoteError {
+    RemoteError::from_code(ErrorCode::LimitExceeded, ErrorOrigin::Broker, message)
+}
+
+/// Answers a request with the error instead of serving it. The events sent
+/// without ack have no response and are dropped.
+fn refuse(msg: SocketMessage, outbound: &Outbound, err: RemoteError) {
+    log::info!(
+        connection = outbound.peer().as_str();
+        "[{}] Refused {}: {}", outbound.peer(), msg, err
+    );
+    let msg = match msg.kind() {
+        MessageType::RemoteCallRequest => msg
+            .set_kind(MessageType::RemoteCallResponse)
+            .set_status(Some(ResponseStatus::Broker)),
+        MessageType::DescribeRequest => msg.set_kind(MessageType::DescribeResponse),
+        MessageType::StreamRequest => msg
+            .set_kind(MessageType::StreamEnd)
+            .set_status(Some(ResponseStatus::Broker)),
+        MessageType::SendEventRequest if wants_ack(&msg) => {
+            msg.set_kind(MessageType::SendEventResponse)
+        }
+        _ => return,
+    };
+    if let Err(err) = outbound.send(msg.set_body(&err.as_bytes())) {
+        log::warn!("[{}] Refused: {}", outbound.peer(), err);
+    }
+}
+
+/// Whether the sender of the event waits for its report.
+fn wants_ack(msg: &SocketMessage) -> bool {
+    serde_json::from_slice::<Event>(msg.body())
+        .map(|event| event.ack)
+        .unwrap_or_default()
+}
+
+/// Records a call forwarded to the object owner, counted against its caller.
+fn track(transactions: &TransactionList, id: u64, call: PendingCall) {
+    call.caller.call_started();
+    transactions.insert(id, call);
+}
+
+/// Answers the calls still waiting on the objects of a connection that
+/// closed, instead of leaving their callers waiting.
+fn abandon(transactions: &TransactionList, objects: &[String], metrics: &Metrics) {
+    let ids: Vec<u64> = transactions
+        .iter()
+        .filter(|call| objects.contains(&call.object))
+        .map(|call| *call.key())
+        .collect();
+    for id in ids {
+        let Some(call) = untrack(transactions, id) else {
+            continue;
+        };
+        let err = RemoteError::from(CommonErrors::RemoteConnectionError);
+        let mut msg = SocketMessage::new()
+            .set_id(id)
+            .set_kind(call.response)
+            .set_body(&err.as_bytes());
+        if call.response != MessageType::DescribeResponse {
+            msg = msg.set_status(Some(ResponseStatus::Broker));
+        }
+        metrics.error(msg.body());
+        if let Err(err) = call.caller.send(msg) {
+            log::warn!("[{}] Abandoned call: {}", call.caller.peer(), err);
+        }
     }
 }
 
+/// Cancels the streams of a caller that closed, so that their owners stop
+/// sending them.
+fn cancel_streams(transactions: &TransactionList, caller: &Outbound, list_objects: &ListObjects) {
+    let ids: Vec<u64> = transactions
+        .iter()
+        .filter(|call| {
+            call.response == MessageType::StreamEnd
+                && call.caller.ip_address() == caller.ip_address()
+        })
+        .map(|call| *call.key())
+        .collect();
+    for id in ids {
+        let Some(call) = untrack(transactions, id) else {
+            continue;
+        };
+        if let Some(owner) = list_objects.owner(&call.object) {
+            let cancel = SocketMessage::new()
+                .set_id(id)
+                .set_kind(MessageType::StreamCancel);
+            if let Err(err) = owner.send(cancel) {
+                log::warn!("[{}] Cancel stream: {}", owner.peer(), err);
+            }
+        }
+    }
+}
+
+/// Forgets a call once it is answered.
+fn untrack(transactions: &TransactionList, id: u64) -> Option<PendingCall> {
+    let (_, call) = transactions.remove(&id)?;
+    call.caller.call_ended();
+    transactions.answered.notify_waiters();
+    Some(call)
+}
+
+/// Forgets a call once it is answered by the owner of its object, with the
+/// response the call waits for. A peer cannot answer the calls of objects
+/// it does not own, those responses leave the call pending.
+fn untrack_answer(
+    transactions: &TransactionList,
+    list_objects: &ListObjects,
+    sender: &Outbound,
+    msg: &SocketMessage,
+) -> Option<PendingCall> {
+    let (_, call) = transactions.remove_if(&msg.id(), |_, call| {
+        answers(call, msg.kind(), sender, list_objects)
+    })?;
+    call.caller.call_ended();
+    transactions.answered.notify_waiters();
+    Some(call)
+}
+
+/// Whether the sender owns the object of the call and the response is of
+/// the kind the call waits for.
+fn answers(
+    call: &PendingCall,
+    response: MessageType,
+    sender: &Outbound,
+    list_objects: &ListObjects,
+) -> bool {
+    call.response == response
+        && list_objects
+            .owner(&call.object)
+            .is_some_and(|owner| owner.ip_address() == sender.ip_address())
+}
+
 async fn process_message(
     mut msg: SocketMessage,
-    socket: Socket,
+    socket: Outbound,
     inner_id_count: TransactionId,
     inner_list_call_object: TransactionList,
-    list_object_requestor: Requestor<RequestListObjects, SocketMessage>,
+    list_objects: Arc<ListObjects>,
+    metrics: Arc<Metrics>,
 ) -> Result<(), Error> {
     match msg.kind() {
         MessageType::AddShareObjectRequest => {
-            let mut id = inner_id_count.lock().await;
-            *id += 1;
-            msg = msg.set_id(*id);
-            log::info!("[{}] {}", socket.ip_address(), msg);
-            let res: Result<Option<SocketMessage>, atticus::Error> = list_object_requestor
-                .request(RequestListObjects::Add(msg, socket.clone()))
-                .await;
-            let msg =
-                message::result_to_socket_message(res, *id, MessageType::AddShareObjectResponse);
-            socket.write(&msg.as_bytes()).await?;
+            let id = next_transaction_id(&inner_id_count);
+            msg = msg.set_id(id);
+            log::info!(
+                connection = socket.peer().as_str(),
+                transaction = msg.id();
+                "[{}] {}", socket.peer(), msg
+            );
+            let msg = list_objects.add(msg, socket.clone());
+            socket.send(msg)?;
         }
         MessageType::RemoteCallRequest => {
-            let mut lst = inner_list_call_object.lock().await;
-            let mut id = inner_id_count.lock().await;
-            *id += 1;
-            msg = msg.set_id(*id);
-            lst.insert(*id, socket.clone());
-
-            log::info!("[{}] {}", socket.ip_address(), msg);
-            let res = list_object_requestor
-                .request(RequestListObjects::CallMethod(msg))
-                .await?
-                .ok_or(Error::Others("No message".to_string()))?;
-            if res.body() != SUCCESS.as_bytes() {
-                socket.write(&res.as_bytes()).await?;
+            let id = next_transaction_id(&inner_id_count);
+            msg = msg.set_id(id).set_caller(Some(socket.caller()));
+            let (object, method) = match serde_json::from_slice::<CallTarget>(msg.body()) {
+                Ok(target) => (target.object.into_owned(), target.method.into_owned()),
+                Err(err) => {
+                    log::error!("[{}] Invalid call: {}", socket.peer(), err);
+                    let err = RemoteError::from(CommonErrors::SerdeParseError);
+                    metrics.error(&err.as_bytes());
+                    refuse(msg, &socket, err);
+                    return Ok(());
+                }
+            };
+            log::info!(
+                connection = socket.peer().as_str(),
+                transaction = id,
+                object = object.as_str(),
+                method = method.as_str(),
+                trace_id = msg.trace().map(|trace| trace.trace_id.as_str()),
+                span_id = msg.trace().map(|trace| trace.span_id.as_str());
+                "[{}] {}", socket.peer(), msg
+            );
+            track(
+                &inner_list_call_object,
+                id,
+                PendingCall {
+                    caller: socket.clone(),
+                    object: object.clone(),
+                    method: method.clone(),
+                    started: Instant::now(),
+                    response: MessageType::RemoteCallResponse,
+                },
+            );
+
+            // Only the calls of registered objects are counted by name.
+            let res = list_objects.call_method(&object, msg);
+            if res.body() == SUCCESS.as_bytes() {
+                metrics.call(&object, &method);
+            } else {
+                untrack(&inner_list_call_object, id);
+                metrics.error(res.body());
+                socket.send(res)?;
             }
         }
         MessageType::RemoteCallResponse => {
-            log::info!("[{}] {}", socket.ip_address(), msg);
-            let mut lst = inner_list_call_object.lock().await;
-            if let Some(remote) = lst.get(&msg.id()) {
-                let data = serde_json::to_vec(&msg)?;
-                remote.write(&data).await?;
-                lst.remove(&msg.id());
+            if let Some(call) =
+                untrack_answer(&inner_list_call_object, &list_objects, &socket, &msg)
+            {
+                log::info!(
+                    connection = socket.peer().as_str(),
+                    transaction = msg.id(),
+                    object = call.object.as_str(),
+                    method = call.method.as_str(),
+                    duration_ms = call.started.elapsed().as_secs_f64() * 1000.0;
+                    "[{}] {}", socket.peer(), msg
+                );
+                let is_error = match msg.status() {
+                    Some(status) => status != ResponseStatus::Ok,
+                    None => serde_json::from_slice::<RemoteError>(msg.body()).is_ok(),
+                };
+                list_objects.record_response(&call.object, is_error);
+                metrics.call_latency(&call.object, &call.method, call.started.elapsed());
+                if is_error {
+                    metrics.error(msg.body());
+                }
+                call.caller.send(msg)?;
+            } else {
+                log::warn!(
+                    connection = socket.peer().as_str(),
+                    transaction = msg.id();
+                    "[{}] {} answers no pending call of its own", socket.peer(), msg
+                );
+            }
+        }
+        MessageType::DescribeRequest => {
+            let id = next_transaction_id(&inner_id_count);
+            msg = msg.set_id(id);
+            track(
+                &inner_list_call_object,
+                id,
+                PendingCall {
+                    caller: socket.clone(),
+                    object: String::from_utf8_lossy(msg.body()).to_string(),
+                    method: "describe".to_string(),
+                    started: Instant::now(),
+                    response: MessageType::DescribeResponse,
+                },
+            );
+
+            log::info!(
+                connection = socket.peer().as_str(),
+                transaction = msg.id();
+                "[{}] {}", socket.peer(), msg
+            );
+            let res = list_objects.describe(msg);
+            if res.body() != SUCCESS.as_bytes() {
+                untrack(&inner_list_call_object, id);
+                metrics.error(res.body());
+                socket.send(res)?;
+            }
+        }
+        MessageType::DescribeResponse => {
+            log::info!(
+                connection = socket.peer().as_str(),
+                transaction = msg.id();
+                "[{}] {}", socket.peer(), msg
+            );
+            if let Some(call) =
+                untrack_answer(&inner_list_call_object, &list_objects, &socket, &msg)
+            {
+                call.caller.send(msg)?;
+            } else {
+                log::warn!(
+                    connection = socket.peer().as_str(),
+                    transaction = msg.id();
+                    "[{}] {} answers no pending describe of its own", socket.peer(), msg
+                );
             }
         }
         MessageType::SendEventRequest => {
-            let mut id = inner_id_count.lock().await;
-            *id += 1;
-            msg = msg.set_id(*id);
-            log::info!("[{}] {}", socket.ip_address(), msg);
+            let id = next_transaction_id(&inner_id_count);
+            msg = msg.set_id(id);
+            log::info!(
+                connection = socket.peer().as_str(),
+                transaction = msg.id();
+                "[{}] {}", socket.peer(), msg
+            );
 
-            let ret = list_object_requestor
-                .request(RequestListObjects::SendEvent(msg))
-                .await;
-            log::trace!("{:?}", ret);
+            let ack = wants_ack(&msg);
+            let res = list_objects.send_event(msg);
+            match serde_json::from_slice::<EventReport>(res.body()) {
+                Ok(report) => metrics.event(report.delivered),
+                Err(_) => metrics.error(res.body()),
+            }
+            if ack {
+                socket.send(res)?;
+            } else {
+                log::trace!("{}", res);
+            }
         }
         MessageType::SubscribeEventRequest => {
-            let mut id = inner_id_count.lock().await;
-            *id += 1;
-            msg = msg.set_id(*id);
-            log::info!("[{}] {}", socket.ip_address(), msg);
+            let id = next_transaction_id(&inner_id_count);
+            msg = msg.set_id(id);
+            log::info!(
+                connection = socket.peer().as_str(),
+                transaction = msg.id();
+                "[{}] {}", socket.peer(), msg
+            );
 
-            let ret = list_object_requestor
-                .request(RequestListObjects::SubscribeEvent(msg, socket.clone()))
-                .await;
-            log::trace!("{:?}", ret);
+            let ret = list_objects.subscribe_event(msg, socket.clone());
+            log::trace!("{}", ret);
         }
         MessageType::WaitForObject => {
-            let mut id = inner_id_count.lock().await;
-            *id += 1;
-            msg = msg.set_id(*id);
-            log::info!("[{}] {}", socket.ip_address(), msg);
-            let res: Result<Option<SocketMessage>, atticus::Error> = list_object_requestor
-                .request(RequestListObjects::WaitForObject(msg))
-                .await;
-            let msg = message::result_to_socket_message(res, *id, MessageType::WaitForObject);
-            socket.write(&msg.as_bytes()).await?;
+            let id = next_transaction_id(&inner_id_count);
+            msg = msg.set_id(id);
+            log::info!(
+                connection = socket.peer().as_str(),
+                transaction = msg.id();
+                "[{}] {}", socket.peer(), msg
+            );
+            let msg = list_objects.wait_for_object(msg);
+            socket.send(msg)?;
         }
-        _ => {
-            unimplemented!("{:?}", msg.kind());
+        MessageType::StreamRequest => {
+            let id = next_transaction_id(&inner_id_count);
+            msg = msg.set_id(id).set_caller(Some(socket.caller()));
+            let (object, method) = serde_json::from_slice::<CallTarget>(msg.body())
+                .map(|target| (target.object.into_owned(), target.method.into_owned()))
+                .unwrap_or_default();
+            log::info!(
+                connection = socket.peer().as_str(),
+                transaction = id,
+                object = object.as_str(),
+                method = method.as_str(),
+                trace_id = msg.trace().map(|trace| trace.trace_id.as_str()),
+                span_id = msg.trace().map(|trace| trace.span_id.as_str());
+                "[{}] {}", socket.peer(), msg
+            );
+            track(
+                &inner_list_call_object,
+                id,
+                PendingCall {
+                    caller: socket.clone(),
+                    object: object.clone(),
+                    method: method.clone(),
+                    started: Instant::now(),
+                    response: MessageType::StreamEnd,
+                },
+            );
+
+            let res = list_objects.stream(msg);
+            if res.body() == SUCCESS.as_bytes() {
+                metrics.call(&object, &method);
+            } else {
+                untrack(&inner_list_call_object, id);
+                metrics.error(res.body());
+                socket.send(res)?;
+            }
+        }
+        MessageType::StreamChunk => {
+            log::trace!("[{}] {}", socket.peer(), msg);
+            let caller = inner_list_call_object
+                .get(&msg.id())
+                .filter(|call| answers(call, MessageType::StreamEnd, &socket, &list_objects))
+                .map(|call| call.caller.clone());
+            if let Some(caller) = caller {
+                caller.send(msg)?;
+            } else {
+                log::warn!(
+                    connection = socket.peer().as_str(),
+                    transaction = msg.id();
+                    "[{}] {} belongs to no pending stream of its own", socket.peer(), msg
+                );
+            }
+        }
+        MessageType::StreamEnd => {
+            if let Some(call) =
+                untrack_answer(&inner_list_call_object, &list_objects, &socket, &msg)
+            {
+                log::info!(
+                    connection = socket.peer().as_str(),
+                    transaction = msg.id(),
+                    object = call.object.as_str(),
+                    method = call.method.as_str(),
+                    duration_ms = call.started.elapsed().as_secs_f64() * 1000.0;
+                    "[{}] {}", socket.peer(), msg
+                );
+                let is_error = msg.status() != Some(ResponseStatus::Ok);
+                list_objects.record_response(&call.object, is_error);
+                metrics.call_latency(&call.object, &call.method, call.started.elapsed());
+                if is_error {
+                    metrics.error(msg.body());
+                }
+                call.caller.send(msg)?;
+            } else {
+                log::warn!(
+                    connection = socket.peer().as_str(),
+                    transaction = msg.id();
+                    "[{}] {} ends no pending stream of its own", socket.peer(), msg
+                );
+            }
+        }
+        MessageType::StreamCredit | MessageType::StreamCancel => {
+            log::trace!("[{}] {}", socket.peer(), msg);
+            // Only the caller of a stream may pace or cancel it.
+            let object = inner_list_call_object
+                .get(&msg.id())
+                .filter(|call| call.caller.ip_address() == socket.ip_address())
+                .map(|call| call.object.clone());
+            if let Some(owner) = object.and_then(|object| list_objects.owner(&object)) {
+                owner.send(msg)?;
+            }
+        }
+        MessageType::Hello => {
+            log::info!(
+                connection = socket.peer().as_str(),
+                transaction = msg.id();
+                "[{}] {}", socket.peer(), msg
+            );
+            let (welcome, codec) = match serde_json::from_slice::<Hello>(msg.body()) {
+                Ok(hello) => {
+                    socket.set_name(&hello.name, hello.pid);
+                    socket.set_features(&hello.features);
+                    list_objects.identify(&socket.ip_address(), &hello);
+                    handshake(&hello)
+                }
+                Err(err) => (Welcome::reject(format!("Invalid hello: {}", err)), None),
+            };
+
+            socket.send(
+                SocketMessage::new()
+                    .set_id(msg.id())
+                    .set_kind(MessageType::Welcome)
+                    .set_body(&welcome.as_bytes()),
+            )?;
+            match codec {
+                Some(codec) => socket.set_codec(codec),
+                None => {
+                    log::warn!(
+                        "[{}] Rejected: {}",
+                        socket.peer(),
+                        welcome.error.unwrap_or_default()
+                    );
+                    socket.close_when_flushed();
+                }
+            }
         }
+        kind => return Err(Error::UnexpectedMessage(kind)),
     }
     Ok(())
 }
 
-struct ListObject(Requestor<RequestListObjects, SocketMessage>);
+/// Answers the hello of a client. The codec is the first one of the client
+/// preference that this server supports, and there is none when the client
+/// is rejected.
+fn handshake(hello: &Hello) -> (Welcome, Option<Arc<dyn Codec>>) {
+    if hello.protocol < MIN_PROTOCOL_VERSION {
+        let error = format!(
+            "Incompatible client {}: protocol version {}, at least {} is required",
+            hello.version, hello.protocol, MIN_PROTOCOL_VERSION
+        );
+        return (Welcome::reject(error), None);
+    }
+
+    let codec = hello
+        .codecs
+        .iter()
+        .find_map(|name| codec::by_name(name))
+        .unwrap_or_else(|| Arc::new(codec::JsonCodec));
+    (Welcome::new(codec.name()), Some(codec))
+}
+
+/// An answer of the built-in `list` object that could not be converted.
+fn parse_error(err: impl std::fmt::Display) -> RemoteError {
+    RemoteError::from_code(ErrorCode::SerdeParseError, ErrorOrigin::Broker, err)
+}
+
+/// The built-in `list` object, to inspect the server.
+struct ListObject {
+    objects: Arc<ListObjects>,
+    transactions: TransactionList,
+}
+
+impl ListObject {
+    fn pending_calls(&self) -> Vec<u8> {
+        #[derive(Serialize)]
+        struct Call {
+            id: u64,
+            caller: String,
+            object: String,
+            method: String,
+            age_ms: u64,
+        }
+        #[derive(Serialize)]
+        struct Calls {
+            calls: Vec<Call>,
+        }
+        let mut calls: Vec<Call> = self
+            .transactions
+            .iter()
+            .map(|entry| Call {
+                id: *entry.key(),
+                caller: entry.caller.peer(),
+                object: entry.object.clone(),
+                method: entry.method.clone(),
+                age_ms: entry.started.elapsed().as_millis() as u64,
+            })
+            .collect();
+        calls.sort_by_key(|call| call.id);
+
+        serde_json::to_vec(&Calls { calls }).unwrap_or_else(|err| err.to_string().into_bytes())
+    }
+}
 
 #[async_trait]
 impl SharedObject for ListObject {
+    fn describe(&self) -> Option<ObjectDescription> {
+        let schema = |schema: &str| JsonElem::try_from(schema).unwrap_or(JsonElem::Null);
+        let object = schema(r#"{"type":"object"}"#);
+        let none = schema(r#"{"type":"null"}"#);
+
+        Some(ObjectDescription::new(vec![
+            MethodDescription::new("listObjects", "Lists the registered shared objects.")
+                .with_params(none.clone())
+                .with_returns(object.clone()),
+            MethodDescription::new(
+                "listConnections",
+                "Lists the connected clients with their objects and subscriptions.",
+            )
+            .with_params(none.clone())
+            .with_returns(object.clone()),
+            MethodDescription::new("listEvents", "Lists the subscribers of every event.")
+                .with_params(none.clone())
+                .with_returns(object.clone()),
+            MethodDescription::new(
+                "objectInfo",
+                "Returns the owner, the registration time and the call counters of an object.",
+            )
+            .with_params(schema(r#"{"type":"string"}"#))
+            .with_returns(object.clone()),
+            MethodDescription::new(
+                "pendingCalls",
+                "Lists the remote calls waiting for a response.",
+            )
+            .with_params(none)
+            .with_returns(object),
+        ]))
+    }
+
     async fn remote_call(&self, method: &str, param: JsonElem) -> Result<JsonElem, RemoteError> {
         log::trace!("Method: {} Param: {:?}", method, param);
         match method {
             "listObjects" => {
-                let result = self
-                    .0
-                    .request(RequestListObjects::ListObject)
-                    .await
-                    .map_err(|err| RemoteError::new(JsonElem::String(err.to_string())))?
-                    .ok_or_else(|| RemoteError::new(JsonElem::String("No list".to_string())))?;
-                Ok(JsonElem::try_from(result.body())
-                    .map_err(|err| RemoteError::new(JsonElem::String(err.to_string())))?)
+                let result = self.objects.list_objects();
+                Ok(JsonElem::try_from(result.body()).map_err(parse_error)?)
             }
-            _ => Err(RemoteError::new(JsonElem::String(format!(
-                "{} method not found.",
-                method
-            )))),
+            "listConnections" => {
+                let result = self.objects.list_connections();
+                Ok(JsonElem::try_from(result.body()).map_err(parse_error)?)
+            }
+            "listEvents" => {
+                let result = self.objects.list_events();
+                Ok(JsonElem::try_from(result.body()).map_err(parse_error)?)
+            }
+            "objectInfo" => {
+                let JsonElem::String(object) = param else {
+                    return Err(RemoteError::from_code(
+                        ErrorCode::InvalidParams,
+                        ErrorOrigin::Broker,
+                        "objectInfo expects the object name.",
+                    ));
+                };
+                let result = self.objects.object_info(&object)?;
+                Ok(JsonElem::try_from(result.body()).map_err(parse_error)?)
+            }
+            "pendingCalls" => {
+                Ok(JsonElem::try_from(self.pending_calls().as_slice()).map_err(parse_error)?)
+            }
+            _ => Err(RemoteError::from_code(
+                ErrorCode::MethodNotFound,
+                ErrorOrigin::Broker,
+                format!("{} method not found.", method),
+            )),
         }
     }
 }
 
-async fn start_share_list_objects(requestor: Requestor<RequestListObjects, SocketMessage>) {
-    tokio::spawn(async move {
-        let mut shared = SharedObjectDispatcher::new().await.unwrap();
-        let object = ListObject(requestor);
+/// Registers the built-in list object on the server's own connection.
+async fn start_share_list_objects(
+    stream: TcpStream,
+    list_objects: Arc<ListObjects>,
+    transactions: TransactionList,
+) -> Result<JoinHandle<Result<(), Error>>, Error> {
+    let options = ClientOptions::default().with_name("remote-call");
+    let socket = Socket::connect_stream(stream, &options).await?;
+    let mut shared = SharedObjectDispatcher::from_socket(socket);
+    let object = ListObject {
+        objects: list_objects,
+        transactions,
+    };
 
-        shared
-            .register_object("list", Box::new(object))
-            .await
-            .unwrap();
-        shared.spawn().await;
-    });
+    shared
+        .register_object("list", Box::new(object))
+        .await
+        .map_err(|err| Error::Others(format!("Cannot register the list object: {}", err)))?;
+    Ok(shared.spawn().await)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Once},
+        time::{Duration, Instant},
+    };
 
     use crate::{
+        config::{Limits, RateLimit},
         connector::Connector,
-        error::{CommonErrors, RemoteError},
+        error::{CommonErrors, ErrorCode, ErrorOrigin, RemoteError},
         logger::setup_logger,
-        shared_object::{SharedObject, SharedObjectDispatcher},
-        socket::ENV_SERVER_ADDRESS,
-        wait_for_object::wait_for_objects,
+        message::{MessageType, ResponseStatus, SocketMessage},
+        objects::SUCCESS,
+        options::ClientOptions,
+        options::Heartbeat,
+        outbound::{OverflowPolicy, QueueConfig},
+        shared_object::{CallContext, SharedObject, SharedObjectDispatcher},
+        stream::StreamSender,
+        trace::{self, TraceContext},
+        wait_for_object::wait_for_objects_with,
         EventListener,
     };
     use async_trait::async_trait;
     use json_elem::JsonElem;
-    use tokio::{runtime::Builder, sync::Mutex, task::LocalSet};
-
-    use super::start_server;
-
-    fn find_available_port(start_port: u16) -> Option<u16> {
-        (start_port..=u16::MAX)
-            .find(|&port| std::net::TcpListener::bind(("127.0.0.1", port)).is_ok())
+    use tokio::sync::{Mutex, Notify};
+
+    use super::{ServerBuilder, ServerHandle};
+
+    /// Starts a server of its own on a port the system picks, and the
+    /// options of a client of that server.
+    async fn start() -> (ServerHandle, ClientOptions) {
+        start_with(Limits {
+            drain_timeout: Duration::from_secs(1),
+            ..Limits::default()
+        })
+        .await
     }
 
-    #[ctor::ctor]
-    fn setup_server() {
-        let address = format!("127.0.0.1:{}", find_available_port(3000).unwrap());
-
-        std::env::set_var(ENV_SERVER_ADDRESS, address);
-        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    async fn start_with(limits: Limits) -> (ServerHandle, ClientOptions) {
+        serve(ServerBuilder::new().with_limits(limits)).await
+    }
 
-        std::thread::spawn(move || {
-            let local = LocalSet::new();
-            local.spawn_local(async move {
-                // The server
-                let server = tokio::spawn(async move {
-                    setup_logger();
-                    start_server().await;
-                });
+    async fn serve(builder: ServerBuilder) -> (ServerHandle, ClientOptions) {
+        static LOGGER: Once = Once::new();
+        LOGGER.call_once(setup_logger);
 
-                let _ = server.await;
-            });
-            runtime.block_on(local);
-        });
+        let server = builder
+            .with_address("127.0.0.1:0")
+            .with_metrics("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let options = ClientOptions::default().with_address(&server.local_addr().to_string());
+        (server, options)
     }
 
     struct Mango;
@@ -304,31 +1403,394 @@ mod tests {
         }
     }
 
-    #[tokio::test]
-    async fn test_server_shared_object_call_method() {
-        let mut shared = SharedObjectDispatcher::new().await.unwrap();
-
-        shared
-            .register_object("mango", Box::new(Mango))
-            .await
-            .unwrap();
-        shared
-            .register_object("orange", Box::new(Orange))
-            .await
-            .unwrap();
+    /// Streams the numbers up to the given one, or without end.
+    #[derive(Default)]
+    struct Counter {
+        cancelled: Arc<Notify>,
+    }
 
-        shared
-            .register_object("apple", Box::new(Apple))
-            .await
+    #[async_trait]
+    impl SharedObject for Counter {
+        async fn remote_call(
+            &self,
+            method: &str,
+            _param: JsonElem,
+        ) -> Result<JsonElem, RemoteError> {
+            Err(RemoteError::from_code(
+                ErrorCode::MethodNotFound,
+                ErrorOrigin::Object,
+                method,
+            ))
+        }
+
+        async fn remote_stream(
+            &self,
+            _context: &CallContext,
+            method: &str,
+            param: JsonElem,
+            sender: StreamSender,
+        ) -> Result<(), RemoteError> {
+            let count = match (method, param) {
+                ("count" | "fail", JsonElem::Integer(count)) => count,
+                ("forever", _) => i32::MAX,
+                _ => {
+                    return Err(RemoteError::new(JsonElem::String(
+                        "a count is expected".into(),
+                    )))
+                }
+            };
+            for number in 0..count {
+                if let Err(err) = sender.send(JsonElem::Integer(number)).await {
+                    if sender.is_cancelled() {
+                        self.cancelled.notify_one();
+                    }
+                    return Err(err);
+                }
+            }
+            if method == "fail" {
+                return Err(RemoteError::new(JsonElem::String("out of numbers".into())));
+            }
+            Ok(())
+        }
+    }
+
+    struct Sleepy;
+
+    #[async_trait]
+    impl SharedObject for Sleepy {
+        async fn remote_call(
+            &self,
+            method: &str,
+            param: JsonElem,
+        ) -> Result<JsonElem, RemoteError> {
+            log::trace!("[Sleepy] Method: {} Param: {:?}", method, param);
+
+            if method == "slow" {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+            Ok(JsonElem::String(method.into()))
+        }
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Traces {
+        traces: Vec<Option<TraceContext>>,
+    }
+
+    /// Returns the trace context of the call, and for `forward`, also the
+    /// one of the call it makes to `trace_leaf`.
+    struct Tracer {
+        proxy: Option<Connector>,
+    }
+
+    #[async_trait]
+    impl SharedObject for Tracer {
+        async fn remote_call(
+            &self,
+            method: &str,
+            _param: JsonElem,
+        ) -> Result<JsonElem, RemoteError> {
+            let mut traces = vec![trace::current()];
+            if let (Some(proxy), "forward") = (&self.proxy, method) {
+                let leaf = proxy
+                    .remote_call("trace_leaf", "current", JsonElem::Null)
+                    .await?;
+                traces.extend(leaf.convert_to::<Traces>().unwrap().traces);
+            }
+            Ok(JsonElem::convert_from(&Traces { traces }).unwrap())
+        }
+    }
+
+    /// Returns results that look like errors.
+    struct Lookalike;
+
+    #[async_trait]
+    impl SharedObject for Lookalike {
+        async fn remote_call(
+            &self,
+            method: &str,
+            _param: JsonElem,
+        ) -> Result<JsonElem, RemoteError> {
+            match method {
+                "object" => {
+                    let mut result = HashMap::new();
+                    result.insert("error".to_string(), JsonElem::String("none".into()));
+                    Ok(JsonElem::HashMap(result))
+                }
+                "array" => Ok(JsonElem::Vec(vec![JsonElem::String("none".into())])),
+                _ => Err(RemoteError::new(JsonElem::String("failed".into()))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_status() {
+        let (server, options) = start().await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("lookalike", Box::new(Lookalike))
+            .await
+            .unwrap();
+        let process = shared.spawn().await;
+        wait_for_objects_with(options.clone(), vec!["lookalike".to_string()])
+            .await
+            .unwrap();
+
+        let proxy = Connector::connect_with(options.clone()).await.unwrap();
+        let mut expected = HashMap::new();
+        expected.insert("error".to_string(), JsonElem::String("none".into()));
+        assert_eq!(
+            proxy
+                .remote_call("lookalike", "object", JsonElem::Null)
+                .await
+                .unwrap(),
+            JsonElem::HashMap(expected)
+        );
+        assert_eq!(
+            proxy
+                .remote_call("lookalike", "array", JsonElem::Null)
+                .await
+                .unwrap(),
+            JsonElem::Vec(vec![JsonElem::String("none".into())])
+        );
+        assert_eq!(
+            proxy
+                .remote_call("lookalike", "fail", JsonElem::Null)
+                .await
+                .unwrap_err(),
+            RemoteError::new(JsonElem::String("failed".into()))
+        );
+        process.abort();
+        server.shutdown().await;
+    }
+
+    /// Refuses the callers that are not named `auditor`, and returns the
+    /// context of the call.
+    struct Audited;
+
+    #[async_trait]
+    impl SharedObject for Audited {
+        async fn remote_call(
+            &self,
+            _method: &str,
+            _param: JsonElem,
+        ) -> Result<JsonElem, RemoteError> {
+            unreachable!("called with a context")
+        }
+
+        async fn remote_call_with_context(
+            &self,
+            context: &CallContext,
+            _method: &str,
+            _param: JsonElem,
+        ) -> Result<JsonElem, RemoteError> {
+            let caller = context.caller.clone().unwrap_or_default();
+            if caller.name != "auditor" {
+                return Err(RemoteError::new(JsonElem::String("forbidden".into())));
+            }
+            let mut result = HashMap::new();
+            result.insert("name".to_string(), JsonElem::String(caller.name));
+            result.insert("pid".to_string(), JsonElem::Integer(caller.pid as i32));
+            result.insert(
+                "transaction".to_string(),
+                JsonElem::Integer(context.transaction as i32),
+            );
+            result.insert(
+                "remaining_ms".to_string(),
+                JsonElem::Integer(
+                    context
+                        .remaining()
+                        .map(|remaining| remaining.as_millis() as i32)
+                        .unwrap_or(-1),
+                ),
+            );
+            Ok(JsonElem::HashMap(result))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_call_context() {
+        let (server, options) = start().await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("audited", Box::new(Audited))
+            .await
+            .unwrap();
+        let process = shared.spawn().await;
+        wait_for_objects_with(options.clone(), vec!["audited".to_string()])
+            .await
+            .unwrap();
+
+        let proxy = Connector::connect_with(options.clone().with_name("auditor"))
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_secs(5));
+        let result = proxy
+            .remote_call("audited", "whoami", JsonElem::Null)
+            .await
+            .unwrap();
+        let JsonElem::HashMap(result) = result else {
+            panic!("unexpected result {:?}", result);
+        };
+        assert_eq!(result["name"], JsonElem::String("auditor".into()));
+        assert_eq!(result["pid"], JsonElem::Integer(std::process::id() as i32));
+        assert!(matches!(result["transaction"], JsonElem::Integer(id) if id > 0));
+        assert!(matches!(result["remaining_ms"], JsonElem::Integer(ms) if ms > 0 && ms <= 5000));
+
+        let proxy = Connector::connect_with(options.clone().with_name("stranger"))
+            .await
+            .unwrap();
+        assert_eq!(
+            proxy
+                .remote_call("audited", "whoami", JsonElem::Null)
+                .await
+                .unwrap_err(),
+            RemoteError::new(JsonElem::String("forbidden".into()))
+        );
+        process.abort();
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_trace_propagation() {
+        let (server, options) = start().await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("trace_leaf", Box::new(Tracer { proxy: None }))
+            .await
+            .unwrap();
+        shared
+            .register_object(
+                "trace_middle",
+                Box::new(Tracer {
+                    proxy: Some(Connector::connect_with(options.clone()).await.unwrap()),
+                }),
+            )
+            .await
+            .unwrap();
+        let process = shared.spawn().await;
+        wait_for_objects_with(
+            options.clone(),
+            vec!["trace_leaf".to_string(), "trace_middle".to_string()],
+        )
+        .await
+        .unwrap();
+
+        let proxy = Connector::connect_with(options.clone()).await.unwrap();
+        let root = TraceContext::new_root();
+        let traces = trace::scope(
+            root.clone(),
+            proxy.remote_call("trace_middle", "forward", JsonElem::Null),
+        )
+        .await
+        .unwrap()
+        .convert_to::<Traces>()
+        .unwrap()
+        .traces;
+
+        let middle = traces[0].clone().unwrap();
+        let leaf = traces[1].clone().unwrap();
+        assert_eq!(middle.trace_id, root.trace_id);
+        assert_eq!(middle.parent_span_id, Some(root.span_id));
+        assert_eq!(leaf.trace_id, root.trace_id);
+        assert_eq!(leaf.parent_span_id, Some(middle.span_id));
+
+        let traces = proxy
+            .remote_call("trace_leaf", "current", JsonElem::Null)
+            .await
+            .unwrap()
+            .convert_to::<Traces>()
+            .unwrap()
+            .traces;
+        let new_trace = traces[0].clone().unwrap();
+        assert_ne!(new_trace.trace_id, root.trace_id);
+        assert_eq!(new_trace.parent_span_id, None);
+        process.abort();
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_dispatch() {
+        let (server, options) = start().await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap()
+            .with_concurrency_limit(4);
+
+        shared
+            .register_object("sleepy", Box::new(Sleepy))
+            .await
+            .unwrap();
+        let process1 = shared.spawn().await;
+        wait_for_objects_with(options.clone(), vec!["sleepy".to_string()])
+            .await
+            .unwrap();
+
+        let inner_options = options.clone();
+        let slow = tokio::spawn(async move {
+            let proxy = Connector::connect_with(inner_options.clone())
+                .await
+                .unwrap();
+            proxy
+                .remote_call("sleepy", "slow", JsonElem::Null)
+                .await
+                .unwrap()
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        let proxy = Connector::connect_with(options.clone()).await.unwrap();
+        let fast = proxy
+            .remote_call("sleepy", "fast", JsonElem::Null)
+            .await
+            .unwrap();
+
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(fast, JsonElem::String("fast".into()));
+        assert_eq!(slow.await.unwrap(), JsonElem::String("slow".into()));
+        process1.abort();
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_server_shared_object_call_method() {
+        let (server, options) = start().await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+
+        shared
+            .register_object("mango", Box::new(Mango))
+            .await
+            .unwrap();
+        shared
+            .register_object("orange", Box::new(Orange))
+            .await
+            .unwrap();
+
+        shared
+            .register_object("apple", Box::new(Apple))
+            .await
             .unwrap();
 
         let process1 = shared.spawn().await;
 
         let process2_result = Arc::new(Mutex::new(JsonElem::String(String::new())));
         let process2_result2 = process2_result.clone();
+        let inner_options = options.clone();
         let process2 = tokio::spawn(async move {
-            wait_for_objects(vec!["mango".to_string()]).await.unwrap();
-            let proxy = Connector::connect().await.unwrap();
+            wait_for_objects_with(inner_options.clone(), vec!["mango".to_string()])
+                .await
+                .unwrap();
+            let proxy = Connector::connect_with(inner_options.clone())
+                .await
+                .unwrap();
 
             let mut param = HashMap::new();
             param.insert(
@@ -347,9 +1809,14 @@ mod tests {
 
         let process3_result = Arc::new(Mutex::new(JsonElem::String(String::new())));
         let process3_result3 = process3_result.clone();
+        let inner_options = options.clone();
         let process3 = tokio::spawn(async move {
-            wait_for_objects(vec!["mango".to_string()]).await.unwrap();
-            let proxy = Connector::connect().await.unwrap();
+            wait_for_objects_with(inner_options.clone(), vec!["mango".to_string()])
+                .await
+                .unwrap();
+            let proxy = Connector::connect_with(inner_options.clone())
+                .await
+                .unwrap();
 
             let result = proxy
                 .remote_call("mango", "login", JsonElem::Null)
@@ -362,9 +1829,14 @@ mod tests {
 
         let process4_result = Arc::new(Mutex::new(JsonElem::String(String::new())));
         let process4_result4 = process4_result.clone();
+        let inner_options = options.clone();
         let process4 = tokio::spawn(async move {
-            wait_for_objects(vec!["mango".to_string()]).await.unwrap();
-            let proxy = Connector::connect().await.unwrap();
+            wait_for_objects_with(inner_options.clone(), vec!["mango".to_string()])
+                .await
+                .unwrap();
+            let proxy = Connector::connect_with(inner_options.clone())
+                .await
+                .unwrap();
 
             let result = proxy
                 .remote_call("mango", "login", JsonElem::Null)
@@ -377,9 +1849,14 @@ mod tests {
 
         let process5_result = Arc::new(Mutex::new(JsonElem::String(String::new())));
         let process5_result5 = process5_result.clone();
+        let inner_options = options.clone();
         let process5 = tokio::spawn(async move {
-            wait_for_objects(vec!["orange".to_string()]).await.unwrap();
-            let proxy = Connector::connect().await.unwrap();
+            wait_for_objects_with(inner_options.clone(), vec!["orange".to_string()])
+                .await
+                .unwrap();
+            let proxy = Connector::connect_with(inner_options.clone())
+                .await
+                .unwrap();
 
             let result = proxy
                 .remote_call("orange", "login", JsonElem::Null)
@@ -392,9 +1869,14 @@ mod tests {
 
         let process6_result = Arc::new(Mutex::new(JsonElem::String(String::new())));
         let process6_result6 = process6_result.clone();
+        let inner_options = options.clone();
         let process6 = tokio::spawn(async move {
-            wait_for_objects(vec!["orange".to_string()]).await.unwrap();
-            let proxy = Connector::connect().await.unwrap();
+            wait_for_objects_with(inner_options.clone(), vec!["orange".to_string()])
+                .await
+                .unwrap();
+            let proxy = Connector::connect_with(inner_options.clone())
+                .await
+                .unwrap();
 
             let mut param = HashMap::new();
             param.insert(
@@ -415,9 +1897,14 @@ mod tests {
             String::new(),
         ))));
         let process7_result7 = process7_result.clone();
+        let inner_options = options.clone();
         let process7 = tokio::spawn(async move {
-            wait_for_objects(vec!["apple".to_string()]).await.unwrap();
-            let proxy = Connector::connect().await.unwrap();
+            wait_for_objects_with(inner_options.clone(), vec!["apple".to_string()])
+                .await
+                .unwrap();
+            let proxy = Connector::connect_with(inner_options.clone())
+                .await
+                .unwrap();
 
             let mut param = HashMap::new();
             param.insert(
@@ -472,14 +1959,19 @@ mod tests {
             *res7,
             RemoteError::new(JsonElem::String("exception happend".to_string()))
         );
+        server.shutdown().await;
     }
 
     #[tokio::test]
     async fn test_event() {
+        let (server, options) = start().await;
         let result = Arc::new(Mutex::new(JsonElem::Null));
         let inner = result.clone();
+        let inner_options = options.clone();
         let event_subscriber = tokio::spawn(async move {
-            let event_listener = EventListener::dispatch().await.unwrap();
+            let event_listener = EventListener::dispatch_with(inner_options.clone())
+                .await
+                .unwrap();
             event_listener
                 .listen("event", |param| async move {
                     log::info!("Event: {:?}", param);
@@ -493,8 +1985,11 @@ mod tests {
                 .unwrap();
         });
 
+        let inner_options = options.clone();
         let event_sender = tokio::spawn(async move {
-            let sender = Connector::connect().await.unwrap();
+            let sender = Connector::connect_with(inner_options.clone())
+                .await
+                .unwrap();
             sender
                 .send_event(
                     "event",
@@ -511,19 +2006,1245 @@ mod tests {
             *var,
             JsonElem::String("Sending you this event!!".to_string())
         );
+        server.shutdown().await;
     }
 
     #[tokio::test]
-    async fn test_no_shared_object_call_method() {
-        let sender = Connector::connect().await.unwrap();
-        let result = sender
-            .remote_call("no object", "login", JsonElem::Null)
+    async fn test_listen_skips_other_frames() {
+        use crate::{
+            message::{Event, Welcome},
+            socket::Socket,
+        };
+
+        // A server that answers the subscription with a refusal and a
+        // malformed event before the event.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            let socket = Socket::new(stream, addr);
+            let hello = socket.receive().await.unwrap();
+            socket
+                .send(
+                    &SocketMessage::new()
+                        .set_id(hello.id())
+                        .set_kind(MessageType::Welcome)
+                        .set_body(&Welcome::new("json").as_bytes()),
+                )
+                .await
+                .unwrap();
+            socket.receive().await.unwrap();
+            let event = Event {
+                event: "event".to_string(),
+                param: JsonElem::Integer(7),
+                ack: false,
+            };
+            let refusal = RemoteError::from(CommonErrors::ServerConnectionError);
+            for msg in [
+                SocketMessage::new()
+                    .set_kind(MessageType::RemoteCallResponse)
+                    .set_status(Some(ResponseStatus::Broker))
+                    .set_body(&serde_json::to_vec(&refusal).unwrap()),
+                SocketMessage::new()
+                    .set_kind(MessageType::SendEventRequest)
+                    .set_body(b"\"event\""),
+                SocketMessage::new()
+                    .set_kind(MessageType::SendEventRequest)
+                    .set_body(&event.as_bytes()),
+            ] {
+                socket.send(&msg).await.unwrap();
+            }
+            socket
+        });
+
+        let options = ClientOptions::new().with_address(&address.to_string());
+        let listener = EventListener::dispatch_with(options).await.unwrap();
+        let (events, mut received) = tokio::sync::mpsc::unbounded_channel();
+        listener
+            .listen("event", move |param| async move {
+                events.send(param).unwrap();
+                Ok::<(), RemoteError>(())
+            })
             .await
-            .unwrap_err();
+            .unwrap();
+        let param = tokio::time::timeout(Duration::from_secs(1), received.recv())
+            .await
+            .unwrap();
+        assert_eq!(param, Some(JsonElem::Integer(7)));
+        drop(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_event_with_ack() {
+        let (server, options) = start().await;
+        for _ in 0..2 {
+            let event_listener = EventListener::dispatch_with(options.clone()).await.unwrap();
+            // Subscribing again, such as on a retry, does not count twice.
+            for _ in 0..2 {
+                event_listener
+                    .listen("ack_event", |param| async move {
+                        log::info!("Event: {:?}", param);
+                        Ok::<(), RemoteError>(())
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let sender = Connector::connect_with(options.clone()).await.unwrap();
+        let report = sender
+            .send_event_with_ack("ack_event", JsonElem::Bool(true))
+            .await
+            .unwrap();
+        assert_eq!(report.delivered, 2);
+        assert!(report.failed.is_empty());
+
+        let report = sender
+            .send_event_with_ack("no_subscriber_event", JsonElem::Null)
+            .await
+            .unwrap();
+        assert_eq!(report.delivered, 0);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_legacy_client() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (server, options) = start().await;
+        wait_for_objects_with(options.clone(), vec!["list".to_string()])
+            .await
+            .unwrap();
+
+        let mut stream = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        let request = SocketMessage::new()
+            .set_kind(MessageType::WaitForObject)
+            .set_body("list".as_bytes());
+        stream
+            .write_all(&serde_json::to_vec(&request).unwrap())
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        let reply = serde_json::from_slice::<SocketMessage>(&buf[0..n]).unwrap();
+
+        assert_eq!(reply.kind(), MessageType::WaitForObject);
+        assert_eq!(reply.body(), SUCCESS.as_bytes());
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_list_connections() {
+        let (server, options) = start().await;
+        let mut shared =
+            SharedObjectDispatcher::new_with(options.clone().with_name("papaya-owner"))
+                .await
+                .unwrap();
+        shared
+            .register_object("papaya", Box::new(Mango))
+            .await
+            .unwrap();
+        shared.spawn().await;
+        let listener = EventListener::dispatch_with(options.clone().with_name("papaya-listener"))
+            .await
+            .unwrap();
+        listener
+            .listen("papaya_event", |_| async { Ok::<(), RemoteError>(()) })
+            .await
+            .unwrap();
+        wait_for_objects_with(options.clone(), vec!["papaya".to_string()])
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let caller = Connector::connect_with(options.with_name("papaya-caller"))
+            .await
+            .unwrap();
+        let result = caller
+            .remote_call("list", "listConnections", JsonElem::Null)
+            .await
+            .unwrap()
+            .convert_to::<serde_json::Value>()
+            .unwrap();
 
+        let connections = result["connections"].as_array().unwrap();
+        let find = |name: &str| {
+            connections
+                .iter()
+                .find(|connection| connection["name"] == name)
+                .unwrap()
+                .clone()
+        };
+        let owner = find("papaya-owner");
+        assert_eq!(owner["pid"], std::process::id());
+        assert_eq!(owner["objects"], serde_json::json!(["papaya"]));
         assert_eq!(
-            result,
-            RemoteError::new(JsonElem::String(CommonErrors::ObjectNotFound.to_string()))
+            find("papaya-listener")["subscriptions"],
+            serde_json::json!(["papaya_event"])
+        );
+        let caller = find("papaya-caller");
+        assert_eq!(caller["version"], env!("CARGO_PKG_VERSION"));
+        assert!(caller["connected_at"].is_string());
+        assert!(find("remote-call")["objects"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!("list")));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_introspection() {
+        let (server, options) = start().await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("dozy", Box::new(Sleepy))
+            .await
+            .unwrap();
+        shared.spawn().await;
+        let listener = EventListener::dispatch_with(options.clone()).await.unwrap();
+        listener
+            .listen("dozy_event", |_| async { Ok::<(), RemoteError>(()) })
+            .await
+            .unwrap();
+        wait_for_objects_with(options.clone(), vec!["dozy".to_string()])
+            .await
+            .unwrap();
+
+        let inner_options = options.clone();
+        let slow = tokio::spawn(async move {
+            let proxy = Connector::connect_with(inner_options.clone())
+                .await
+                .unwrap();
+            proxy
+                .remote_call("dozy", "slow", JsonElem::Null)
+                .await
+                .unwrap()
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let proxy = Connector::connect_with(options.clone()).await.unwrap();
+        let inspect = |method: &'static str, param: JsonElem| {
+            let proxy = proxy.clone();
+            async move {
+                proxy
+                    .remote_call("list", method, param)
+                    .await
+                    .unwrap()
+                    .convert_to::<serde_json::Value>()
+                    .unwrap()
+            }
+        };
+
+        let pending = inspect("pendingCalls", JsonElem::Null).await;
+        let call = pending["calls"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|call| call["object"] == "dozy")
+            .unwrap()
+            .clone();
+        assert_eq!(call["method"], "slow");
+        assert!(call["age_ms"].as_u64().unwrap() >= 50);
+
+        let events = inspect("listEvents", JsonElem::Null).await;
+        let event = events["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event["event"] == "dozy_event")
+            .unwrap()
+            .clone();
+        assert_eq!(event["subscribers"].as_array().unwrap().len(), 1);
+
+        slow.await.unwrap();
+        let info = inspect("objectInfo", JsonElem::String("dozy".into())).await;
+        assert_eq!(info["owner_pid"], std::process::id());
+        assert_eq!(info["calls"], 1);
+        assert_eq!(info["completed"], 1);
+        assert_eq!(info["errors"], 0);
+
+        let result = proxy
+            .remote_call("list", "objectInfo", JsonElem::String("no object".into()))
+            .await
+            .unwrap_err();
+        assert_eq!(result, RemoteError::from(CommonErrors::ObjectNotFound));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_describe() {
+        let (server, options) = start().await;
+        wait_for_objects_with(options.clone(), vec!["list".to_string()])
+            .await
+            .unwrap();
+        let proxy = Connector::connect_with(options.clone()).await.unwrap();
+
+        let description = proxy.describe("list").await.unwrap();
+        let methods: Vec<&str> = description
+            .methods
+            .iter()
+            .map(|method| method.name.as_str())
+            .collect();
+        assert_eq!(
+            methods,
+            vec![
+                "listObjects",
+                "listConnections",
+                "listEvents",
+                "objectInfo",
+                "pendingCalls"
+            ]
+        );
+        assert!(description
+            .to_string()
+            .contains(r#"objectInfo({"type":"string"}) -> {"type":"object"}"#));
+
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("lychee", Box::new(Mango))
+            .await
+            .unwrap();
+        shared.spawn().await;
+        wait_for_objects_with(options.clone(), vec!["lychee".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            proxy.describe("lychee").await.unwrap_err(),
+            RemoteError::from_code(
+                ErrorCode::MethodNotFound,
+                ErrorOrigin::Object,
+                "lychee does not describe its methods."
+            )
+        );
+
+        assert_eq!(
+            proxy.describe("no object").await.unwrap_err(),
+            RemoteError::from(CommonErrors::ObjectNotFound)
+        );
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (server, options) = start().await;
+        wait_for_objects_with(options.clone(), vec!["list".to_string()])
+            .await
+            .unwrap();
+        let proxy = Connector::connect_with(options.clone()).await.unwrap();
+        proxy
+            .remote_call("list", "listObjects", JsonElem::Null)
+            .await
+            .unwrap();
+        let _ = proxy
+            .remote_call("no object", "login", JsonElem::Null)
+            .await;
+
+        let mut stream = tokio::net::TcpStream::connect(server.metrics_addr().unwrap())
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(
+            response.contains("remote_call_calls_total{object=\"list\",method=\"listObjects\"}")
+        );
+        assert!(response.contains(
+            "remote_call_call_duration_seconds_count{object=\"list\",method=\"listObjects\"}"
+        ));
+        assert!(response.contains("remote_call_errors_total{kind=\"ObjectNotFound\"}"));
+        assert!(!response.contains("no object"));
+        assert!(response.contains("# TYPE remote_call_active_connections gauge"));
+        assert!(response.contains("remote_call_pending_transactions "));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_client() {
+        use crate::{
+            message::{Hello, Welcome},
+            socket::Socket,
+        };
+
+        let (server, _) = start().await;
+        let stream = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        let addr = stream.peer_addr().unwrap();
+        let socket = Socket::new(stream, addr);
+
+        let hello = Hello {
+            protocol: 1,
+            ..Hello::new(vec!["json".to_string()])
+        };
+        socket
+            .send(
+                &SocketMessage::new()
+                    .set_kind(MessageType::Hello)
+                    .set_body(&hello.as_bytes()),
+            )
+            .await
+            .unwrap();
+
+        let reply = socket.receive().await.unwrap();
+        assert_eq!(reply.kind(), MessageType::Welcome);
+        let welcome: Welcome = serde_json::from_slice(reply.body()).unwrap();
+        assert!(welcome.error.unwrap().contains("protocol version 1"));
+
+        // The server closes the connection after the rejection.
+        assert!(socket.receive().await.is_err());
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[tokio::test]
+    async fn test_frame_after_welcome() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{
+            codec::{Codec, JsonCodec, MsgPackCodec, MSGPACK},
+            message::{GoingAway, Welcome, PROTOCOL_VERSION},
+            socket::Socket,
+        };
+
+        // A server that starts draining right after the handshake, the
+        // notice in the same write as the welcome.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut hello = [0; 1024];
+            let _ = stream.read(&mut hello).await.unwrap();
+            let welcome = SocketMessage::new()
+                .set_kind(MessageType::Welcome)
+                .set_body(&Welcome::new(MSGPACK).as_bytes());
+            let notice = GoingAway {
+                reason: "Draining".to_string(),
+                drain_ms: 1000,
+            };
+            let notice = SocketMessage::new()
+                .set_kind(MessageType::GoingAway)
+                .set_body(&notice.as_bytes());
+            let mut data = JsonCodec.encode(&welcome, PROTOCOL_VERSION).unwrap();
+            data.extend(MsgPackCodec.encode(&notice, PROTOCOL_VERSION).unwrap());
+            stream.write_all(&data).await.unwrap();
+            stream
+        });
+
+        let options = ClientOptions::new().with_address(&address.to_string());
+        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let socket = Socket::connect_stream(stream, &options.with_codec(MSGPACK))
+            .await
+            .unwrap();
+        let notice = tokio::select! {
+            notice = socket.wait_going_away() => notice,
+            msg = socket.receive() => panic!("Unexpected {:?}", msg),
+        };
+        assert_eq!(notice.drain_ms, 1000);
+        drop(server.await.unwrap());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[tokio::test]
+    async fn test_codec_bridge() {
+        use crate::{codec::MSGPACK, message::CallMethod, socket::Socket};
+
+        let (server, options) = start().await;
+        // The object owner speaks JSON, the caller MessagePack.
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("kiwi", Box::new(Mango))
+            .await
+            .unwrap();
+        shared.spawn().await;
+        wait_for_objects_with(options.clone(), vec!["kiwi".to_string()])
+            .await
+            .unwrap();
+
+        let socket = Socket::connect(&options.clone().with_codec(MSGPACK))
+            .await
+            .unwrap();
+        assert_eq!(socket.codec().name(), MSGPACK);
+
+        let call_method = CallMethod {
+            object: "kiwi".to_string(),
+            method: "login".to_string(),
+            param: JsonElem::Null,
+        };
+        socket
+            .send(
+                &SocketMessage::new()
+                    .set_kind(MessageType::RemoteCallRequest)
+                    .set_body(&call_method.as_bytes()),
+            )
+            .await
+            .unwrap();
+        let reply = socket.receive().await.unwrap();
+
+        assert_eq!(reply.kind(), MessageType::RemoteCallResponse);
+        assert_eq!(
+            JsonElem::try_from(reply.body()).unwrap(),
+            JsonElem::String("This is my response from mango".into())
+        );
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_no_shared_object_call_method() {
+        let (server, options) = start().await;
+        let sender = Connector::connect_with(options.clone()).await.unwrap();
+        let result = sender
+            .remote_call("no object", "login", JsonElem::Null)
+            .await
+            .unwrap_err();
+
+        assert_eq!(result, RemoteError::from(CommonErrors::ObjectNotFound));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_invalid_call() {
+        use crate::{message::Hello, socket::Socket};
+
+        let (server, _) = start().await;
+        let stream = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        let addr = stream.peer_addr().unwrap();
+        let socket = Socket::new(stream, addr);
+        let hello = Hello::new(vec!["json".to_string()]);
+        for msg in [
+            SocketMessage::new()
+                .set_kind(MessageType::Hello)
+                .set_body(&hello.as_bytes()),
+            SocketMessage::new()
+                .set_kind(MessageType::RemoteCallRequest)
+                .set_body(br#"{"object":"mango"}"#),
+        ] {
+            socket.send(&msg).await.unwrap();
+        }
+        socket.receive().await.unwrap();
+
+        let reply = socket.receive().await.unwrap();
+        assert_eq!(reply.kind(), MessageType::RemoteCallResponse);
+        assert_eq!(reply.status(), Some(ResponseStatus::Broker));
+        let err = serde_json::from_slice::<RemoteError>(reply.body()).unwrap();
+        assert_eq!(err, RemoteError::from(CommonErrors::SerdeParseError));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_server_handle() {
+        let (server, options) = start().await;
+        assert_ne!(server.local_addr().port(), 0);
+
+        // A second server cannot take the same address.
+        let busy = ServerBuilder::new()
+            .with_address(&server.local_addr().to_string())
+            .start()
+            .await
+            .unwrap_err();
+        assert!(busy.to_string().contains("Cannot listen on"));
+
+        let proxy = Connector::connect_with(options.clone()).await.unwrap();
+        wait_for_objects_with(options.clone(), vec!["list".to_string()])
+            .await
+            .unwrap();
+        let address = server.local_addr();
+        tokio::time::timeout(Duration::from_secs(5), server.shutdown())
+            .await
+            .unwrap();
+
+        assert!(proxy
+            .remote_call("list", "listObjects", JsonElem::Null)
+            .await
+            .is_err());
+        assert!(tokio::net::TcpStream::connect(address).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let (server, options) = start().await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("drowsy", Box::new(Sleepy))
+            .await
+            .unwrap();
+        shared.spawn().await;
+        let listener = EventListener::dispatch_with(options.clone()).await.unwrap();
+        listener
+            .listen("drowsy_event", |_| async { Ok::<(), RemoteError>(()) })
+            .await
+            .unwrap();
+        wait_for_objects_with(options.clone(), vec!["drowsy".to_string()])
+            .await
+            .unwrap();
+        let late = Connector::connect_with(options.clone()).await.unwrap();
+        let idle = late.clone();
+        let watcher = tokio::spawn(async move { idle.wait_going_away().await });
+        // Waiting for the notice leaves the connection to the calls.
+        assert_eq!(
+            late.remote_call("drowsy", "fast", JsonElem::Null)
+                .await
+                .unwrap(),
+            JsonElem::String("fast".into())
+        );
+
+        let inner_options = options.clone();
+        let slow = tokio::spawn(async move {
+            let proxy = Connector::connect_with(inner_options).await.unwrap();
+            proxy.remote_call("drowsy", "slow", JsonElem::Null).await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let shutdown = tokio::spawn(server.shutdown());
+
+        // The clients are told, and the calls made from now on are refused.
+        let notice = tokio::time::timeout(Duration::from_secs(1), listener.going_away())
+            .await
+            .unwrap();
+        assert_eq!(notice.drain_ms, 1000);
+        let seen = tokio::time::timeout(Duration::from_secs(1), watcher)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(seen, notice);
+        let refused = late
+            .remote_call("drowsy", "fast", JsonElem::Null)
+            .await
+            .unwrap_err();
+        assert_eq!(refused.code, ErrorCode::ServerConnectionError);
+        assert_eq!(refused.origin, ErrorOrigin::Broker);
+        assert_eq!(late.going_away(), Some(notice));
+
+        // The call in flight is still answered before the server closes.
+        assert_eq!(
+            slow.await.unwrap().unwrap(),
+            JsonElem::String("slow".into())
+        );
+        tokio::time::timeout(Duration::from_secs(2), shutdown)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(late
+            .remote_call("drowsy", "fast", JsonElem::Null)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits() {
+        use crate::{message::CallMethod, socket::Socket};
+
+        // The connection of the built-in list object is not counted.
+        let (server, options) = start_with(Limits {
+            max_connections: Some(2),
+            max_frame_size: Some(1024),
+            max_pending_calls: Some(1),
+            ..Limits::default()
+        })
+        .await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("drowsy", Box::new(Sleepy))
+            .await
+            .unwrap();
+        shared.spawn().await;
+
+        let socket = Socket::connect(&options).await.unwrap();
+        let refused = Connector::connect_with(options.clone()).await.unwrap_err();
+        assert!(refused.error.to_string().contains("Too many connections"));
+
+        let call = |method: &str, param: JsonElem| {
+            let call_method = CallMethod {
+                object: "drowsy".to_string(),
+                method: method.to_string(),
+                param,
+            };
+            SocketMessage::new()
+                .set_kind(MessageType::RemoteCallRequest)
+                .set_body(&call_method.as_bytes())
+        };
+        let limit_exceeded = |reply: SocketMessage| {
+            assert_eq!(reply.kind(), MessageType::RemoteCallResponse);
+            let err: RemoteError = serde_json::from_slice(reply.body()).unwrap();
+            assert_eq!(err.code, ErrorCode::LimitExceeded);
+            assert_eq!(err.origin, ErrorOrigin::Broker);
+        };
+
+        // The second call is refused while the first one is pending.
+        socket.send(&call("slow", JsonElem::Null)).await.unwrap();
+        socket.send(&call("slow", JsonElem::Null)).await.unwrap();
+        limit_exceeded(socket.receive().await.unwrap());
+        let reply = socket.receive().await.unwrap();
+        assert_eq!(
+            JsonElem::try_from(reply.body()).unwrap(),
+            JsonElem::String("slow".into())
+        );
+
+        // A frame too large is answered, then the peer is disconnected.
+        let param = JsonElem::String("x".repeat(2000));
+        socket.send(&call("fast", param)).await.unwrap();
+        limit_exceeded(socket.receive().await.unwrap());
+        assert!(socket.receive().await.is_err());
+
+        // Its connection is given back.
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let proxy = loop {
+            match Connector::connect_with(options.clone()).await {
+                Ok(proxy) => break proxy,
+                Err(err) if Instant::now() > deadline => panic!("{:?}", err),
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+        assert_eq!(
+            proxy
+                .remote_call("drowsy", "fast", JsonElem::Null)
+                .await
+                .unwrap(),
+            JsonElem::String("fast".into())
+        );
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let (server, options) = start_with(Limits {
+            rate_limit: Some(RateLimit { rate: 1, burst: 2 }),
+            ..Limits::default()
+        })
+        .await;
+        let sender = Connector::connect_with(options.clone()).await.unwrap();
+        let call = || sender.remote_call("no object", "login", JsonElem::Null);
+
+        for _ in 0..2 {
+            assert_eq!(call().await.unwrap_err().code, ErrorCode::ObjectNotFound);
+        }
+        // Refused past the burst, then disconnected after as many refusals.
+        for _ in 0..3 {
+            assert_eq!(call().await.unwrap_err().code, ErrorCode::LimitExceeded);
+        }
+        assert_eq!(
+            call().await.unwrap_err().code,
+            ErrorCode::ClientConnectionError
+        );
+
+        // Other connections have their own limit.
+        let other = Connector::connect_with(options.clone()).await.unwrap();
+        assert_eq!(
+            other
+                .remote_call("no object", "login", JsonElem::Null)
+                .await
+                .unwrap_err()
+                .code,
+            ErrorCode::ObjectNotFound
+        );
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        use crate::{message::Hello, socket::Socket};
+
+        let heartbeat = Heartbeat {
+            interval: Duration::from_millis(50),
+            misses: 3,
+        };
+        let (server, options) = serve(ServerBuilder::new().with_heartbeat(Some(heartbeat))).await;
+        let pinging = Connector::connect_with(options.clone().with_heartbeat(Some(heartbeat)))
+            .await
+            .unwrap();
+        let quiet = Connector::connect_with(options.clone().with_heartbeat(None))
+            .await
+            .unwrap();
+
+        // An owner that announces the heartbeat, then freezes.
+        let stream = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        let addr = stream.peer_addr().unwrap();
+        let frozen = Socket::new(stream, addr);
+        let hello = Hello::new(vec!["json".to_string()]);
+        for msg in [
+            SocketMessage::new()
+                .set_kind(MessageType::Hello)
+                .set_body(&hello.as_bytes()),
+            SocketMessage::new()
+                .set_kind(MessageType::AddShareObjectRequest)
+                .set_body("frozen".as_bytes()),
+        ] {
+            frozen.send(&msg).await.unwrap();
+            frozen.receive().await.unwrap();
+        }
+
+        // It is dropped with its object, and the call it left is answered.
+        let err = tokio::time::timeout(
+            Duration::from_secs(1),
+            pinging.remote_call("frozen", "login", JsonElem::Null),
+        )
+        .await
+        .unwrap()
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::RemoteConnectionError);
+
+        // The idle clients are kept.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        for connector in [&pinging, &quiet] {
+            let err = connector
+                .remote_call("frozen", "login", JsonElem::Null)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code, ErrorCode::ObjectNotFound);
+        }
+        drop(frozen);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_message() {
+        use crate::{message::Hello, socket::Socket};
+
+        let (server, options) = start().await;
+        let stream = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        let addr = stream.peer_addr().unwrap();
+        let rogue = Socket::new(stream, addr);
+        let hello = Hello::new(vec!["json".to_string()]);
+        for msg in [
+            SocketMessage::new()
+                .set_kind(MessageType::Hello)
+                .set_body(&hello.as_bytes()),
+            SocketMessage::new()
+                .set_kind(MessageType::AddShareObjectRequest)
+                .set_body("rogue".as_bytes()),
+        ] {
+            rogue.send(&msg).await.unwrap();
+            rogue.receive().await.unwrap();
+        }
+
+        // A kind only the server sends ends the connection, with its cleanup.
+        rogue
+            .send(&SocketMessage::new().set_kind(MessageType::Welcome))
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), rogue.receive())
+            .await
+            .unwrap()
+            .unwrap_err();
+
+        let proxy = Connector::connect_with(options).await.unwrap();
+        let err = proxy
+            .remote_call("rogue", "login", JsonElem::Null)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::ObjectNotFound);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_foreign_responses() {
+        use crate::{message::Hello, socket::Socket};
+
+        let (server, options) = start().await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("dozy", Box::new(Sleepy))
+            .await
+            .unwrap();
+        shared
+            .register_object("counter", Box::new(Counter::default()))
+            .await
+            .unwrap();
+        shared.spawn().await;
+        wait_for_objects_with(options.clone(), vec!["dozy".to_string()])
+            .await
+            .unwrap();
+
+        let proxy = Connector::connect_with(options.clone()).await.unwrap();
+        let slow =
+            tokio::spawn(async move { proxy.remote_call("dozy", "slow", JsonElem::Null).await });
+        let streamer = Connector::connect_with(options.clone()).await.unwrap();
+        let mut stream = streamer
+            .remote_stream("counter", "forever", JsonElem::Null)
+            .await
+            .unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), JsonElem::Integer(0));
+
+        // A peer that owns neither object answers every transaction id.
+        let tcp = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        let addr = tcp.peer_addr().unwrap();
+        let rogue = Socket::new(tcp, addr);
+        let hello = SocketMessage::new()
+            .set_kind(MessageType::Hello)
+            .set_body(&Hello::new(vec!["json".to_string()]).as_bytes());
+        rogue.send(&hello).await.unwrap();
+        rogue.receive().await.unwrap();
+        let forged = JsonElem::String("forged".into());
+        let body: Vec<u8> = forged.try_into().unwrap();
+        for id in 0..100 {
+            for kind in [
+                MessageType::StreamChunk,
+                MessageType::StreamEnd,
+                MessageType::RemoteCallResponse,
+            ] {
+                let msg = SocketMessage::new()
+                    .set_id(id)
+                    .set_kind(kind)
+                    .set_body(&body)
+                    .set_status(Some(ResponseStatus::Ok));
+                rogue.send(&msg).await.unwrap();
+            }
+        }
+
+        assert_eq!(
+            slow.await.unwrap().unwrap(),
+            JsonElem::String("slow".into())
+        );
+        for number in 1..40 {
+            assert_eq!(
+                stream.next().await.unwrap().unwrap(),
+                JsonElem::Integer(number)
+            );
+        }
+        drop(stream);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_describe_legacy_owner() {
+        use crate::{message::Hello, socket::Socket};
+
+        let (server, options) = start().await;
+        let tcp = tokio::net::TcpStream::connect(server.local_addr())
+            .await
+            .unwrap();
+        let addr = tcp.peer_addr().unwrap();
+        let relic = Socket::new(tcp, addr);
+        let mut hello = Hello::new(vec!["json".to_string()]);
+        hello.features.clear();
+        for msg in [
+            SocketMessage::new()
+                .set_kind(MessageType::Hello)
+                .set_body(&hello.as_bytes()),
+            SocketMessage::new()
+                .set_kind(MessageType::AddShareObjectRequest)
+                .set_body("relic".as_bytes()),
+        ] {
+            relic.send(&msg).await.unwrap();
+            relic.receive().await.unwrap();
+        }
+
+        // The broker answers for an owner that would not.
+        let proxy = Connector::connect_with(options).await.unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(1), proxy.describe("relic"))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::MethodNotFound);
+        assert_eq!(err.origin, ErrorOrigin::Broker);
+        drop(relic);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_acl_keeps_list() {
+        use crate::{
+            config::{AclAction, AclPolicy},
+            message::CallMethod,
+            socket::Socket,
+        };
+
+        // Only 127.0.0.2 may connect, the server's own connection still does.
+        let (server, options) = serve(ServerBuilder::new().with_acl(AclPolicy {
+            default: AclAction::Deny,
+            allow: vec!["127.0.0.2".parse().unwrap()],
+            deny: Vec::new(),
+        }))
+        .await;
+        let refused = Connector::connect_with(options.clone()).await;
+        assert!(refused.is_err());
+
+        let tcp = tokio::net::TcpSocket::new_v4().unwrap();
+        tcp.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let tcp = tcp.connect(server.local_addr()).await.unwrap();
+        let socket = Socket::connect_stream(tcp, &options).await.unwrap();
+        let call = CallMethod {
+            object: "list".to_string(),
+            method: "listObjects".to_string(),
+            param: JsonElem::Null,
+        };
+        socket
+            .send(
+                &SocketMessage::new()
+                    .set_kind(MessageType::RemoteCallRequest)
+                    .set_body(&call.as_bytes()),
+            )
+            .await
+            .unwrap();
+        let reply = socket.receive().await.unwrap();
+        assert_eq!(reply.status(), Some(ResponseStatus::Ok));
+        assert!(String::from_utf8_lossy(reply.body()).contains("list"));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_call() {
+        let (server, options) = start().await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("dozy", Box::new(Sleepy))
+            .await
+            .unwrap();
+        shared.spawn().await;
+        wait_for_objects_with(options.clone(), vec!["dozy".to_string()])
+            .await
+            .unwrap();
+
+        // The response of the call given up is not taken by the next one.
+        let proxy = Connector::connect_with(options).await.unwrap();
+        let slow = proxy.remote_call("dozy", "slow", JsonElem::Null);
+        tokio::time::timeout(Duration::from_millis(100), slow)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            proxy
+                .remote_call("dozy", "fast", JsonElem::Null)
+                .await
+                .unwrap(),
+            JsonElem::String("fast".into())
+        );
+        assert_eq!(
+            proxy.describe("dozy").await.unwrap_err().code,
+            ErrorCode::MethodNotFound
+        );
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dead_server() {
+        use crate::{message::Welcome, socket::Socket};
+
+        // A server that answers the handshake, then hangs.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut hung = Vec::new();
+            while let Ok((stream, addr)) = listener.accept().await {
+                let socket = Socket::new(stream, addr);
+                let hello = socket.receive().await.unwrap();
+                let welcome = SocketMessage::new()
+                    .set_id(hello.id())
+                    .set_kind(MessageType::Welcome)
+                    .set_body(&Welcome::new("json").as_bytes());
+                socket.send(&welcome).await.unwrap();
+                hung.push(socket);
+            }
+        });
+
+        let options = ClientOptions::default()
+            .with_address(&address.to_string())
+            .with_codec("json")
+            .with_heartbeat(Some(Heartbeat {
+                interval: Duration::from_millis(50),
+                misses: 3,
+            }));
+        let proxy = Connector::connect_with(options.clone()).await.unwrap();
+        let err = tokio::time::timeout(
+            Duration::from_secs(1),
+            proxy.remote_call("mango", "login", JsonElem::Null),
+        )
+        .await
+        .unwrap()
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::ClientConnectionError);
+
+        let listener = EventListener::dispatch_with(options).await.unwrap();
+        listener
+            .listen("mango_event", |_| async { Ok::<(), RemoteError>(()) })
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), listener.closed())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stuck_subscriber() {
+        let (server, options) = serve(ServerBuilder::new().with_queue(QueueConfig {
+            capacity: 4,
+            overflow: OverflowPolicy::DropOldest,
+        }))
+        .await;
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("mango", Box::new(Mango))
+            .await
+            .unwrap();
+        shared.spawn().await;
+
+        // A subscriber that never returns from its callback stops reading.
+        let stuck = EventListener::dispatch_with(options.clone()).await.unwrap();
+        stuck
+            .listen("flood", |_| {
+                std::future::pending::<Result<(), RemoteError>>()
+            })
+            .await
+            .unwrap();
+        let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let healthy = EventListener::dispatch_with(options.clone()).await.unwrap();
+        healthy
+            .listen("flood", move |param| {
+                let sender = sender.clone();
+                async move {
+                    let _ = sender.send(param);
+                    Ok::<(), RemoteError>(())
+                }
+            })
+            .await
+            .unwrap();
+        wait_for_objects_with(options.clone(), vec!["mango".to_string()])
+            .await
+            .unwrap();
+
+        // Enough to fill the socket buffers and the queue of the stuck one.
+        let proxy = Connector::connect_with(options.clone()).await.unwrap();
+        let big = JsonElem::String("x".repeat(64 * 1024));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            for _ in 0..100 {
+                let report = proxy
+                    .send_event_with_ack("flood", big.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(report.delivered, 2);
+                assert_eq!(
+                    proxy
+                        .remote_call("mango", "login", JsonElem::Null)
+                        .await
+                        .unwrap(),
+                    JsonElem::String("This is my response from mango".into())
+                );
+            }
+            proxy
+                .send_event("flood", JsonElem::String("last".into()))
+                .await
+                .unwrap();
+            while received.recv().await.unwrap() != JsonElem::String("last".into()) {}
+        })
+        .await
+        .unwrap();
+        drop(stuck);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_remote_stream() {
+        let (server, options) = start().await;
+        let counter = Counter::default();
+        let cancelled = counter.cancelled.clone();
+        let mut shared = SharedObjectDispatcher::new_with(options.clone())
+            .await
+            .unwrap();
+        shared
+            .register_object("counter", Box::new(counter))
+            .await
+            .unwrap();
+        shared
+            .register_object("mango", Box::new(Mango))
+            .await
+            .unwrap();
+        shared.spawn().await;
+        wait_for_objects_with(options.clone(), vec!["counter".to_string()])
+            .await
+            .unwrap();
+        let proxy = Connector::connect_with(options.clone())
+            .await
+            .unwrap()
+            .with_stream_window(4);
+
+        // More chunks than the window, so the credit has to come back.
+        let mut stream = proxy
+            .remote_stream("counter", "count", JsonElem::Integer(10))
+            .await
+            .unwrap();
+        let mut numbers = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            numbers.push(chunk.unwrap());
+        }
+        assert_eq!(numbers, (0..10).map(JsonElem::Integer).collect::<Vec<_>>());
+
+        // The error ends the stream, after the chunks sent before it.
+        let mut stream = proxy
+            .remote_stream("counter", "fail", JsonElem::Integer(2))
+            .await
+            .unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), JsonElem::Integer(0));
+        assert_eq!(stream.next().await.unwrap().unwrap(), JsonElem::Integer(1));
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(err.error, JsonElem::String("out of numbers".into()));
+        assert!(stream.next().await.is_none());
+
+        for (object, code) in [
+            ("mango", ErrorCode::MethodNotFound),
+            ("no object", ErrorCode::ObjectNotFound),
+        ] {
+            let mut stream = proxy
+                .remote_stream(object, "count", JsonElem::Integer(1))
+                .await
+                .unwrap();
+            assert_eq!(stream.next().await.unwrap().unwrap_err().code, code);
+            assert!(stream.next().await.is_none());
+        }
+
+        // Dropping the stream cancels it, and the connection is reused.
+        let mut stream = proxy
+            .remote_stream("counter", "forever", JsonElem::Null)
+            .await
+            .unwrap();
+        for number in 0..3 {
+            assert_eq!(
+                stream.next().await.unwrap().unwrap(),
+                JsonElem::Integer(number)
+            );
+        }
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(1), cancelled.notified())
+            .await
+            .unwrap();
+        assert_eq!(
+            proxy
+                .remote_call("mango", "login", JsonElem::Null)
+                .await
+                .unwrap(),
+            JsonElem::String("This is my response from mango".into())
         );
+        server.shutdown().await;
     }
 }
@@ -1,45 +1,167 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use async_trait::async_trait;
+use dashmap::DashMap;
 use json_elem::JsonElem;
-use tokio::{net::TcpStream, sync::Mutex, task::JoinHandle};
+use tokio::{
+    sync::{Mutex, Semaphore},
+    task::JoinHandle,
+};
 
 use crate::{
-    error::{CommonErrors, Error, RemoteError},
-    message::{CallMethod, MessageType, SocketMessage},
+    error::{CommonErrors, Error, ErrorCode, ErrorOrigin, RemoteError},
+    message::{
+        CallMethod, Caller, GoingAway, MessageType, ObjectDescription, ResponseStatus,
+        SocketMessage, StreamCall, StreamCredit,
+    },
     objects::FAILED,
-    socket::{Socket, ENV_SERVER_ADDRESS, SERVER_ADDRESS},
-    util,
+    options::ClientOptions,
+    socket::Socket,
+    stream::StreamSender,
+    trace::{self, TraceContext},
 };
 
 #[async_trait]
 pub trait SharedObject: Send + Sync + 'static {
+    /// Executes a method called by another process. The trace context of
+    /// the call is available from [`trace::current`], and the remote calls
+    /// made from here are traced as its children.
     async fn remote_call(&self, method: &str, param: JsonElem) -> Result<JsonElem, RemoteError>;
+
+    /// Executes a method called by another process, knowing who calls it.
+    /// Objects that audit or authorize their callers, or that honor the
+    /// deadline of the call, implement this one. It calls
+    /// [`SharedObject::remote_call`] by default.
+    async fn remote_call_with_context(
+        &self,
+        context: &CallContext,
+        method: &str,
+        param: JsonElem,
+    ) -> Result<JsonElem, RemoteError> {
+        let _ = context;
+        self.remote_call(method, param).await
+    }
+
+    /// Executes a method that streams its result, called by another process
+    /// with [`Connector::remote_stream`]. The chunks are sent with the
+    /// sender, which waits while the caller is behind and fails once the
+    /// caller has cancelled. The stream ends when this returns, with the
+    /// error if there is one. Objects that do not stream keep the default,
+    /// which fails with `MethodNotFound`.
+    ///
+    /// [`Connector::remote_stream`]: crate::Connector::remote_stream
+    async fn remote_stream(
+        &self,
+        context: &CallContext,
+        method: &str,
+        param: JsonElem,
+        sender: StreamSender,
+    ) -> Result<(), RemoteError> {
+        let _ = (context, param, sender);
+        Err(RemoteError::from_code(
+            ErrorCode::MethodNotFound,
+            ErrorOrigin::Object,
+            format!("{} does not stream.", method),
+        ))
+    }
+
+    /// Describes the methods of the object, so callers can discover them.
+    /// Objects that do not describe themselves return `None`.
+    fn describe(&self) -> Option<ObjectDescription> {
+        None
+    }
+}
+
+/// What a shared object knows about the remote call it executes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CallContext {
+    /// The connection that made the call. Only legacy servers leave it out.
+    pub caller: Option<Caller>,
+    /// The transaction id the server gave to the call.
+    pub transaction: u64,
+    /// When the caller stops waiting for the response.
+    pub deadline: Option<SystemTime>,
+    pub trace: Option<TraceContext>,
 }
 
-type ListSharedObjects = Arc<Mutex<HashMap<String, Box<dyn SharedObject>>>>;
+impl CallContext {
+    fn from_request(msg: &SocketMessage) -> Self {
+        Self {
+            caller: msg.caller().cloned(),
+            transaction: msg.id(),
+            deadline: msg
+                .deadline()
+                .map(|deadline| UNIX_EPOCH + Duration::from_millis(deadline)),
+            trace: msg.trace().cloned(),
+        }
+    }
+
+    /// The time left until the deadline, zero once it has passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Whether the caller has stopped waiting for the response.
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+}
+
+/// The default number of remote method calls a dispatcher executes at the same time.
+pub const CONCURRENCY_LIMIT: usize = 128;
+
+type ListSharedObjects = Arc<Mutex<HashMap<String, Arc<dyn SharedObject>>>>;
+/// The streams being sent, by transaction id.
+type ListStreams = Arc<DashMap<u64, StreamSender>>;
 /// An object that is responsible in registering the object to the IPC server,
 /// and spawning a tokio task to handling incoming remote method calls from
 /// other processes.
 pub struct SharedObjectDispatcher {
     socket: Socket,
     list: ListSharedObjects,
+    concurrency_limit: usize,
 }
 
 impl SharedObjectDispatcher {
     /// Create a new ObjectDispatcher object and connects to the IPC server.
     pub async fn new() -> Result<Self, RemoteError> {
-        let server_address = std::env::var(ENV_SERVER_ADDRESS).unwrap_or(SERVER_ADDRESS.to_owned());
-        let stream = TcpStream::connect(server_address)
+        Self::new_with(ClientOptions::default()).await
+    }
+
+    /// Create a new ObjectDispatcher object and connects to the IPC server
+    /// with the given client name, address and codec.
+    pub async fn new_with(options: ClientOptions) -> Result<Self, RemoteError> {
+        let socket = Socket::connect(&options)
             .await
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+            .map_err(RemoteError::client)?;
+
+        Ok(Self::from_socket(socket))
+    }
 
-        let addr = stream.peer_addr().unwrap();
-        Ok(Self {
-            socket: Socket::new(stream, addr),
+    /// Creates a dispatcher on a connection that has done its handshake.
+    pub(crate) fn from_socket(socket: Socket) -> Self {
+        Self {
+            socket,
             list: Arc::new(Mutex::new(HashMap::new())),
-        })
+            concurrency_limit: CONCURRENCY_LIMIT,
+        }
     }
+
+    /// Sets how many remote method calls are executed at the same time.
+    /// Further calls wait until one of the running calls completes.
+    pub fn with_concurrency_limit(mut self, limit: usize) -> Self {
+        self.concurrency_limit = limit.max(1);
+        self
+    }
+
     /// This registers the Shared Object into the IPC server.
     pub async fn register_object(
         &mut self,
@@ -48,26 +170,15 @@ impl SharedObjectDispatcher {
     ) -> Result<(), RemoteError> {
         let mut list = self.list.lock().await;
 
-        list.insert(object.to_string(), shared_object);
+        list.insert(object.to_string(), Arc::from(shared_object));
 
         let msg = SocketMessage::new()
             .set_kind(MessageType::AddShareObjectRequest)
             .set_body(object.as_bytes());
 
-        self.socket
-            .write(&msg.as_bytes())
-            .await
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+        self.socket.send(&msg).await.map_err(RemoteError::client)?;
 
-        let mut buf = Vec::new();
-        let n = self
-            .socket
-            .read(&mut buf)
-            .await
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
-
-        let msg = serde_json::from_slice::<SocketMessage>(&buf[0..n])
-            .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+        let msg = self.socket.receive().await.map_err(RemoteError::client)?;
 
         if msg.kind() == MessageType::AddShareObjectResponse {
             if msg.body() == FAILED.as_bytes() {
@@ -79,56 +190,190 @@ impl SharedObjectDispatcher {
         Ok(())
     }
 
+    /// Completes once the server has announced that it is going away, so
+    /// that the objects can be registered again on a new connection. The
+    /// notice is read by the task that [`SharedObjectDispatcher::spawn`]
+    /// spawns, the calls in flight are still answered.
+    pub async fn going_away(&self) -> GoingAway {
+        self.socket.wait_going_away().await
+    }
+
     /// This handles remote object method call from other processess.
     /// It spawns a tokio task to handle the calls asynchronously and sends
-    /// back the response back to the remote process.
+    /// back the response back to the remote process. Each call runs on its
+    /// own tokio task, so a slow method does not block the other objects.
     pub async fn spawn(&mut self) -> JoinHandle<Result<(), Error>> {
         let socket = self.socket.clone();
         let list = self.list.clone();
+        let limit = Arc::new(Semaphore::new(self.concurrency_limit));
+        let streams = ListStreams::default();
 
         tokio::spawn(async move {
             loop {
-                let mut buf = Vec::new();
-                socket.read(&mut buf).await?;
-
-                let sep = util::separate(buf.as_slice());
-                for data in sep {
-                    if let Ok(msg) = serde_json::from_slice::<SocketMessage>(data.as_slice()) {
+                match socket.receive().await {
+                    Ok(msg) => {
                         if msg.kind() == MessageType::RemoteCallRequest {
-                            Self::handle_remote_call_request(list.clone(), msg, socket.clone())
-                                .await?;
+                            let permit = limit
+                                .clone()
+                                .acquire_owned()
+                                .await
+                                .map_err(|e| Error::Others(e.to_string()))?;
+                            let list = list.clone();
+                            let socket = socket.clone();
+                            tokio::spawn(async move {
+                                if let Err(err) =
+                                    Self::handle_remote_call_request(list, msg, socket).await
+                                {
+                                    log::error!("handle_remote_call_request: {}", err);
+                                }
+                                drop(permit);
+                            });
+                        } else if msg.kind() == MessageType::StreamRequest {
+                            let permit = limit
+                                .clone()
+                                .acquire_owned()
+                                .await
+                                .map_err(|e| Error::Others(e.to_string()))?;
+                            let list = list.clone();
+                            let socket = socket.clone();
+                            let streams = streams.clone();
+                            tokio::spawn(async move {
+                                if let Err(err) =
+                                    Self::handle_stream_request(list, streams, msg, socket).await
+                                {
+                                    log::error!("handle_stream_request: {}", err);
+                                }
+                                drop(permit);
+                            });
+                        } else if msg.kind() == MessageType::StreamCredit {
+                            let credit = serde_json::from_slice::<StreamCredit>(msg.body());
+                            if let (Some(sender), Ok(credit)) = (streams.get(&msg.id()), credit) {
+                                sender.grant(credit.credit);
+                            }
+                        } else if msg.kind() == MessageType::StreamCancel {
+                            if let Some(sender) = streams.get(&msg.id()) {
+                                sender.cancel();
+                            }
+                        } else if msg.kind() == MessageType::DescribeRequest {
+                            let msg = Self::handle_describe_request(&list, msg).await;
+                            socket.send(&msg).await?;
                         }
-                    } else {
-                        log::error!("Invalid stream");
+                    }
+                    Err(Error::Serde(err)) => {
+                        log::error!("Invalid stream: {}", err);
                         let mut msg = SocketMessage::new();
-                        let err = RemoteError::new(JsonElem::String(
-                            CommonErrors::SerdeParseError.to_string(),
-                        ));
+                        let err = RemoteError::from(CommonErrors::SerdeParseError)
+                            .with_origin(ErrorOrigin::Object);
                         msg = msg
                             .set_body(&err.as_bytes())
-                            .set_kind(MessageType::RemoteCallResponse);
+                            .set_kind(MessageType::RemoteCallResponse)
+                            .set_status(Some(ResponseStatus::Error));
 
-                        socket.write(&msg.as_bytes()).await?;
+                        socket.send(&msg).await?;
+                    }
+                    Err(err) => {
+                        for sender in streams.iter() {
+                            sender.cancel();
+                        }
+                        return Err(err);
                     }
                 }
             }
         })
     }
 
+    async fn handle_describe_request(
+        list: &ListSharedObjects,
+        msg: SocketMessage,
+    ) -> SocketMessage {
+        let object = String::from_utf8_lossy(msg.body()).to_string();
+        let shared_object = list.lock().await.get(&object).cloned();
+        let body = match shared_object.map(|shared_object| shared_object.describe()) {
+            Some(Some(description)) => description.as_bytes(),
+            Some(None) => RemoteError::from_code(
+                ErrorCode::MethodNotFound,
+                ErrorOrigin::Object,
+                format!("{} does not describe its methods.", object),
+            )
+            .as_bytes(),
+            None => RemoteError::from(CommonErrors::ObjectNotFound)
+                .with_origin(ErrorOrigin::Object)
+                .as_bytes(),
+        };
+        msg.set_body(&body).set_kind(MessageType::DescribeResponse)
+    }
+
+    async fn handle_stream_request(
+        list: ListSharedObjects,
+        streams: ListStreams,
+        msg: SocketMessage,
+        socket: Socket,
+    ) -> Result<(), Error> {
+        let context = CallContext::from_request(&msg);
+        let id = msg.id();
+        let result = match serde_json::from_slice::<StreamCall>(msg.body()) {
+            Ok(call) => match list.lock().await.get(&call.object).cloned() {
+                Some(object) => {
+                    let sender = StreamSender::new(id, socket.clone(), call.window);
+                    streams.insert(id, sender.clone());
+                    let trace = context.trace.clone().unwrap_or_else(TraceContext::new_root);
+                    let result = object.remote_stream(&context, &call.method, call.param, sender);
+                    let result = trace::scope(trace, result).await;
+                    streams.remove(&id);
+                    result
+                }
+                None => Err(RemoteError::from(CommonErrors::ObjectNotFound)
+                    .with_origin(ErrorOrigin::Object)),
+            },
+            Err(_) => {
+                Err(RemoteError::from(CommonErrors::SerdeParseError)
+                    .with_origin(ErrorOrigin::Object))
+            }
+        };
+
+        let msg = SocketMessage::new()
+            .set_id(id)
+            .set_kind(MessageType::StreamEnd);
+        let msg = match result {
+            Ok(()) => msg.set_status(Some(ResponseStatus::Ok)),
+            Err(err) => msg
+                .set_body(&err.as_bytes())
+                .set_status(Some(ResponseStatus::Error)),
+        };
+        socket.send(&msg).await?;
+        Ok(())
+    }
+
     async fn handle_remote_call_request(
         list: ListSharedObjects,
         mut msg: SocketMessage,
         socket: Socket,
     ) -> Result<(), Error> {
-        let val = list.lock().await;
+        let context = CallContext::from_request(&msg);
+        msg = msg.set_caller(None).set_deadline(None);
         if let Ok(call) = serde_json::from_slice::<CallMethod>(msg.body()) {
-            let msg = if let Some(rem_call) = val.get(&call.object) {
-                match rem_call.remote_call(&call.method, call.param).await {
+            let object = list.lock().await.get(&call.object).cloned();
+            let msg = if let Some(rem_call) = object {
+                let trace = context.trace.clone().unwrap_or_else(TraceContext::new_root);
+                #[cfg(feature = "tracing")]
+                let span = tracing::info_span!(
+                    "shared_object",
+                    object = %call.object,
+                    method = %call.method,
+                    trace_id = %trace.trace_id,
+                    span_id = %trace.span_id,
+                    parent_span_id = trace.parent_span_id.as_deref(),
+                );
+                let result = rem_call.remote_call_with_context(&context, &call.method, call.param);
+                #[cfg(feature = "tracing")]
+                let result = tracing::Instrument::instrument(result, span);
+                match trace::scope(trace, result).await {
                     Ok(response) => {
                         let body: Vec<u8> = response.try_into()?;
                         msg = msg
                             .set_body(&body)
-                            .set_kind(MessageType::RemoteCallResponse);
+                            .set_kind(MessageType::RemoteCallResponse)
+                            .set_status(Some(ResponseStatus::Ok));
                         msg
                     }
                     Err(err) => {
@@ -136,26 +381,30 @@ impl SharedObjectDispatcher {
                         let body: Vec<u8> = response.try_into()?;
                         msg = msg
                             .set_body(&body)
-                            .set_kind(MessageType::RemoteCallResponse);
+                            .set_kind(MessageType::RemoteCallResponse)
+                            .set_status(Some(ResponseStatus::Error));
                         msg
                     }
                 }
             } else {
-                let err =
-                    RemoteError::new(JsonElem::String(CommonErrors::ObjectNotFound.to_string()));
+                let err = RemoteError::from(CommonErrors::ObjectNotFound)
+                    .with_origin(ErrorOrigin::Object);
                 msg = msg
                     .set_body(&err.as_bytes())
-                    .set_kind(MessageType::RemoteCallResponse);
+                    .set_kind(MessageType::RemoteCallResponse)
+                    .set_status(Some(ResponseStatus::Error));
                 msg
             };
-            socket.write(&msg.as_bytes()).await?;
+            socket.send(&msg).await?;
         } else {
-            let err = RemoteError::new(JsonElem::String(CommonErrors::SerdeParseError.to_string()));
+            let err =
+                RemoteError::from(CommonErrors::SerdeParseError).with_origin(ErrorOrigin::Object);
             msg = msg
                 .set_body(&err.as_bytes())
-                .set_kind(MessageType::RemoteCallResponse);
+                .set_kind(MessageType::RemoteCallResponse)
+                .set_status(Some(ResponseStatus::Error));
 
-            socket.write(&msg.as_bytes()).await?;
+            socket.send(&msg).await?;
         }
         Ok(())
     }
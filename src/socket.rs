@@ -1,4 +1,12 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, Interest},
@@ -6,80 +14,425 @@ use tokio::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpStream,
     },
-    sync::Mutex,
+    sync::{watch, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    codec::{self, Codec, FrameScan, JsonCodec},
+    error::Error,
+    message::{
+        GoingAway, Hello, MessageType, SocketMessage, Welcome, HEARTBEAT, LEGACY_PROTOCOL_VERSION,
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    options::{ClientOptions, Heartbeat},
 };
 
 pub const CHUNK_SIZE: usize = 4096;
+/// The largest frame the server reads from a peer by default, in bytes.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
 pub const ENV_SERVER_ADDRESS: &str = "ENV_SERVER_ADDRESS";
 pub const SERVER_ADDRESS: &str = "127.0.0.1:1986";
 
+#[derive(Debug)]
+struct Reader {
+    read: OwnedReadHalf,
+    buffer: Vec<u8>,
+    scan: FrameScan,
+    frames: VecDeque<SocketMessage>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Socket {
-    read: Arc<Mutex<OwnedReadHalf>>,
+    read: Arc<Mutex<Reader>>,
     write: Arc<Mutex<OwnedWriteHalf>>,
     ip_address: SocketAddr,
+    peer_version: Arc<AtomicU8>,
+    codec: Arc<RwLock<Arc<dyn Codec>>>,
+    /// Whether the codec is settled. Until then only the first frame of
+    /// what is read is decoded, the frames after the handshake may be in
+    /// the codec it negotiates.
+    negotiated: Arc<AtomicBool>,
+    going_away: Arc<watch::Sender<Option<GoingAway>>>,
+    max_frame_size: Option<usize>,
+    /// When a frame last came from the peer, and how long the peer may
+    /// stay silent once the heartbeat is on.
+    last_seen: Arc<std::sync::Mutex<Instant>>,
+    dead_after: Arc<RwLock<Option<Duration>>>,
+    closed: Arc<watch::Sender<bool>>,
+    /// The features the server announced in the handshake.
+    features: Arc<RwLock<Vec<String>>>,
 }
 
 impl Socket {
     pub fn new(socket: TcpStream, ip_address: SocketAddr) -> Self {
+        // Messages are small and latency bound, do not let Nagle's algorithm delay them.
+        if let Err(err) = socket.set_nodelay(true) {
+            log::warn!("Socket::new: {}", err);
+        }
         let (read, write) = socket.into_split();
         Self {
-            read: Arc::new(Mutex::new(read)),
+            read: Arc::new(Mutex::new(Reader {
+                read,
+                buffer: Vec::new(),
+                scan: FrameScan::default(),
+                frames: VecDeque::new(),
+            })),
             write: Arc::new(Mutex::new(write)),
             ip_address,
+            peer_version: Arc::new(AtomicU8::new(LEGACY_PROTOCOL_VERSION)),
+            codec: Arc::new(RwLock::new(Arc::new(JsonCodec))),
+            negotiated: Arc::new(AtomicBool::new(false)),
+            going_away: Arc::new(watch::channel(None).0),
+            max_frame_size: None,
+            last_seen: Arc::new(std::sync::Mutex::new(Instant::now())),
+            dead_after: Arc::new(RwLock::new(None)),
+            closed: Arc::new(watch::channel(false).0),
+            features: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    /// Limits the size of the frames read from the peer. A frame larger than
+    /// the limit fails the read with [`Error::FrameTooLarge`] as soon as
+    /// that many bytes are buffered, instead of being read whole.
+    pub fn with_max_frame_size(mut self, limit: Option<usize>) -> Self {
+        self.max_frame_size = limit;
+        self
+    }
+
+    /// Connects to the IPC server and opens the connection with the handshake,
+    /// announcing the client and asking for its codec. A server that cannot
+    /// serve this client rejects it with the reason.
+    pub async fn connect(options: &ClientOptions) -> Result<Self, Error> {
+        let stream = TcpStream::connect(options.address.as_str()).await?;
+        Self::connect_stream(stream, options).await
+    }
+
+    /// Opens the handshake on a stream already connected to the server.
+    pub async fn connect_stream(stream: TcpStream, options: &ClientOptions) -> Result<Self, Error> {
+        let addr = stream.peer_addr()?;
+        let socket = Self::new(stream, addr);
+
+        let mut codecs = vec![options.codec.clone()];
+        codecs.extend(
+            codec::supported()
+                .into_iter()
+                .filter(|name| *name != options.codec)
+                .map(String::from),
+        );
+        let mut hello = Hello {
+            name: options.name.clone(),
+            ..Hello::new(codecs)
+        };
+        if options.heartbeat.is_none() {
+            hello.features.retain(|feature| feature != HEARTBEAT);
+        }
+        let hello = SocketMessage::new()
+            .set_kind(MessageType::Hello)
+            .set_body(&hello.as_bytes());
+        socket.send(&hello).await?;
+
+        let reply = socket.receive().await.map_err(|err| {
+            Error::Others(format!("The server did not answer the handshake: {}", err))
+        })?;
+        if reply.kind() != MessageType::Welcome {
+            return Err(Error::Others(format!(
+                "Unexpected handshake reply: {:?}",
+                reply.kind()
+            )));
+        }
+        let welcome: Welcome = serde_json::from_slice(reply.body())?;
+        if let Some(error) = welcome.error {
+            return Err(Error::Others(format!("Rejected by the server: {}", error)));
+        }
+        if welcome.protocol < MIN_PROTOCOL_VERSION {
+            return Err(Error::Others(format!(
+                "Incompatible server {}: protocol version {}, at least {} is required",
+                welcome.version, welcome.protocol, MIN_PROTOCOL_VERSION
+            )));
+        }
+        let codec = codec::by_name(&welcome.codec)
+            .ok_or_else(|| Error::Others(format!("Unknown codec: {}", welcome.codec)))?;
+        socket.set_codec(codec);
+        *socket.features.write().unwrap() = welcome.features;
+        if let Some(heartbeat) = options.heartbeat {
+            if socket.supports(HEARTBEAT) {
+                socket.set_heartbeat(&heartbeat);
+                socket.spawn_pinger(heartbeat.interval);
+            }
         }
+        Ok(socket)
+    }
+
+    /// Whether the server announced the feature in the handshake.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features
+            .read()
+            .unwrap()
+            .iter()
+            .any(|supported| supported == feature)
     }
 
-    pub async fn read(&self, data: &mut Vec<u8>) -> Result<usize, std::io::Error> {
-        let mut read = self.read.lock().await;
+    /// Takes the peer for dead when nothing came from it for the misses of
+    /// the heartbeat. The peer is expected to ping, or to answer the pings.
+    pub fn set_heartbeat(&self, heartbeat: &Heartbeat) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+        *self.dead_after.write().unwrap() = Some(heartbeat.dead_after());
+    }
+
+    /// Pings the peer every interval, until the socket is dropped.
+    fn spawn_pinger(&self, interval: Duration) {
+        let ping = SocketMessage::new().set_kind(MessageType::Ping);
+        let frame = match self.codec().encode(&ping, self.protocol_version()) {
+            Ok(frame) => frame,
+            Err(err) => {
+                log::warn!("[{}] Heartbeat: {}", self.ip_address, err);
+                return;
+            }
+        };
+        let write = Arc::downgrade(&self.write);
+        let ip_address = self.ip_address;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let Some(write) = write.upgrade() else {
+                    break;
+                };
+                if let Err(err) = write_all(&write, &frame).await {
+                    log::debug!("[{}] Heartbeat: {}", ip_address, err);
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Answers a ping. The answer is written by a task of its own, so that
+    /// a receive that is cancelled never leaves half a frame behind.
+    fn pong(&self, id: u64) {
+        let socket = self.clone();
+        tokio::spawn(async move {
+            let pong = SocketMessage::new().set_id(id).set_kind(MessageType::Pong);
+            if let Err(err) = socket.send(&pong).await {
+                log::debug!("[{}] Pong: {}", socket.ip_address, err);
+            }
+        });
+    }
+
+    /// Receives the next message from the peer. Frames that arrive together
+    /// are kept for the next calls, and a frame split across reads is
+    /// completed by the following reads. A going-away notice is kept aside,
+    /// see [`Socket::going_away`], and the pings are answered here. With the
+    /// heartbeat on, a peer that stays silent for too long fails the read.
+    pub async fn receive(&self) -> Result<SocketMessage, Error> {
+        let mut reader = self.read.lock().await;
+        let reader = &mut *reader;
 
         loop {
-            let mut buffer = [0u8; CHUNK_SIZE];
-            if read.ready(Interest::READABLE).await.is_err() {
-                tokio::task::yield_now().await;
+            if let Some(msg) = reader.frames.pop_front() {
+                if msg.kind() == MessageType::GoingAway {
+                    let notice = serde_json::from_slice::<GoingAway>(msg.body())?;
+                    log::info!(
+                        "[{}] The server is going away: {}",
+                        self.ip_address,
+                        notice.reason
+                    );
+                    self.going_away.send_replace(Some(notice));
+                    continue;
+                }
+                match msg.kind() {
+                    MessageType::Ping => self.pong(msg.id()),
+                    MessageType::Pong => {}
+                    _ => return Ok(msg),
+                }
                 continue;
             }
-            match read.read(&mut buffer).await {
-                Ok(bytes_read) => {
-                    if bytes_read == 0 {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::ConnectionReset,
-                            "The connection was reset by the remote server.",
-                        ));
-                    }
-                    data.extend_from_slice(&buffer[0..bytes_read]);
 
-                    if bytes_read < CHUNK_SIZE {
-                        return Ok(data.len());
-                    }
+            if self.decode_buffered(reader)? {
+                continue;
+            }
+
+            reader.buffer.reserve(CHUNK_SIZE);
+            let read = async {
+                if reader.read.ready(Interest::READABLE).await.is_err() {
+                    tokio::task::yield_now().await;
+                    return None;
                 }
-                Err(e) => {
-                    return Err(e);
+                Some(reader.read.read_buf(&mut reader.buffer).await)
+            };
+            let dead_after = *self.dead_after.read().unwrap();
+            let read = match dead_after {
+                Some(dead_after) => {
+                    let deadline = *self.last_seen.lock().unwrap() + dead_after;
+                    match tokio::time::timeout_at(deadline, read).await {
+                        Ok(read) => read,
+                        Err(_) => {
+                            return Err(self.lost(std::io::Error::new(
+                                std::io::ErrorKind::TimedOut,
+                                format!(
+                                    "No heartbeat from {} for {:?}",
+                                    self.ip_address, dead_after
+                                ),
+                            )))
+                        }
+                    }
                 }
+                None => read.await,
+            };
+            let Some(read) = read else {
+                continue;
+            };
+            let bytes_read = read.map_err(|err| self.lost(err))?;
+            if bytes_read == 0 {
+                return Err(self.lost(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "The connection was reset by the remote server.",
+                )));
             }
+            *self.last_seen.lock().unwrap() = Instant::now();
         }
     }
 
-    pub async fn write(&self, data: &[u8]) -> Result<(), std::io::Error> {
-        let mut write = self.write.lock().await;
+    /// Decodes the complete frames buffered so far, telling whether there
+    /// were any. Before the codec is negotiated only the first one is.
+    fn decode_buffered(&self, reader: &mut Reader) -> Result<bool, Error> {
+        let codec = self.codec();
+        let limit = self.max_frame_size.unwrap_or(usize::MAX);
+        if !codec.has_frame(&reader.buffer, &mut reader.scan) {
+            if reader.buffer.len() > limit {
+                reader.buffer = Vec::new();
+                reader.scan = FrameScan::default();
+                return Err(Error::FrameTooLarge(limit));
+            }
+            return Ok(false);
+        }
+        let end = if self.negotiated.load(Ordering::Acquire) {
+            reader.buffer.len()
+        } else {
+            reader.scan.frame_end()
+        };
+        reader.scan = FrameScan::default();
+        let (decoded, consumed) = match codec.decode(&reader.buffer[..end]) {
+            Ok(ret) => ret,
+            Err(err) => {
+                reader.buffer.clear();
+                return Err(err);
+            }
+        };
+        reader.buffer.drain(..consumed);
+        if reader.buffer.len() > limit {
+            reader.buffer = Vec::new();
+            return Err(Error::FrameTooLarge(limit));
+        }
 
-        loop {
-            let ret = write.ready(Interest::WRITABLE).await;
+        let mut error = None;
+        for ret in decoded {
             match ret {
-                Ok(_) => {
-                    let ret = write.write_all(data).await;
-                    let _ = write.flush().await;
-                    return ret;
+                Ok((msg, _)) if msg.body().len() > limit => {
+                    error = Some(Error::FrameTooLarge(limit))
                 }
-                Err(_) => {
-                    tokio::task::yield_now().await;
-                    continue;
+                Ok((msg, version)) => {
+                    self.peer_version.store(version, Ordering::Relaxed);
+                    reader.frames.push_back(msg);
                 }
+                Err(err) => error = Some(err),
             }
         }
+        match error {
+            Some(err) => Err(err),
+            None => Ok(consumed > 0),
+        }
+    }
+
+    /// Sends the message to the peer in the highest protocol version both sides speak.
+    /// The handshake messages are always sent as JSON, whatever the codec.
+    pub async fn send(&self, msg: &SocketMessage) -> Result<(), std::io::Error> {
+        let data = match msg.kind() {
+            MessageType::Hello | MessageType::Welcome => msg.encode(self.protocol_version()),
+            _ => self
+                .codec()
+                .encode(msg, self.protocol_version())
+                .map_err(|err| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+                })?,
+        };
+        self.write(&data).await
+    }
+
+    pub async fn write(&self, data: &[u8]) -> Result<(), std::io::Error> {
+        write_all(&self.write, data).await
+    }
+
+    /// Marks the connection as lost, see [`Socket::closed`].
+    fn lost(&self, err: std::io::Error) -> Error {
+        self.closed.send_replace(true);
+        Error::IO(err)
+    }
+
+    /// Completes once a read has found the connection lost, closed by the
+    /// peer or silent past the heartbeat.
+    pub async fn closed(&self) {
+        let mut closed = self.closed.subscribe();
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+
+    /// The protocol version used when sending to the peer.
+    pub fn protocol_version(&self) -> u8 {
+        self.peer_version
+            .load(Ordering::Relaxed)
+            .min(PROTOCOL_VERSION)
+    }
+
+    /// The codec used for the messages exchanged with the peer.
+    pub fn codec(&self) -> Arc<dyn Codec> {
+        self.codec.read().unwrap().clone()
+    }
+
+    pub fn set_codec(&self, codec: Arc<dyn Codec>) {
+        *self.codec.write().unwrap() = codec;
+        self.negotiated.store(true, Ordering::Release);
     }
 
     pub fn ip_address(&self) -> String {
         self.ip_address.to_string()
     }
+
+    /// The going-away notice of the server, once it has been received.
+    pub fn going_away(&self) -> Option<GoingAway> {
+        self.going_away.borrow().clone()
+    }
+
+    /// Completes once the server has announced that it is going away. The
+    /// notice is only seen while the connection is being read.
+    pub async fn wait_going_away(&self) -> GoingAway {
+        let mut going_away = self.going_away.subscribe();
+        let notice = match going_away.wait_for(Option::is_some).await {
+            Ok(notice) => notice.clone(),
+            Err(_) => None,
+        };
+        match notice {
+            Some(notice) => notice,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+async fn write_all(write: &Mutex<OwnedWriteHalf>, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut write = write.lock().await;
+
+    loop {
+        let ret = write.ready(Interest::WRITABLE).await;
+        match ret {
+            Ok(_) => {
+                let ret = write.write_all(data).await;
+                let _ = write.flush().await;
+                return ret;
+            }
+            Err(_) => {
+                tokio::task::yield_now().await;
+                continue;
+            }
+        }
+    }
 }
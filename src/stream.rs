@@ -0,0 +1,193 @@
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use json_elem::JsonElem;
+use tokio::sync::{mpsc, OwnedMutexGuard, Semaphore};
+
+use crate::{
+    connector::invalid_response,
+    error::{ErrorCode, ErrorOrigin, RemoteError},
+    message::{MessageType, ResponseStatus, SocketMessage, StreamCredit},
+    socket::Socket,
+};
+
+/// The chunks a caller lets the owner send ahead of the ones it has taken,
+/// unless the connector sets another window.
+pub const STREAM_WINDOW: u32 = 16;
+
+/// Sends the chunks of a streaming call to its caller, see
+/// [`SharedObject::remote_stream`].
+///
+/// [`SharedObject::remote_stream`]: crate::SharedObject::remote_stream
+#[derive(Clone, Debug)]
+pub struct StreamSender {
+    id: u64,
+    socket: Socket,
+    window: u32,
+    credit: Arc<Semaphore>,
+}
+
+impl StreamSender {
+    pub(crate) fn new(id: u64, socket: Socket, window: u32) -> Self {
+        Self {
+            id,
+            socket,
+            window,
+            credit: Arc::new(Semaphore::new(window as usize)),
+        }
+    }
+
+    /// Sends a chunk, waiting until the caller is ready for it. It fails with
+    /// [`ErrorCode::Cancelled`] once the caller has cancelled the stream.
+    pub async fn send(&self, chunk: JsonElem) -> Result<(), RemoteError> {
+        let permit = self.credit.acquire().await.map_err(|_| {
+            RemoteError::from_code(
+                ErrorCode::Cancelled,
+                ErrorOrigin::Object,
+                "The caller cancelled the stream.",
+            )
+        })?;
+        permit.forget();
+
+        let body: Vec<u8> = chunk.try_into().map_err(|err: json_elem::error::Error| {
+            RemoteError::from_code(ErrorCode::SerdeParseError, ErrorOrigin::Object, err)
+        })?;
+        let msg = SocketMessage::new()
+            .set_id(self.id)
+            .set_kind(MessageType::StreamChunk)
+            .set_body(&body);
+        self.socket.send(&msg).await.map_err(|err| {
+            RemoteError::from_code(ErrorCode::ServerConnectionError, ErrorOrigin::Object, err)
+        })
+    }
+
+    /// Whether the caller has cancelled the stream, or has gone.
+    pub fn is_cancelled(&self) -> bool {
+        self.credit.is_closed()
+    }
+
+    /// Lets more chunks be sent, never more than a window ahead.
+    pub(crate) fn grant(&self, credit: u32) {
+        let room = (self.window as usize).saturating_sub(self.credit.available_permits());
+        self.credit.add_permits((credit as usize).min(room));
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.credit.close();
+    }
+}
+
+/// The chunks of a streaming call, see [`Connector::remote_stream`]. It
+/// ends after the last chunk, or with the error the stream ended with.
+/// Dropping it before the end cancels the stream.
+///
+/// [`Connector::remote_stream`]: crate::Connector::remote_stream
+#[derive(Debug)]
+pub struct RemoteStream {
+    chunks: mpsc::Receiver<Result<JsonElem, RemoteError>>,
+}
+
+impl RemoteStream {
+    /// Reads the stream on its own task, holding the connector until the
+    /// stream has ended.
+    pub(crate) fn spawn(socket: Socket, window: u32, busy: OwnedMutexGuard<usize>) -> Self {
+        let (sender, chunks) = mpsc::channel(window.max(1) as usize);
+        tokio::spawn(async move {
+            receive(socket, window, sender).await;
+            drop(busy);
+        });
+        Self { chunks }
+    }
+
+    /// The next chunk, `None` once the stream has ended.
+    pub async fn next(&mut self) -> Option<Result<JsonElem, RemoteError>> {
+        self.chunks.recv().await
+    }
+}
+
+impl futures_core::Stream for RemoteStream {
+    type Item = Result<JsonElem, RemoteError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.chunks.poll_recv(cx)
+    }
+}
+
+/// Reads the stream until its end, handing the chunks over and giving the
+/// credit back as they are taken. Once the stream is dropped, it is
+/// cancelled and the chunks still coming are discarded, so that the
+/// connection is left clean for the next call.
+async fn receive(socket: Socket, window: u32, chunks: mpsc::Sender<Result<JsonElem, RemoteError>>) {
+    let mut id = None;
+    let mut taken = 0;
+    let mut cancelled = false;
+
+    loop {
+        let res = tokio::select! {
+            res = socket.receive() => res,
+            _ = chunks.closed(), if !cancelled => {
+                cancelled = true;
+                if let Some(id) = id {
+                    control(&socket, id, MessageType::StreamCancel, None).await;
+                }
+                continue;
+            }
+        };
+        let msg = match res {
+            Ok(msg) => msg,
+            Err(err) => {
+                let _ = chunks.send(Err(RemoteError::client(err))).await;
+                return;
+            }
+        };
+        match msg.kind() {
+            MessageType::StreamChunk if cancelled => {
+                // The stream was dropped before its id was known.
+                if id.replace(msg.id()).is_none() {
+                    control(&socket, msg.id(), MessageType::StreamCancel, None).await;
+                }
+            }
+            MessageType::StreamChunk => {
+                id = Some(msg.id());
+                let chunk = JsonElem::try_from(msg.body()).map_err(invalid_response);
+                if chunks.send(chunk).await.is_err() {
+                    cancelled = true;
+                    control(&socket, msg.id(), MessageType::StreamCancel, None).await;
+                    continue;
+                }
+                taken += 1;
+                if taken >= (window / 2).max(1) {
+                    let credit = StreamCredit { credit: taken };
+                    control(&socket, msg.id(), MessageType::StreamCredit, Some(credit)).await;
+                    taken = 0;
+                }
+            }
+            MessageType::StreamEnd => {
+                if msg.status() != Some(ResponseStatus::Ok) && !cancelled {
+                    let err = serde_json::from_slice::<RemoteError>(msg.body())
+                        .unwrap_or_else(invalid_response);
+                    let _ = chunks.send(Err(err)).await;
+                }
+                return;
+            }
+            kind => {
+                let err = invalid_response(format!("Unexpected {:?} in a stream", kind));
+                let _ = chunks.send(Err(err)).await;
+                return;
+            }
+        }
+    }
+}
+
+async fn control(socket: &Socket, id: u64, kind: MessageType, credit: Option<StreamCredit>) {
+    let mut msg = SocketMessage::new().set_id(id).set_kind(kind);
+    if let Some(credit) = credit {
+        msg = msg.set_body(&credit.as_bytes());
+    }
+    if let Err(err) = socket.send(&msg).await {
+        log::debug!("[{}] {:?}: {}", socket.ip_address(), kind, err);
+    }
+}
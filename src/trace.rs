@@ -0,0 +1,102 @@
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// Identifies a remote call within the trace it belongs to, so that the
+/// hops of a call chain across processes can be correlated.
+///
+/// The ids are lowercase hex, a 32 digit trace id and 16 digit span ids,
+/// as in the W3C trace context.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_span_id: Option<String>,
+}
+
+impl TraceContext {
+    /// Starts a new trace.
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: format!("{:016x}{:016x}", random_id(), random_id()),
+            span_id: format!("{:016x}", random_id()),
+            parent_span_id: None,
+        }
+    }
+
+    /// A span of the same trace, started from this one.
+    pub fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            span_id: format!("{:016x}", random_id()),
+            parent_span_id: Some(self.span_id.clone()),
+        }
+    }
+
+    /// The context of an outgoing call: a child of the call being served,
+    /// or a new trace when there is none.
+    pub fn outgoing() -> Self {
+        current()
+            .map(|parent| parent.child())
+            .unwrap_or_else(Self::new_root)
+    }
+}
+
+/// The trace context of the remote call the current task is serving.
+pub fn current() -> Option<TraceContext> {
+    CURRENT.try_with(|trace| trace.clone()).ok()
+}
+
+/// Runs the future with the given trace context. The remote calls it makes
+/// become children of that context.
+pub async fn scope<F: Future>(trace: TraceContext, f: F) -> F::Output {
+    CURRENT.scope(trace, f).await
+}
+
+fn random_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u32(std::process::id());
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_nanos())
+            .unwrap_or_default(),
+    );
+    hasher.finish().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current, scope, TraceContext};
+
+    #[tokio::test]
+    async fn trace_scope() {
+        assert_eq!(current(), None);
+
+        let root = TraceContext::new_root();
+        assert_eq!(root.trace_id.len(), 32);
+        assert_eq!(root.span_id.len(), 16);
+        assert_eq!(root.parent_span_id, None);
+
+        let outgoing = scope(root.clone(), async { TraceContext::outgoing() }).await;
+        assert_eq!(outgoing.trace_id, root.trace_id);
+        assert_eq!(outgoing.parent_span_id, Some(root.span_id.clone()));
+        assert_ne!(outgoing.span_id, root.span_id);
+
+        assert_ne!(TraceContext::outgoing().trace_id, root.trace_id);
+    }
+}
@@ -1,28 +1,25 @@
-pub fn separate(data: &[u8]) -> Vec<Vec<u8>> {
-    let json_str = String::from_utf8(data.to_vec()).unwrap();
+use serde_json::value::RawValue;
 
-    if !json_str.contains("}{") {
-        return vec![data.to_vec()];
-    }
-    let parts: Vec<String> = json_str
-        .trim()
-        .split("}{")
-        .map(|part| part.to_string() + "}")
-        .collect();
-
-    let num_parts = parts.len();
-    let mut ret = Vec::new();
-
-    for (n, mut part) in parts.into_iter().enumerate() {
-        if n >= 1 {
-            part = "{".to_string() + &part;
-        }
-        if n == (num_parts - 1) {
-            part.pop();
+/// Splits a stream into its complete JSON frames. It returns the frames and
+/// the number of bytes they span; the bytes after that belong to a frame
+/// that has not fully arrived yet.
+pub fn separate(data: &[u8]) -> Result<(Vec<&[u8]>, usize), serde_json::Error> {
+    let mut stream = serde_json::Deserializer::from_slice(data).into_iter::<&RawValue>();
+    let mut frames = Vec::new();
+    let mut consumed = 0;
+
+    loop {
+        match stream.next() {
+            Some(Ok(frame)) => {
+                frames.push(frame.get().as_bytes());
+                consumed = stream.byte_offset();
+            }
+            Some(Err(err)) if err.is_eof() => break,
+            Some(Err(err)) => return Err(err),
+            None => break,
         }
-        ret.push(part.as_bytes().to_vec());
     }
-    ret
+    Ok((frames, consumed))
 }
 
 #[cfg(test)]
@@ -32,27 +29,47 @@ mod test {
     #[test]
     fn test_separate() {
         let json_str = r#"{"id":5,"kind":3,"msg":[34,84,104,105,115,32,105,115,32,109,121,32,114,101,115,112,111,110,115,101,32,102,114,111,109,32,109,97,110,103,111,34]}{"id":6,"kind":3,"msg":[34,84,104,105,115,32,105,115,32,109,121,32,114,101,115,112,111,110,115,101,32,102,114,111,109,32,109,97,110,103,111,34]}{"id":8,"kind":3,"msg":[34,84,104,105,115,32,105,115,32,109,121,32,114,101,115,112,111,110,115,101,32,102,114,111,109,32,109,97,110,103,111,34]}"#.as_bytes();
-        let ret = util::separate(json_str);
+        let (ret, consumed) = util::separate(json_str).unwrap();
 
         assert_eq!(ret.len(), 3);
+        assert_eq!(consumed, json_str.len());
         for data in ret {
-            let _msg = serde_json::from_slice::<SocketMessage>(data.as_slice()).unwrap();
+            let _msg = serde_json::from_slice::<SocketMessage>(data).unwrap();
         }
 
         let json_str = r#"{"id":5,"kind":3,"msg":[34,84,104,105,115,32,105,115,32,109,121,32,114,101,115,112,111,110,115,101,32,102,114,111,109,32,109,97,110,103,111,34]}"#.as_bytes();
-        let ret = util::separate(json_str);
+        let (ret, consumed) = util::separate(json_str).unwrap();
 
         assert_eq!(ret.len(), 1);
+        assert_eq!(consumed, json_str.len());
         for data in ret {
-            let _msg = serde_json::from_slice::<SocketMessage>(data.as_slice()).unwrap();
+            let _msg = serde_json::from_slice::<SocketMessage>(data).unwrap();
         }
 
         let json_str = r#"{"id":5,"kind":3,"msg":[34,84,104,105,115,32,105,115,32,109,121,32,114,101,115,112,111,110,115,101,32,102,114,111,109,32,109,97,110,103,111,34]}{"id":8,"kind":3,"msg":[34,84,104,105,115,32,105,115,32,109,121,32,114,101,115,112,111,110,115,101,32,102,114,111,109,32,109,97,110,103,111,34]}"#.as_bytes();
-        let ret = util::separate(json_str);
+        let (ret, consumed) = util::separate(json_str).unwrap();
 
         assert_eq!(ret.len(), 2);
+        assert_eq!(consumed, json_str.len());
         for data in ret {
-            let _msg = serde_json::from_slice::<SocketMessage>(data.as_slice()).unwrap();
+            let _msg = serde_json::from_slice::<SocketMessage>(data).unwrap();
         }
     }
+
+    #[test]
+    fn test_separate_partial_frame() {
+        let json_str =
+            r#"{"v":2,"id":5,"kind":3,"body":"}{"}{"v":2,"id":6,"kind":3,"bo"#.as_bytes();
+        let (ret, consumed) = util::separate(json_str).unwrap();
+
+        assert_eq!(ret.len(), 1);
+        assert_eq!(
+            &json_str[consumed..],
+            r#"{"v":2,"id":6,"kind":3,"bo"#.as_bytes()
+        );
+
+        let (msg, version) = SocketMessage::decode(ret[0]).unwrap();
+        assert_eq!(msg.body(), r#""}{""#.as_bytes());
+        assert_eq!(version, 2);
+    }
 }
@@ -1,43 +1,34 @@
-use json_elem::JsonElem;
-use tokio::net::TcpStream;
-
 use crate::{
     error::RemoteError,
     message::{MessageType, SocketMessage},
     objects::SUCCESS,
-    socket::{Socket, ENV_SERVER_ADDRESS, SERVER_ADDRESS},
+    options::ClientOptions,
+    socket::Socket,
 };
 
 /// A function that will guarantees that the object is already available for
 /// remote method calls for synchronization purposes.
 pub async fn wait_for_objects(list: Vec<String>) -> Result<(), RemoteError> {
-    let server_address = std::env::var(ENV_SERVER_ADDRESS).unwrap_or(SERVER_ADDRESS.to_owned());
-    let stream = TcpStream::connect(server_address).await.unwrap();
+    wait_for_objects_with(ClientOptions::default(), list).await
+}
 
-    let addr = stream.peer_addr().unwrap();
-    let socket = Socket::new(stream, addr);
+/// Waits for the objects on the IPC server given by the options.
+pub async fn wait_for_objects_with(
+    options: ClientOptions,
+    list: Vec<String>,
+) -> Result<(), RemoteError> {
+    let socket = Socket::connect(&options)
+        .await
+        .map_err(RemoteError::client)?;
 
     for body in list {
         loop {
             let request = SocketMessage::new()
                 .set_kind(MessageType::WaitForObject)
                 .set_body(body.as_bytes());
-            let stream = serde_json::to_vec(&request)
-                .map_err(|err| RemoteError::new(JsonElem::String(err.to_string())))?;
-
-            socket
-                .write(stream.as_slice())
-                .await
-                .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
-
-            let mut buf = Vec::new();
-            let n = socket
-                .read(&mut buf)
-                .await
-                .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+            socket.send(&request).await.map_err(RemoteError::client)?;
 
-            let reply = serde_json::from_slice::<SocketMessage>(&buf[0..n])
-                .map_err(|e| RemoteError::new(JsonElem::String(e.to_string())))?;
+            let reply = socket.receive().await.map_err(RemoteError::client)?;
 
             if reply.body() == SUCCESS.as_bytes() {
                 break;